@@ -1,241 +1,42 @@
+use std::{collections::VecDeque, path::Path};
+
 use bevy::prelude::*;
 use bitfield::bitfield;
-use noise::NoisePlugin;
-use pulse::PulsePlugin;
-use triangle::TrianglePlugin;
-
+use blip::BlipBuffer;
+pub use debugger::apu_gui;
+use dmc::Dmc;
+pub use filter::FilterProfile;
+use filter::OutputFilter;
+use noise::Noise;
+use pulse::Pulse;
+use recorder::Recorder;
+use stream::{ApuStreamPlugin, SampleQueue};
+use triangle::Triangle;
+use vgm::VgmLogger;
+
+use crate::region::Region;
+
+mod blip;
+mod debugger;
+mod dmc;
+mod filter;
 mod noise;
 mod pulse;
+pub mod recorder;
+mod stream;
 mod triangle;
+mod vgm;
 
-const CPU_HZ: f32 = 1789773.0;
+pub const CPU_HZ: f32 = 1789773.0;
+
+/// number of output samples kept for the debugger waveforms
+const SCOPE_LENGTH: usize = 512;
 
 const LENGTH_COUNTER_TABLE: [u8; 32] = [
     10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
     192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
-bitfield! {
-    #[derive(Default)]
-    struct PulseRegister(u32);
-    impl Debug;
-    volume, set_volume: 3, 0;
-    envelope, set_envelope: 4, 4;
-    constant_volume, set_constant_volume: 4, 4;
-    length_counter_halt, set_length_counter_halt: 5, 5;
-    envelope_loop, set_envelope_loop: 5, 5;
-    duty, set_duty: 7, 6;
-    shift_amount, set_shift_amount: 10, 8;
-    negate, set_negate: 11, 11;
-    sweep_period, set_sweep_period: 14, 12;
-    sweep_enabled, set_sweep_enabled: 15, 15;
-    timer, set_timer: 26, 16;
-    length_counter, set_length_counter: 31, 27;
-}
-
-#[derive(Default)]
-struct Pulse {
-    reg: PulseRegister,
-    target_period: u32,
-    volume: u8,
-    mute: bool,
-    // length
-    length_reload: bool,
-    length_counter: u8,
-    // envelope
-    envelope_reload: bool,
-    decay_level: u8,
-    envelope_divider: u8,
-    envelope_counter: u8,
-    envelope_period: u8,
-    // sweep
-    sweep_reload: bool,
-    sweep_counter: u8,
-    sweep_complement: bool,
-}
-
-impl Pulse {
-    fn new(sweep_complement: bool) -> Self {
-        Self {
-            sweep_complement,
-            ..default()
-        }
-    }
-
-    fn update_target_period(&mut self) {
-        let change_amount = self.reg.timer() >> self.reg.shift_amount();
-        self.target_period = if self.reg.negate() != 0 {
-            self.reg
-                .timer()
-                .saturating_sub(change_amount + (self.sweep_complement as u32))
-        } else {
-            self.reg.timer() + change_amount
-        };
-        self.mute = self.target_period > 0x7FF || self.reg.timer() < 0x08;
-    }
-
-    fn clock_length_counter(&mut self) {
-        if self.length_reload {
-            self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
-            self.length_reload = false;
-        } else {
-            if self.reg.length_counter_halt() == 0 {
-                let next_length = self.length_counter.saturating_sub(1);
-                self.length_counter = next_length;
-            }
-        }
-    }
-
-    fn clock_envelope(&mut self) {
-        if self.envelope_reload {
-            self.envelope_reload = false;
-            self.decay_level = 15;
-            self.envelope_period = (self.reg.volume() + 1) as u8;
-            self.envelope_divider = self.envelope_period;
-        } else {
-            self.envelope_divider = self.envelope_divider.saturating_sub(1);
-            if self.envelope_divider == 0 {
-                self.envelope_divider = self.envelope_period;
-                if self.decay_level > 0 {
-                    self.decay_level -= 1;
-                } else if self.reg.envelope_loop() != 0 {
-                    self.decay_level = 15;
-                }
-            }
-        }
-        if self.reg.constant_volume() != 0 {
-            self.volume = self.reg.volume() as u8;
-        } else {
-            self.volume = self.decay_level;
-        }
-    }
-
-    fn clock_sweep(&mut self) {
-        if self.reg.sweep_enabled() != 0 && self.sweep_counter == 0 && self.reg.shift_amount() != 0
-        {
-            if !self.mute {
-                self.reg.set_timer(self.target_period);
-            }
-        }
-        if self.sweep_reload || self.sweep_counter == 0 {
-            self.sweep_counter = self.reg.sweep_period() as u8;
-            self.sweep_reload = false;
-        } else {
-            self.sweep_counter = self.sweep_counter.saturating_sub(1);
-        }
-    }
-}
-
-bitfield! {
-    #[derive(Default)]
-    struct TriangleRegister(u32);
-    impl Debug;
-    linear_counter, set_linear_counter: 6, 0;
-    linear_control, set_linear_control: 7, 7;
-    length_counter_halt, set_length_counter_halt: 7, 7;
-    timer, set_timer: 26, 16;
-    length_counter, set_length_counter: 31, 27;
-}
-
-#[derive(Default)]
-struct Triangle {
-    reg: TriangleRegister,
-    length_counter_reload: bool,
-    length_counter: u8,
-    linear_counter_reload: bool,
-    linear_counter: u8,
-}
-
-impl Triangle {
-    fn clock_linear_counter(&mut self) {
-        if self.linear_counter_reload {
-            self.linear_counter = self.reg.linear_counter() as u8;
-        } else {
-            self.linear_counter = self.linear_counter.saturating_sub(1);
-        }
-        if self.reg.linear_control() == 0 {
-            self.linear_counter_reload = false;
-        }
-    }
-
-    fn clock_length_counter(&mut self) {
-        if self.length_counter_reload {
-            self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
-            self.length_counter_reload = false;
-        } else {
-            if self.reg.length_counter_halt() == 0 {
-                self.length_counter = self.length_counter.saturating_sub(1);
-            }
-        }
-    }
-}
-
-bitfield! {
-    #[derive(Default)]
-    struct NoiseRegister(u32);
-    impl Debug;
-    volume, set_volume: 3, 0;
-    constant_volume, set_constant_volume: 4, 4;
-    envelope_loop, set_envelope_loop: 5, 5;
-    length_counter_halt, set_length_counter_halt: 5, 5;
-    period, set_period: 19, 16;
-    loop_noise, set_loop_noise: 23, 23;
-    length_counter, set_length_counter: 31, 27;
-}
-
-#[derive(Default)]
-struct Noise {
-    reg: NoiseRegister,
-    volume: u8,
-    // length
-    length_reload: bool,
-    length_counter: u8,
-    // envelope
-    envelope_reload: bool,
-    decay_level: u8,
-    envelope_divider: u8,
-    envelope_counter: u8,
-    envelope_period: u8,
-}
-
-impl Noise {
-    fn clock_length_counter(&mut self) {
-        if self.length_reload {
-            self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
-            self.length_reload = false;
-        } else {
-            if self.reg.length_counter_halt() == 0 {
-                let next_length = self.length_counter.saturating_sub(1);
-                self.length_counter = next_length;
-            }
-        }
-    }
-
-    fn clock_envelope(&mut self) {
-        if self.envelope_reload {
-            self.envelope_reload = false;
-            self.decay_level = 15;
-            self.envelope_period = (self.reg.volume() + 1) as u8;
-            self.envelope_divider = self.envelope_period;
-        } else {
-            self.envelope_divider = self.envelope_divider.saturating_sub(1);
-            if self.envelope_divider == 0 {
-                self.envelope_divider = self.envelope_period;
-                if self.decay_level > 0 {
-                    self.decay_level -= 1;
-                } else if self.reg.envelope_loop() != 0 {
-                    self.decay_level = 15;
-                }
-            }
-        }
-        if self.reg.constant_volume() != 0 {
-            self.volume = self.reg.volume() as u8;
-        } else {
-            self.volume = self.decay_level;
-        }
-    }
-}
-
 bitfield! {
     #[derive(Default)]
     struct ApuStatus(u8);
@@ -243,6 +44,7 @@ bitfield! {
     pulse, set_pulse: 1, 0;
     triangle, set_triangle: 2;
     noise, set_noise: 3;
+    dmc, set_dmc: 4;
 }
 
 bitfield! {
@@ -264,197 +66,391 @@ enum Step {
 
 #[derive(Component)]
 pub struct Apu {
+    region: Region,
     pulse: [Pulse; 2],
     triangle: Triangle,
     noise: Noise,
+    dmc: Dmc,
     status: ApuStatus,
     frame_counter: FrameCounter,
+    /// CPU cycles since the start of the frame sequence
     cycles: usize,
-    irq: bool,
+    odd_cycle: bool,
+    /// CPU cycles until a $4017 write resets the frame sequence
+    frame_reset_delay: u8,
+    frame_irq: bool,
+    blip: BlipBuffer,
+    filter_profile: FilterProfile,
+    filter: OutputFilter,
+    queue: SampleQueue,
+    recorder: Option<Recorder>,
+    vgm: Option<VgmLogger>,
+    /// pulse 1, pulse 2, triangle, noise and DMC silenced in the mix
+    pub muted: [bool; 5],
+    /// recent levels of each channel, one per output sample
+    scope: [VecDeque<u8>; 5],
 }
 
 impl Default for Apu {
     fn default() -> Self {
         Self {
+            region: Region::default(),
             pulse: [Pulse::new(false), Pulse::new(true)],
             triangle: Triangle::default(),
             noise: Noise::default(),
+            dmc: Dmc::default(),
             status: ApuStatus::default(),
             frame_counter: FrameCounter::default(),
             cycles: 0,
-            irq: false,
+            odd_cycle: false,
+            frame_reset_delay: 0,
+            frame_irq: false,
+            blip: BlipBuffer::default(),
+            filter_profile: FilterProfile::default(),
+            filter: OutputFilter::default(),
+            queue: SampleQueue::default(),
+            recorder: None,
+            vgm: None,
+            muted: [false; 5],
+            scope: Default::default(),
         }
     }
 }
 
 impl Apu {
-    pub fn tick(&mut self, cycles: usize) -> bool {
-        if cycles % 6 == 0 {
-            self.cycles += 1;
-            self.pulse[0].update_target_period();
-            self.pulse[1].update_target_period();
-            match (self.cycles, self.frame_counter.step_mode()) {
-                (3729, _) => self.quarter_frame_tick(),
-                (7457, _) => {
-                    self.half_frame_tick();
-                    self.quarter_frame_tick();
-                }
-                (11186, _) => {
-                    self.quarter_frame_tick();
-                }
-                (14915, 0x00) => {
-                    self.half_frame_tick();
-                    self.quarter_frame_tick();
-                    self.cycles = 0;
-                }
-                (18641, 0x01) => {
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.cycles = 0;
+                // the 5-step mode clocks the units as soon as it is selected
+                if self.frame_counter.step_mode() == 0x01 {
                     self.half_frame_tick();
                     self.quarter_frame_tick();
-                    self.cycles = 0;
                 }
-                _ => return false,
             }
-            self.irq =
-                (self.cycles == 14914 || self.cycles == 0) && self.frame_counter.irq_inhibit() == 0;
-            true
-        } else {
-            false
+        }
+
+        self.cycles += 1;
+        let [first, second, third, four_step, five_step] = self.region.frame_steps();
+        match (self.cycles, self.frame_counter.step_mode()) {
+            (cycles, _) if cycles == first || cycles == third => self.quarter_frame_tick(),
+            (cycles, _) if cycles == second => {
+                self.half_frame_tick();
+                self.quarter_frame_tick();
+            }
+            (cycles, 0x00) if cycles == four_step - 1 => self.set_frame_irq(),
+            (cycles, 0x00) if cycles == four_step => {
+                self.half_frame_tick();
+                self.quarter_frame_tick();
+                self.set_frame_irq();
+            }
+            (cycles, 0x00) if cycles == four_step + 1 => {
+                self.set_frame_irq();
+                self.cycles = 0;
+            }
+            (cycles, 0x01) if cycles == five_step => {
+                self.half_frame_tick();
+                self.quarter_frame_tick();
+            }
+            (cycles, 0x01) if cycles == five_step + 1 => self.cycles = 0,
+            _ => {}
         }
     }
 
-    pub fn quarter_frame_tick(&mut self) {
-        for pulse_id in 0..=1 {
-            if (self.status.pulse() >> pulse_id) & 1 != 0 {
-                self.pulse[pulse_id].clock_envelope();
+    fn set_frame_irq(&mut self) {
+        if self.frame_counter.irq_inhibit() == 0 {
+            self.frame_irq = true;
+        }
+    }
+
+    /// Called once per CPU cycle with the level of the cartridge expansion audio. Every
+    /// channel is clocked and the mixed output is added to the sample stream.
+    pub fn cpu_tick(&mut self, expansion: f32) {
+        self.odd_cycle = !self.odd_cycle;
+        self.clock_frame_counter();
+        self.pulse[0].tick();
+        self.pulse[1].tick();
+        self.triangle.tick();
+        self.noise.tick();
+        self.dmc.tick();
+        if let Some(vgm) = &mut self.vgm {
+            vgm.tick();
+        }
+        let sample = self
+            .blip
+            .push(self.mix() + expansion)
+            .map(|sample| self.filter.process(sample));
+        if let Some(sample) = sample {
+            self.queue.push(sample);
+            let outputs = self.outputs();
+            for (scope, level) in self.scope.iter_mut().zip(outputs) {
+                if scope.len() == SCOPE_LENGTH {
+                    scope.pop_front();
+                }
+                scope.push_back(level);
             }
         }
-        if self.status.noise() {
-            self.noise.clock_envelope();
+        self.record(sample, expansion);
+    }
+
+    fn record(&mut self, sample: Option<f32>, expansion: f32) {
+        if self.recorder.is_none() {
+            return;
         }
-        if self.status.triangle() {
-            self.triangle.clock_linear_counter();
+        let [pulse1, pulse2, triangle, noise, dmc] = self.outputs();
+        let levels = [
+            pulse_out(pulse1),
+            pulse_out(pulse2),
+            tnd_out(triangle, 0, 0),
+            tnd_out(0, noise, 0),
+            tnd_out(0, 0, dmc),
+            expansion,
+        ];
+        let recorder = self.recorder.as_mut().unwrap();
+        if let Err(err) = recorder.write(sample, levels) {
+            error!("Failed to write audio recording: {}", err);
+            self.recorder = None;
         }
     }
 
-    pub fn irq(&mut self) -> bool {
-        let v = self.irq;
-        self.irq = false;
-        v
+    /// Switches the frame sequencer, noise and DMC periods and the output sample rate to
+    /// the timings of `region`.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+        self.blip = BlipBuffer::new(region.cpu_hz());
     }
 
-    pub fn half_frame_tick(&mut self) {
-        for pulse_id in 0..=1 {
-            if (self.status.pulse() >> pulse_id) & 1 != 0 {
-                self.pulse[pulse_id].clock_length_counter();
-                self.pulse[pulse_id].clock_sweep();
+    pub fn set_filter_profile(&mut self, profile: FilterProfile) {
+        self.filter_profile = profile;
+        self.filter = OutputFilter::new(profile);
+    }
+
+    /// Starts writing the filtered output to a WAV file at `path`, along with one file per
+    /// channel when `stems` is set.
+    pub fn start_recording(&mut self, path: &Path, stems: bool) -> std::io::Result<()> {
+        self.stop_recording();
+        self.recorder = Some(Recorder::new(
+            path,
+            stems,
+            self.filter_profile,
+            self.region.cpu_hz(),
+        )?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(Err(err)) = self.recorder.take().map(|mut recorder| recorder.finish()) {
+            error!("Failed to finish audio recording: {}", err);
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Starts logging register writes to a VGM file at `path`, written when the log stops.
+    pub fn start_vgm(&mut self, path: &Path) {
+        self.stop_vgm();
+        self.vgm = Some(VgmLogger::new(path, self.region.cpu_hz()));
+    }
+
+    pub fn stop_vgm(&mut self) {
+        if let Some(Err(err)) = self.vgm.take().map(|mut vgm| vgm.finish()) {
+            error!("Failed to save VGM log: {}", err);
+        }
+    }
+
+    pub fn logging_vgm(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    /// logs a write to expansion audio registers
+    pub fn vgm_write(&mut self, addr: u16, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write(addr, data);
+        }
+    }
+
+    /// address and length of the DMC sample, when its bytes have to be logged
+    pub fn vgm_dmc_sample(&self) -> Option<(u16, u16)> {
+        self.vgm
+            .as_ref()
+            .map(|_| (self.dmc.sample_addr, self.dmc.sample_length))
+    }
+
+    pub fn vgm_memory(&mut self, addr: u16, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write_memory(addr, data);
+        }
+    }
+
+    /// Nonlinear mixer of the 2A03, the pulse channels share one DAC and the triangle,
+    /// noise and DMC another. Output is between 0.0 and 1.0.
+    fn mix(&self) -> f32 {
+        let [pulse1, pulse2, triangle, noise, dmc] = self.outputs();
+        pulse_out(pulse1 + pulse2) + tnd_out(triangle, noise, dmc)
+    }
+
+    /// level of each channel going into the mixer, zero when muted
+    fn outputs(&self) -> [u8; 5] {
+        let mut outputs = [
+            self.pulse[0].output(),
+            self.pulse[1].output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ];
+        for (output, muted) in outputs.iter_mut().zip(self.muted) {
+            if muted {
+                *output = 0;
             }
         }
-        if self.status.triangle() {
-            self.triangle.clock_length_counter();
+        outputs
+    }
+
+    /// number of frame sequencer steps already clocked in the current sequence
+    fn frame_step(&self) -> usize {
+        let steps = self.region.frame_steps();
+        let steps = if self.frame_counter.step_mode() == 0x00 {
+            &steps[..4]
+        } else {
+            &steps[..]
+        };
+        steps.iter().filter(|step| self.cycles >= **step).count()
+    }
+
+    /// address the DMC wants to fetch a sample byte from, stalling the CPU
+    pub fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub fn dmc_dma_complete(&mut self, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write_memory(self.dmc.current_addr, data);
+        }
+        self.dmc.dma_complete(data);
+    }
+
+    pub fn quarter_frame_tick(&mut self) {
+        self.pulse[0].clock_envelope();
+        self.pulse[1].clock_envelope();
+        self.noise.clock_envelope();
+        self.triangle.clock_linear_counter();
+    }
+
+    /// Both interrupts stay asserted until acknowledged, the frame interrupt by reading
+    /// $4015 or inhibiting it through $4017, the DMC one by writing $4010 or $4015.
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// Reads without the side effect of acknowledging the frame interrupt.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4015 => Some(
+                (self.pulse[0].length_counter > 0) as u8
+                    | ((self.pulse[1].length_counter > 0) as u8) << 1
+                    | ((self.triangle.length_counter > 0) as u8) << 2
+                    | ((self.noise.length_counter > 0) as u8) << 3
+                    | ((self.dmc.bytes_remaining > 0) as u8) << 4
+                    | (self.frame_irq as u8) << 6
+                    | (self.dmc.irq as u8) << 7,
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr);
+        if addr == 0x4015 {
+            self.frame_irq = false;
         }
-        if self.status.noise() {
-            self.noise.clock_length_counter();
+        data
+    }
+
+    pub fn half_frame_tick(&mut self) {
+        for pulse in self.pulse.iter_mut() {
+            pulse.clock_length_counter();
+            pulse.clock_sweep();
         }
+        self.triangle.clock_length_counter();
+        self.noise.clock_length_counter();
     }
 
     pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        self.vgm_write(addr, data);
         match addr {
-            0x4000 | 0x4004 => {
-                let pulse_id = ((addr >> 2) & 1) as usize;
-                self.pulse[pulse_id].reg.0 &= !0xFF;
-                self.pulse[pulse_id].reg.0 |= data as u32;
-
-                if (self.status.pulse() >> pulse_id) & 1 != 0 {
-                    self.pulse[pulse_id].envelope_reload = true;
-                    self.pulse[pulse_id].envelope_counter =
-                        LENGTH_COUNTER_TABLE[self.pulse[pulse_id].reg.length_counter() as usize];
-                }
-            }
-            0x4001 | 0x4005 => {
-                let pulse_id = ((addr >> 2) & 1) as usize;
-                self.pulse[pulse_id].reg.0 &= !(0xFFu32 << 8);
-                self.pulse[pulse_id].reg.0 |= (data as u32) << 8;
-                if (self.status.pulse() >> pulse_id) & 1 != 0 {
-                    self.pulse[pulse_id].sweep_reload = true;
-                }
-            }
-            0x4002 | 0x4006 => {
-                let pulse_id = ((addr >> 2) & 1) as usize;
-                self.pulse[pulse_id].reg.0 &= !(0xFFu32 << 16);
-                self.pulse[pulse_id].reg.0 |= (data as u32) << 16;
-            }
-            0x4003 | 0x4007 => {
+            0x4000..=0x4007 => {
                 let pulse_id = ((addr >> 2) & 1) as usize;
-                self.pulse[pulse_id].reg.0 &= !(0xFFu32 << 24);
-                self.pulse[pulse_id].reg.0 |= (data as u32) << 24;
-                if (self.status.pulse() >> pulse_id) & 1 != 0 {
-                    self.pulse[pulse_id].length_reload = true;
-                }
+                let enabled = (self.status.pulse() >> pulse_id) & 1 != 0;
+                self.pulse[pulse_id].write(addr, data, enabled);
             }
-            0x4008 => {
-                self.triangle.reg.0 &= !0xFFu32;
-                self.triangle.reg.0 |= data as u32;
-                if self.status.triangle() {
-                    self.triangle.linear_counter_reload = true;
+            0x4008..=0x400B => self.triangle.write(addr, data, self.status.triangle()),
+            0x400C..=0x400F => self.noise.write(addr, data, self.status.noise()),
+            0x4010..=0x4013 => self.dmc.write(addr, data),
+            0x4015 => {
+                self.status.0 = data;
+                if self.status.pulse() & 0x01 == 0 {
+                    self.pulse[0].length_counter = 0;
                 }
-            }
-            0x400A => {
-                self.triangle.reg.0 &= !(0xFFu32 << 16);
-                self.triangle.reg.0 |= (data as u32) << 16;
-            }
-            0x400B => {
-                self.triangle.reg.0 &= !(0xFFu32 << 24);
-                self.triangle.reg.0 |= (data as u32) << 24;
-                if self.status.triangle() {
-                    self.triangle.length_counter_reload = true;
-                    self.triangle.linear_counter_reload = true;
+                if self.status.pulse() & 0x02 == 0 {
+                    self.pulse[1].length_counter = 0;
                 }
-            }
-            0x400C => {
-                self.noise.reg.0 &= !0xFF;
-                self.noise.reg.0 |= data as u32;
-                if self.status.noise() {
-                    self.noise.envelope_reload = true;
-                    self.noise.envelope_counter =
-                        LENGTH_COUNTER_TABLE[self.noise.reg.length_counter() as usize];
+                if !self.status.triangle() {
+                    self.triangle.length_counter = 0;
                 }
-            }
-            0x400E => {
-                self.noise.reg.0 &= !(0xFFu32 << 16);
-                self.noise.reg.0 |= (data as u32) << 16;
-            }
-            0x400F => {
-                self.noise.reg.0 &= !(0xFFu32 << 24);
-                self.noise.reg.0 |= (data as u32) << 24;
-                if self.status.noise() {
-                    self.noise.length_reload = true;
+                if !self.status.noise() {
+                    self.noise.length_counter = 0;
                 }
-            }
-            0x4015 => {
-                // should also reset length counter
-                self.status.0 = data;
-                self.pulse[0].length_counter *= (data >> 0) & 1;
-                self.pulse[1].length_counter *= (data >> 1) & 1;
-                self.triangle.length_counter *= (data >> 2) & 1;
+                self.dmc.set_enabled(self.status.dmc());
             }
             0x4017 => {
                 self.frame_counter.0 = data;
+                if self.frame_counter.irq_inhibit() != 0 {
+                    self.frame_irq = false;
+                }
+                // the sequence is reset 3 or 4 cycles later depending on the APU cycle
+                // the write lands on
+                self.frame_reset_delay = if self.odd_cycle { 4 } else { 3 };
             }
             _ => {}
         }
     }
 }
 
+fn pulse_out(pulse: u8) -> f32 {
+    if pulse == 0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse as f32 + 100.0)
+    }
+}
+
+fn tnd_out(triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let tnd = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+    if tnd == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd + 100.0)
+    }
+}
+
+fn update_sample_rate(mut query: Query<&mut Apu>) {
+    for mut apu in query.iter_mut() {
+        let adjustment = apu.queue.rate_adjustment();
+        apu.blip.set_rate_adjustment(adjustment);
+    }
+}
+
 pub struct ApuPlugin;
 
 impl Plugin for ApuPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((
-            PulsePlugin::<0>,
-            PulsePlugin::<1>,
-            TrianglePlugin,
-            NoisePlugin,
-        ));
+        app.add_plugins(ApuStreamPlugin)
+            .add_systems(Update, update_sample_rate);
     }
 }
 
@@ -488,4 +484,58 @@ mod tests {
         );
         assert_eq!(apu.pulse[1].reg.length_counter(), 0b11011);
     }
+
+    #[test]
+    fn dmc_sample_fetch() {
+        let mut apu = Apu::default();
+        apu.cpu_write(0x4010, 0x8F);
+        apu.cpu_write(0x4011, 0x40);
+        apu.cpu_write(0x4012, 0xFF);
+        apu.cpu_write(0x4013, 0x00);
+        assert_eq!(apu.dmc.output_level, 0x40);
+        assert_eq!(apu.dmc_dma_request(), None);
+
+        apu.cpu_write(0x4015, 0x10);
+        assert_eq!(apu.dmc_dma_request(), Some(0xFFC0));
+        apu.dmc_dma_complete(0xFF);
+        assert_eq!(apu.dmc_dma_request(), None);
+        assert!(apu.irq());
+
+        // the IRQ is held until acknowledged
+        assert!(apu.irq());
+        apu.cpu_write(0x4015, 0x10);
+        assert!(!apu.irq());
+
+        // the output unit loads the sample byte, then plays one bit every 54 cycles
+        for _ in 0..54 * 9 {
+            apu.cpu_tick(0.0);
+        }
+        assert_eq!(apu.dmc.output_level, 0x50);
+    }
+
+    #[test]
+    fn frame_irq_acknowledge() {
+        let mut apu = Apu::default();
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4003, 0x08);
+        for _ in 0..29829 {
+            apu.cpu_tick(0.0);
+        }
+        assert!(apu.irq());
+        assert_eq!(apu.cpu_read(0x4015), Some(0x41));
+        assert!(!apu.irq());
+        assert_eq!(apu.cpu_read(0x4015), Some(0x01));
+
+        // switching to 5-step mode clocks the length counter right after the reset delay
+        apu.cpu_write(0x4017, 0xC0);
+        let length = apu.pulse[0].length_counter;
+        for _ in 0..4 {
+            apu.cpu_tick(0.0);
+        }
+        assert_eq!(apu.pulse[0].length_counter, length - 1);
+        for _ in 0..37282 {
+            apu.cpu_tick(0.0);
+        }
+        assert!(!apu.irq());
+    }
 }
@@ -0,0 +1,119 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use super::{stream::SAMPLE_RATE, CPU_HZ};
+
+/// sub-sample positions the band-limited step is computed for
+const PHASES: usize = 32;
+
+/// width of the band-limited step in output samples
+const TAPS: usize = 16;
+
+/// cutoff of the low-pass filter relative to the Nyquist frequency of the output
+const CUTOFF: f64 = 0.9;
+
+/// Band-limited synthesis of the APU output. Instead of averaging the level of every CPU
+/// cycle, each change of level is added to the output as a band-limited step, which keeps
+/// square waves free of aliasing when downsampled to the audio sample rate.
+pub struct BlipBuffer {
+    /// windowed sinc impulses for each phase, each summing to 1
+    kernel: Vec<[f32; TAPS]>,
+    /// level changes waiting to be integrated, the front being the next output sample
+    deltas: VecDeque<f32>,
+    /// time of the current CPU cycle in output samples, relative to the front of `deltas`
+    time: f64,
+    /// output samples per CPU cycle
+    ratio: f64,
+    cpu_hz: f64,
+    level: f32,
+    integrator: f32,
+}
+
+impl Default for BlipBuffer {
+    fn default() -> Self {
+        Self::new(CPU_HZ as f64)
+    }
+}
+
+impl BlipBuffer {
+    pub fn new(cpu_hz: f64) -> Self {
+        Self {
+            kernel: build_kernel(),
+            deltas: VecDeque::from(vec![0.0; TAPS + 1]),
+            time: 0.0,
+            ratio: SAMPLE_RATE as f64 / cpu_hz,
+            cpu_hz,
+            level: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    /// scales the output sample rate by `adjustment` around the nominal one
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.ratio = SAMPLE_RATE as f64 / self.cpu_hz * adjustment;
+    }
+
+    /// Called once per CPU cycle with the output level of the APU, returns an output sample
+    /// when one is complete.
+    pub fn push(&mut self, level: f32) -> Option<f32> {
+        if level != self.level {
+            let delta = level - self.level;
+            let phase = ((self.time * PHASES as f64) as usize).min(PHASES - 1);
+            for (tap, weight) in self.kernel[phase].iter().enumerate() {
+                self.deltas[tap] += delta * weight;
+            }
+            self.level = level;
+        }
+
+        // there are less output samples than CPU cycles, at most one is completed per cycle
+        self.time += self.ratio;
+        if self.time >= 1.0 {
+            self.time -= 1.0;
+            self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+            self.deltas.push_back(0.0);
+            Some(self.integrator)
+        } else {
+            None
+        }
+    }
+}
+
+/// Blackman windowed sinc, delayed by half the kernel width plus the phase.
+fn build_kernel() -> Vec<[f32; TAPS]> {
+    (0..PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; TAPS];
+            for (tap, weight) in taps.iter_mut().enumerate() {
+                let x = tap as f64 - (TAPS / 2) as f64 - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+                };
+                let n = (tap as f64 - offset) / TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos();
+                *weight = (sinc * window) as f32;
+            }
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|weight| *weight /= sum);
+            taps
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlipBuffer;
+
+    #[test]
+    fn step_settles() {
+        let mut blip = BlipBuffer::default();
+        let mut last = 0.0;
+        for sample in (0..2000).filter_map(|_| blip.push(1.0)) {
+            // a band-limited step rings a little around the new level
+            assert!(sample < 1.2);
+            last = sample;
+        }
+        assert!((last - 1.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,180 @@
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Color32, Sense, Stroke},
+    EguiContexts,
+};
+
+use super::Apu;
+
+const CHANNELS: [&str; 5] = ["Pulse 1", "Pulse 2", "Triangle", "Noise", "DMC"];
+
+pub fn apu_gui(mut query: Query<&mut Apu>, mut contexts: EguiContexts) {
+    egui::Window::new("APU Info").show(contexts.ctx_mut(), |ui| {
+        let Ok(mut apu) = query.get_single_mut() else {
+            ui.label("No APU found");
+            return;
+        };
+        ui.monospace(format!(
+            "(0x4015) STATUS:    {a:#04X} ({a:#010b})",
+            a = apu.status.0
+        ));
+        ui.monospace(format!(
+            "(0x4017) FRAME:     {a:#04X} ({a:#010b})",
+            a = apu.frame_counter.0
+        ));
+        ui.monospace(format!(
+            "FRAME STEP: {}/{} ({} cycles, {:?})",
+            apu.frame_step(),
+            if apu.frame_counter.step_mode() == 0x00 {
+                4
+            } else {
+                5
+            },
+            apu.cycles,
+            apu.region
+        ));
+        ui.monospace(format!(
+            "FRAME IRQ:  {}  DMC IRQ: {}",
+            apu.frame_irq, apu.dmc.irq
+        ));
+
+        for (channel, name) in CHANNELS.iter().enumerate() {
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.strong(*name);
+                ui.checkbox(&mut apu.muted[channel], "mute");
+                let soloed = apu
+                    .muted
+                    .iter()
+                    .enumerate()
+                    .all(|(i, muted)| *muted != (i == channel));
+                if ui.selectable_label(soloed, "solo").clicked() {
+                    for (i, muted) in apu.muted.iter_mut().enumerate() {
+                        *muted = !soloed && i != channel;
+                    }
+                }
+            });
+            channel_state(ui, &apu, channel);
+            let max = if channel == 4 { 127.0 } else { 15.0 };
+            waveform(
+                ui,
+                apu.scope[channel].iter().map(|level| *level as f32 / max),
+            );
+        }
+    });
+}
+
+fn channel_state(ui: &mut egui::Ui, apu: &Apu, channel: usize) {
+    match channel {
+        0 | 1 => {
+            let pulse = &apu.pulse[channel];
+            ui.monospace(format!(
+                "REGS: {:02X?}  DUTY: {}  STEP: {}",
+                pulse.reg.0.to_le_bytes(),
+                pulse.reg.duty(),
+                pulse.sequence_step
+            ));
+            ui.monospace(format!(
+                "LENGTH: {:3} (halt {})  TIMER: {:#05X}",
+                pulse.length_counter,
+                pulse.reg.length_counter_halt(),
+                pulse.reg.timer()
+            ));
+            ui.monospace(format!(
+                "ENVELOPE: constant {} loop {} volume {:2} decay {:2} divider {:2}",
+                pulse.reg.constant_volume(),
+                pulse.reg.envelope_loop(),
+                pulse.reg.volume(),
+                pulse.decay_level,
+                pulse.envelope_divider
+            ));
+            ui.monospace(format!(
+                "SWEEP: enabled {} period {} negate {} shift {} counter {} target {:#05X}{}",
+                pulse.reg.sweep_enabled(),
+                pulse.reg.sweep_period(),
+                pulse.reg.negate(),
+                pulse.reg.shift_amount(),
+                pulse.sweep_counter,
+                pulse.target_period,
+                if pulse.mute { " (muted)" } else { "" }
+            ));
+        }
+        2 => {
+            let triangle = &apu.triangle;
+            ui.monospace(format!(
+                "REGS: {:02X?}  STEP: {}",
+                triangle.reg.0.to_le_bytes(),
+                triangle.sequence_step
+            ));
+            ui.monospace(format!(
+                "LENGTH: {:3} (halt {})  TIMER: {:#05X}",
+                triangle.length_counter,
+                triangle.reg.length_counter_halt(),
+                triangle.reg.timer()
+            ));
+            ui.monospace(format!(
+                "LINEAR: {:3} reload value {:3} reload flag {}",
+                triangle.linear_counter,
+                triangle.reg.linear_counter(),
+                triangle.linear_counter_reload
+            ));
+        }
+        3 => {
+            let noise = &apu.noise;
+            ui.monospace(format!(
+                "REGS: {:02X?}  PERIOD: {}  MODE: {}  LFSR: {:#06X}",
+                noise.reg.0.to_le_bytes(),
+                noise.reg.period(),
+                noise.reg.loop_noise(),
+                noise.shift_register
+            ));
+            ui.monospace(format!(
+                "LENGTH: {:3} (halt {})",
+                noise.length_counter,
+                noise.reg.length_counter_halt()
+            ));
+            ui.monospace(format!(
+                "ENVELOPE: constant {} loop {} volume {:2} decay {:2} divider {:2}",
+                noise.reg.constant_volume(),
+                noise.reg.envelope_loop(),
+                noise.reg.volume(),
+                noise.decay_level,
+                noise.envelope_divider
+            ));
+        }
+        _ => {
+            let dmc = &apu.dmc;
+            ui.monospace(format!(
+                "RATE: {}  LEVEL: {:3}  SAMPLE: {:#06X} ({} bytes)",
+                dmc.rate, dmc.output_level, dmc.sample_addr, dmc.sample_length
+            ));
+            ui.monospace(format!(
+                "ADDRESS: {:#06X}  REMAINING: {}",
+                dmc.current_addr, dmc.bytes_remaining
+            ));
+        }
+    }
+}
+
+/// draws levels between 0.0 and 1.0, oldest first
+fn waveform(ui: &mut egui::Ui, levels: impl ExactSizeIterator<Item = f32>) {
+    let (response, painter) =
+        ui.allocate_painter(egui::vec2(ui.available_width(), 40.0), Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, Color32::BLACK);
+    let step = rect.width() / super::SCOPE_LENGTH as f32;
+    let offset = (super::SCOPE_LENGTH - levels.len()) as f32 * step;
+    let points = levels
+        .enumerate()
+        .map(|(i, level)| {
+            egui::pos2(
+                rect.left() + offset + i as f32 * step,
+                rect.bottom() - level * rect.height(),
+            )
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        points,
+        Stroke::new(1.0f32, Color32::GREEN),
+    ));
+}
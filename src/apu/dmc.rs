@@ -0,0 +1,148 @@
+use crate::region::Region;
+
+/// output unit periods in CPU cycles
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+const PAL_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+pub struct Dmc {
+    rate_table: &'static [u16; 16],
+    irq_enabled: bool,
+    loop_sample: bool,
+    pub rate: u16,
+    timer: u16,
+    pub output_level: u8,
+    pub sample_addr: u16,
+    pub sample_length: u16,
+    // memory reader
+    pub current_addr: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self {
+            rate_table: &RATE_TABLE,
+            irq_enabled: false,
+            loop_sample: false,
+            rate: RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 0,
+            silence: true,
+            irq: false,
+        }
+    }
+}
+
+impl Dmc {
+    pub fn set_region(&mut self, region: Region) {
+        self.rate_table = match region {
+            Region::Pal => &PAL_RATE_TABLE,
+            Region::Ntsc | Region::Dendy => &RATE_TABLE,
+        };
+    }
+
+    /// writes one of the 4 registers of the channel
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg & 0x03 {
+            0x00 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.loop_sample = data & 0x40 != 0;
+                self.rate = self.rate_table[(data & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            0x01 => self.output_level = data & 0x7F,
+            0x02 => self.sample_addr = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) + 1,
+        }
+    }
+
+    /// bit 4 of $4015, which also acknowledges the interrupt
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// address of the next sample byte when the memory reader needs the CPU bus
+    pub fn dma_request(&self) -> Option<u16> {
+        (self.sample_buffer.is_none() && self.bytes_remaining > 0).then_some(self.current_addr)
+    }
+
+    pub fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // the address wraps around to $8000
+        self.current_addr = self.current_addr.wrapping_add(1) | 0x8000;
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_sample {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// clocked once per CPU cycle
+    pub fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining = self.bits_remaining.saturating_sub(1);
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    /// current level of the channel, between 0 and 127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
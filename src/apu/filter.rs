@@ -0,0 +1,122 @@
+use std::f32::consts::PI;
+
+use clap::ValueEnum;
+
+use super::stream::SAMPLE_RATE;
+
+/// Analog filtering applied to the audio output by the console.
+#[derive(Debug, Default, Clone, Copy, PartialEq, ValueEnum)]
+pub enum FilterProfile {
+    /// front-loader NES: high-pass at 90 Hz and 440 Hz, low-pass at 14 kHz
+    #[default]
+    Nes,
+    /// Famicom: high-pass at 37 Hz, low-pass at 14 kHz
+    Famicom,
+    /// unfiltered mixer output
+    None,
+}
+
+/// First-order high-pass filter.
+struct HighPass {
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl HighPass {
+    fn new(cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        Self {
+            alpha: rc / (rc + dt),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.previous_output = self.alpha * (self.previous_output + input - self.previous_input);
+        self.previous_input = input;
+        self.previous_output
+    }
+}
+
+/// First-order low-pass filter.
+struct LowPass {
+    alpha: f32,
+    previous_output: f32,
+}
+
+impl LowPass {
+    fn new(cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / SAMPLE_RATE as f32;
+        Self {
+            alpha: dt / (rc + dt),
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.previous_output += self.alpha * (input - self.previous_output);
+        self.previous_output
+    }
+}
+
+/// Filters of the selected profile, run on every output sample.
+pub struct OutputFilter {
+    high_pass: Vec<HighPass>,
+    low_pass: Option<LowPass>,
+}
+
+impl Default for OutputFilter {
+    fn default() -> Self {
+        Self::new(FilterProfile::default())
+    }
+}
+
+impl OutputFilter {
+    pub fn new(profile: FilterProfile) -> Self {
+        let (high_pass, low_pass) = match profile {
+            FilterProfile::Nes => (
+                vec![HighPass::new(90.0), HighPass::new(440.0)],
+                Some(14000.0),
+            ),
+            FilterProfile::Famicom => (vec![HighPass::new(37.0)], Some(14000.0)),
+            FilterProfile::None => (Vec::new(), None),
+        };
+        Self {
+            high_pass,
+            low_pass: low_pass.map(LowPass::new),
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let sample = self
+            .high_pass
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.process(sample));
+        match &mut self.low_pass {
+            Some(filter) => filter.process(sample),
+            None => sample,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterProfile, OutputFilter};
+
+    #[test]
+    fn high_pass_removes_dc() {
+        let mut filter = OutputFilter::new(FilterProfile::Nes);
+        let mut sample = 0.0;
+        for _ in 0..44100 {
+            sample = filter.process(0.5);
+        }
+        assert!(sample.abs() < 1e-3);
+
+        let mut filter = OutputFilter::new(FilterProfile::None);
+        assert_eq!(filter.process(0.5), 0.5);
+    }
+}
@@ -1,112 +1,129 @@
-use bevy::prelude::*;
-use bevy_fundsp::prelude::*;
-use hacker::resample;
-use uuid::Uuid;
-
-use super::{Apu, CPU_HZ};
-
-#[derive(Component)]
-struct NoiseMarker;
-
-#[derive(Resource)]
-struct NoiseVar {
-    sample_rate: Shared,
-    volume: Shared,
+use bitfield::bitfield;
+
+use super::LENGTH_COUNTER_TABLE;
+use crate::region::Region;
+
+/// timer periods in CPU cycles
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const PAL_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+bitfield! {
+    #[derive(Default)]
+    pub struct NoiseRegister(u32);
+    impl Debug;
+    pub volume, set_volume: 3, 0;
+    pub constant_volume, set_constant_volume: 4, 4;
+    pub envelope_loop, set_envelope_loop: 5, 5;
+    pub length_counter_halt, set_length_counter_halt: 5, 5;
+    pub period, set_period: 19, 16;
+    pub loop_noise, set_loop_noise: 23, 23;
+    pub length_counter, set_length_counter: 31, 27;
 }
 
-#[derive(Resource)]
-struct NoiseId(Uuid);
-
-struct NoiseDsp {
-    uuid: Uuid,
-    sample_rate: Shared,
-    volume: Shared,
+pub struct Noise {
+    pub reg: NoiseRegister,
+    period_table: &'static [u16; 16],
+    // length
+    pub length_counter: u8,
+    // envelope
+    envelope_reload: bool,
+    pub decay_level: u8,
+    pub envelope_divider: u8,
+    // linear feedback shift register
+    timer_counter: u16,
+    pub shift_register: u16,
 }
 
-impl NoiseDsp {
-    fn new(sample_rate: Shared, volume: Shared) -> Self {
+impl Default for Noise {
+    fn default() -> Self {
         Self {
-            uuid: Uuid::new_v4(),
-            sample_rate,
-            volume,
+            reg: NoiseRegister::default(),
+            period_table: &PERIOD_TABLE,
+            length_counter: 0,
+            envelope_reload: false,
+            decay_level: 0,
+            envelope_divider: 0,
+            timer_counter: 0,
+            shift_register: 1,
         }
     }
 }
 
-impl DspGraph for NoiseDsp {
-    fn id(&self) -> Uuid {
-        self.uuid
+impl Noise {
+    pub fn set_region(&mut self, region: Region) {
+        self.period_table = match region {
+            Region::Pal => &PAL_PERIOD_TABLE,
+            Region::Ntsc | Region::Dendy => &PERIOD_TABLE,
+        };
     }
 
-    fn generate_graph(&self) -> Box<dyn AudioUnit> {
-        Box::new(noise() * var(&self.volume) >> split::<U2>() * 0.1)
+    /// writes one of the 4 registers of the channel, `enabled` being its $4015 bit
+    pub fn write(&mut self, reg: u16, data: u8, enabled: bool) {
+        let shift = (reg & 0x03) * 8;
+        self.reg.0 &= !(0xFFu32 << shift);
+        self.reg.0 |= (data as u32) << shift;
+        if reg & 0x03 == 0x03 {
+            if enabled {
+                self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
+            }
+            self.envelope_reload = true;
+        }
     }
-}
-
-pub struct NoisePlugin;
-
-impl Plugin for NoisePlugin {
-    fn build(&self, app: &mut App) {
-        let sample_rate = shared(1.0);
-        let volume = shared(1.0);
-        let noise_var = NoiseVar {
-            sample_rate: sample_rate.clone(),
-            volume: volume.clone(),
-        };
-
-        let noise_dsp = NoiseDsp::new(sample_rate.clone(), volume.clone());
-        let noise_id = noise_dsp.id();
 
-        app.add_dsp_source(noise_dsp, SourceType::Dynamic)
-            .insert_resource(noise_var)
-            .insert_resource(NoiseId(noise_id))
-            .add_systems(PostStartup, setup_noise)
-            .add_systems(FixedPostUpdate, update_noise);
+    pub fn clock_length_counter(&mut self) {
+        if self.reg.length_counter_halt() == 0 {
+            self.length_counter = self.length_counter.saturating_sub(1);
+        }
     }
-}
-
-fn setup_noise(
-    mut commands: Commands,
-    mut assets: ResMut<Assets<DspSource>>,
-    dsp_manager: Res<DspManager>,
-    noise_id: Res<NoiseId>,
-) {
-    let source = assets.add(
-        dsp_manager
-            .get_graph_by_id(&noise_id.0)
-            .expect("Noise DSP source not found"),
-    );
 
-    commands.spawn((
-        AudioSourceBundle {
-            source,
-            settings: PlaybackSettings {
-                paused: true,
-                ..default()
-            },
-        },
-        NoiseMarker,
-    ));
-}
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_reload {
+            self.envelope_reload = false;
+            self.decay_level = 15;
+            self.envelope_divider = self.reg.volume() as u8;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.reg.volume() as u8;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.reg.envelope_loop() != 0 {
+                self.decay_level = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+    }
 
-// const SAMPLE_RATE: [f32; 4] = [44100.0, 48000.0, 88200.0, 96000.0];
+    /// clocked once per CPU cycle, shifts the LFSR every time the timer expires
+    pub fn tick(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.period_table[self.reg.period() as usize] - 1;
+            let tap = if self.reg.loop_noise() != 0 { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
 
-fn update_noise(
-    apu: Query<&Apu>,
-    sink: Query<(&mut AudioSink, &NoiseMarker)>,
-    noise_var: Res<NoiseVar>,
-) {
-    if let (Ok(apu), Ok((sink, _))) = (apu.get_single(), sink.get_single()) {
-        let noise = &apu.noise;
-        let volume = (noise.volume as f32) / 15.0;
-        // noise_var.hz.set(hz);
-        noise_var.volume.set(volume);
-        let enabled = apu.status.noise();
+    pub fn volume(&self) -> u8 {
+        if self.reg.constant_volume() != 0 {
+            self.reg.volume() as u8
+        } else {
+            self.decay_level
+        }
+    }
 
-        if !enabled || noise.length_counter == 0 {
-            sink.pause();
+    /// current level of the channel, between 0 and 15
+    pub fn output(&self) -> u8 {
+        if self.shift_register & 0x01 != 0 || self.length_counter == 0 {
+            0
         } else {
-            sink.play();
+            self.volume()
         }
     }
 }
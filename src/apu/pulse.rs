@@ -1,128 +1,156 @@
-use bevy::prelude::*;
-use bevy_fundsp::prelude::*;
-use uuid::Uuid;
-
-use super::{Apu, CPU_HZ};
-
-#[derive(Component)]
-struct PulseMarker<const ID: usize>;
-
-#[derive(Resource)]
-struct PulseVar<const ID: usize> {
-    hz: Shared,
-    duty: Shared,
-    volume: Shared,
+use bitfield::bitfield;
+
+use super::LENGTH_COUNTER_TABLE;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+bitfield! {
+    #[derive(Default)]
+    pub struct PulseRegister(u32);
+    impl Debug;
+    pub volume, set_volume: 3, 0;
+    pub envelope, set_envelope: 4, 4;
+    pub constant_volume, set_constant_volume: 4, 4;
+    pub length_counter_halt, set_length_counter_halt: 5, 5;
+    pub envelope_loop, set_envelope_loop: 5, 5;
+    pub duty, set_duty: 7, 6;
+    pub shift_amount, set_shift_amount: 10, 8;
+    pub negate, set_negate: 11, 11;
+    pub sweep_period, set_sweep_period: 14, 12;
+    pub sweep_enabled, set_sweep_enabled: 15, 15;
+    pub timer, set_timer: 26, 16;
+    pub length_counter, set_length_counter: 31, 27;
 }
 
-#[derive(Resource)]
-struct PulseId<const ID: usize>(Uuid);
-
-struct PulseDsp {
-    uuid: Uuid,
-    hz: Shared,
-    duty: Shared,
-    volume: Shared,
+#[derive(Default)]
+pub struct Pulse {
+    pub reg: PulseRegister,
+    pub target_period: u32,
+    pub mute: bool,
+    // sequencer
+    timer_counter: u32,
+    pub sequence_step: usize,
+    // length
+    pub length_counter: u8,
+    // envelope
+    envelope_reload: bool,
+    pub decay_level: u8,
+    pub envelope_divider: u8,
+    // sweep
+    sweep_reload: bool,
+    pub sweep_counter: u8,
+    sweep_complement: bool,
 }
 
-impl PulseDsp {
-    fn new(hz: Shared, duty: Shared, volume: Shared) -> Self {
+impl Pulse {
+    pub fn new(sweep_complement: bool) -> Self {
         Self {
-            uuid: Uuid::new_v4(),
-            hz,
-            duty,
-            volume,
+            sweep_complement,
+            ..Default::default()
         }
     }
-}
 
-impl DspGraph for PulseDsp {
-    fn id(&self) -> Uuid {
-        self.uuid
+    /// writes one of the 4 registers of the channel, `enabled` being its $4015 bit
+    pub fn write(&mut self, reg: u16, data: u8, enabled: bool) {
+        let shift = (reg & 0x03) * 8;
+        self.reg.0 &= !(0xFFu32 << shift);
+        self.reg.0 |= (data as u32) << shift;
+        match reg & 0x03 {
+            0x01 => self.sweep_reload = true,
+            0x03 => {
+                if enabled {
+                    self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
+                }
+                self.sequence_step = 0;
+                self.envelope_reload = true;
+            }
+            _ => {}
+        }
+        self.update_target_period();
     }
 
-    fn generate_graph(&self) -> Box<dyn AudioUnit> {
-        Box::new(
-            (var(&self.hz) | var(&self.duty))
-                >> pulse() * var(&self.volume)
-                >> declick()
-                >> split::<U2>() * 0.3,
-        )
-    }
-}
-
-pub struct PulsePlugin<const ID: usize>;
-
-impl<const ID: usize> Plugin for PulsePlugin<ID> {
-    fn build(&self, app: &mut App) {
-        let hz = shared(440.0);
-        let duty = shared(0.5);
-        let volume = shared(1.0);
-        let pulse_var = PulseVar::<ID> {
-            hz: hz.clone(),
-            duty: duty.clone(),
-            volume: volume.clone(),
+    pub fn update_target_period(&mut self) {
+        let change_amount = self.reg.timer() >> self.reg.shift_amount();
+        self.target_period = if self.reg.negate() != 0 {
+            self.reg
+                .timer()
+                .saturating_sub(change_amount + (self.sweep_complement as u32))
+        } else {
+            self.reg.timer() + change_amount
         };
+        self.mute = self.target_period > 0x7FF || self.reg.timer() < 0x08;
+    }
 
-        let pulse_dsp = PulseDsp::new(hz.clone(), duty.clone(), volume.clone());
-        let pulse_id = pulse_dsp.id();
+    pub fn clock_length_counter(&mut self) {
+        if self.reg.length_counter_halt() == 0 {
+            self.length_counter = self.length_counter.saturating_sub(1);
+        }
+    }
 
-        app.add_dsp_source(pulse_dsp, SourceType::Dynamic)
-            .insert_resource(pulse_var)
-            .insert_resource(PulseId::<ID>(pulse_id))
-            .add_systems(PostStartup, setup_pulse::<ID>)
-            .add_systems(FixedPostUpdate, update_pulse::<ID>);
+    pub fn clock_envelope(&mut self) {
+        if self.envelope_reload {
+            self.envelope_reload = false;
+            self.decay_level = 15;
+            self.envelope_divider = self.reg.volume() as u8;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.reg.volume() as u8;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            } else if self.reg.envelope_loop() != 0 {
+                self.decay_level = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
     }
-}
 
-fn setup_pulse<const ID: usize>(
-    mut commands: Commands,
-    mut assets: ResMut<Assets<DspSource>>,
-    dsp_manager: Res<DspManager>,
-    pulse_id: Res<PulseId<ID>>,
-) {
-    let source = assets.add(
-        dsp_manager
-            .get_graph_by_id(&pulse_id.0)
-            .expect("Pulse DSP source not found"),
-    );
+    pub fn clock_sweep(&mut self) {
+        if self.reg.sweep_enabled() != 0
+            && self.sweep_counter == 0
+            && self.reg.shift_amount() != 0
+            && !self.mute
+        {
+            self.reg.set_timer(self.target_period);
+            self.update_target_period();
+        }
+        if self.sweep_reload || self.sweep_counter == 0 {
+            self.sweep_counter = self.reg.sweep_period() as u8;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_counter -= 1;
+        }
+    }
 
-    commands.spawn((
-        AudioSourceBundle {
-            source,
-            settings: PlaybackSettings {
-                paused: true,
-                ..default()
-            },
-        },
-        PulseMarker::<ID>,
-    ));
-}
+    /// clocked once per CPU cycle, the sequencer moves every 2 * (timer + 1) cycles
+    pub fn tick(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.reg.timer() * 2 + 1;
+            self.sequence_step = (self.sequence_step + 1) & 0x07;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
 
-fn update_pulse<const ID: usize>(
-    apu: Query<&Apu>,
-    sink: Query<(&mut AudioSink, &PulseMarker<ID>)>,
-    pulse_var: Res<PulseVar<ID>>,
-) {
-    if let (Ok(apu), Ok((sink, _))) = (apu.get_single(), sink.get_single()) {
-        let pulse = &apu.pulse[ID];
-        let hz = CPU_HZ / (16.0 * ((pulse.reg.timer() as f32) + 1.0));
-        let volume = (pulse.volume as f32) / 15.0;
-        let duty = match pulse.reg.duty() {
-            0 => 0.125,
-            1 => 0.25,
-            2 => 0.5,
-            3 => 0.75,
-            _ => unreachable!(),
-        };
-        pulse_var.hz.set(hz);
-        pulse_var.duty.set(duty);
-        pulse_var.volume.set(volume);
-        let enabled = apu.status.pulse() & (1 << ID) != 0;
+    pub fn volume(&self) -> u8 {
+        if self.reg.constant_volume() != 0 {
+            self.reg.volume() as u8
+        } else {
+            self.decay_level
+        }
+    }
 
-        if pulse.length_counter == 0 || !enabled || pulse.mute {
-            sink.pause();
+    /// current level of the channel, between 0 and 15
+    pub fn output(&self) -> u8 {
+        let duty = DUTY_TABLE[self.reg.duty() as usize][self.sequence_step];
+        if duty == 0 || self.mute || self.length_counter == 0 {
+            0
         } else {
-            sink.play();
+            self.volume()
         }
     }
 }
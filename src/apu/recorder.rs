@@ -0,0 +1,232 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use super::{blip::BlipBuffer, filter::OutputFilter, stream::SAMPLE_RATE, Apu, FilterProfile};
+
+/// names of the channel stems, used as a suffix of the recording file name
+pub const STEMS: [&str; 6] = ["pulse1", "pulse2", "triangle", "noise", "dmc", "expansion"];
+
+/// 16 bit mono PCM WAV file. The header sizes are kept up to date every second so the
+/// file stays readable if the emulator is killed while recording.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&36u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM, 1 channel
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        file.write_all(&(SAMPLE_RATE * 2).to_le_bytes())?;
+        // block align and bits per sample
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { file, samples: 0 })
+    }
+
+    pub fn write(&mut self, sample: f32) -> std::io::Result<()> {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        self.file.write_all(&sample.to_le_bytes())?;
+        self.samples += 1;
+        if self.samples.is_multiple_of(SAMPLE_RATE) {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> std::io::Result<()> {
+        let data_size = self.samples * 2;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.update_header()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// A channel recorded to its own file, with its own band-limited buffer and filters.
+struct Stem {
+    blip: BlipBuffer,
+    filter: OutputFilter,
+    writer: WavWriter,
+}
+
+/// Records the mixed APU output, and optionally each channel separately.
+pub struct Recorder {
+    path: PathBuf,
+    mix: WavWriter,
+    stems: Vec<Stem>,
+}
+
+impl Recorder {
+    pub fn new(
+        path: &Path,
+        stems: bool,
+        profile: FilterProfile,
+        cpu_hz: f64,
+    ) -> std::io::Result<Self> {
+        let stems = if stems {
+            STEMS
+                .iter()
+                .map(|name| {
+                    Ok(Stem {
+                        blip: BlipBuffer::new(cpu_hz),
+                        filter: OutputFilter::new(profile),
+                        writer: WavWriter::create(&stem_path(path, name))?,
+                    })
+                })
+                .collect::<std::io::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        info!("Recording audio to {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            mix: WavWriter::create(path)?,
+            stems,
+        })
+    }
+
+    /// Called once per CPU cycle with the output sample, if one was produced, and the
+    /// level of each channel in the order of `STEMS`.
+    pub fn write(&mut self, sample: Option<f32>, levels: [f32; 6]) -> std::io::Result<()> {
+        if let Some(sample) = sample {
+            self.mix.write(sample)?;
+        }
+        for (stem, level) in self.stems.iter_mut().zip(levels) {
+            if let Some(sample) = stem.blip.push(level) {
+                stem.writer.write(stem.filter.process(sample))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.mix.finish()?;
+        for stem in self.stems.iter_mut() {
+            stem.writer.finish()?;
+        }
+        info!("Stopped recording audio to {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// `music.wav` becomes `music.pulse1.wav`
+fn stem_path(path: &Path, name: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.wav", stem, name))
+}
+
+#[derive(Resource)]
+pub struct RecorderState {
+    path: String,
+    stems: bool,
+    vgm_path: String,
+}
+
+impl Default for RecorderState {
+    fn default() -> Self {
+        Self {
+            path: "recording.wav".to_string(),
+            stems: false,
+            vgm_path: "recording.vgm".to_string(),
+        }
+    }
+}
+
+pub fn recorder_gui(
+    mut query: Query<&mut Apu>,
+    mut state: ResMut<RecorderState>,
+    mut contexts: EguiContexts,
+) {
+    egui::Window::new("Audio Recording").show(contexts.ctx_mut(), |ui| {
+        let Ok(mut apu) = query.get_single_mut() else {
+            ui.label("No APU found");
+            return;
+        };
+        let recording = apu.recording();
+        ui.add_enabled_ui(!recording, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("file");
+                ui.text_edit_singleline(&mut state.path);
+            });
+            ui.checkbox(&mut state.stems, "channel stems");
+        });
+        if recording {
+            if ui.button("stop").clicked() {
+                apu.stop_recording();
+            }
+        } else if ui.button("record").clicked() {
+            apu.start_recording(Path::new(&state.path), state.stems)
+                .unwrap_or_else(|err| error!("Failed to start recording: {}", err));
+        }
+
+        ui.separator();
+        let logging = apu.logging_vgm();
+        ui.add_enabled_ui(!logging, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("VGM file");
+                ui.text_edit_singleline(&mut state.vgm_path);
+            });
+        });
+        if logging {
+            if ui.button("stop VGM log").clicked() {
+                apu.stop_vgm();
+            }
+        } else if ui.button("log VGM").clicked() {
+            apu.start_vgm(Path::new(&state.vgm_path));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WavWriter;
+
+    #[test]
+    fn wav_header() {
+        let path = std::env::temp_dir().join("nes-rs-wav-header.wav");
+        let mut writer = WavWriter::create(&path).unwrap();
+        writer.write(0.0).unwrap();
+        writer.write(1.0).unwrap();
+        writer.write(-1.0).unwrap();
+        drop(writer);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 6);
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 6);
+        assert_eq!(i16::from_le_bytes([bytes[46], bytes[47]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([bytes[48], bytes[49]]), -i16::MAX);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::{
+    audio::{AddAudioSource, Source},
+    prelude::*,
+};
+
+use super::Apu;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// samples beyond this are dropped, the emulation is running ahead of the audio device
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 4;
+
+/// queue length the rate control aims for, 50 ms of latency
+const TARGET_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 20;
+
+/// largest deviation from the nominal sample rate, small enough not to be heard as pitch
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Samples produced by the emulation waiting to be played by the audio device.
+#[derive(Clone, Default)]
+pub struct SampleQueue(Arc<Mutex<VecDeque<f32>>>);
+
+impl SampleQueue {
+    pub fn push(&self, sample: f32) {
+        let mut queue = self.0.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_SAMPLES {
+            queue.pop_front();
+        }
+        queue.push_back(sample);
+    }
+
+    pub fn pop(&self) -> Option<f32> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    /// Dynamic rate control: the emulation produces slightly more samples when the queue
+    /// runs below its target and slightly less above it, so neither the audio device nor
+    /// the display clock has to match the emulated one. Returns the factor to apply to the
+    /// resampling ratio.
+    pub fn rate_adjustment(&self) -> f64 {
+        let queued = self.0.lock().unwrap().len() as f64;
+        let target = TARGET_QUEUED_SAMPLES as f64;
+        1.0 + ((target - queued) / target).clamp(-1.0, 1.0) * MAX_RATE_DELTA
+    }
+}
+
+#[derive(Asset, TypePath)]
+struct ApuStream {
+    queue: SampleQueue,
+}
+
+struct ApuStreamDecoder {
+    queue: SampleQueue,
+    last: f32,
+}
+
+impl Iterator for ApuStreamDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // hold the last sample when starved instead of ending the stream
+        if let Some(sample) = self.queue.pop() {
+            self.last = sample;
+        }
+        Some(self.last)
+    }
+}
+
+impl Source for ApuStreamDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Decodable for ApuStream {
+    type DecoderItem = f32;
+
+    type Decoder = ApuStreamDecoder;
+
+    fn decoder(&self) -> Self::Decoder {
+        ApuStreamDecoder {
+            queue: self.queue.clone(),
+            last: 0.0,
+        }
+    }
+}
+
+pub struct ApuStreamPlugin;
+
+impl Plugin for ApuStreamPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<ApuStream>()
+            .add_systems(PostStartup, setup_stream);
+    }
+}
+
+fn setup_stream(mut commands: Commands, mut assets: ResMut<Assets<ApuStream>>, apu: Query<&Apu>) {
+    if let Ok(apu) = apu.get_single() {
+        let source = assets.add(ApuStream {
+            queue: apu.queue.clone(),
+        });
+        commands.spawn(AudioSourceBundle {
+            source,
+            ..default()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SampleQueue, MAX_RATE_DELTA, TARGET_QUEUED_SAMPLES};
+
+    #[test]
+    fn rate_adjustment() {
+        let queue = SampleQueue::default();
+        assert_eq!(queue.rate_adjustment(), 1.0 + MAX_RATE_DELTA);
+        for _ in 0..TARGET_QUEUED_SAMPLES {
+            queue.push(0.0);
+        }
+        assert_eq!(queue.rate_adjustment(), 1.0);
+        for _ in 0..TARGET_QUEUED_SAMPLES * 2 {
+            queue.push(0.0);
+        }
+        assert_eq!(queue.rate_adjustment(), 1.0 - MAX_RATE_DELTA);
+    }
+}
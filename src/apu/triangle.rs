@@ -1,101 +1,84 @@
-use bevy::prelude::*;
-use bevy_fundsp::prelude::*;
-use uuid::Uuid;
-
-use super::{Apu, CPU_HZ};
-
-#[derive(Component)]
-struct TriangleMarker;
-
-#[derive(Resource)]
-struct TriangleVar {
-    hz: Shared,
+use bitfield::bitfield;
+
+use super::LENGTH_COUNTER_TABLE;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+bitfield! {
+    #[derive(Default)]
+    pub struct TriangleRegister(u32);
+    impl Debug;
+    pub linear_counter, set_linear_counter: 6, 0;
+    pub linear_control, set_linear_control: 7, 7;
+    pub length_counter_halt, set_length_counter_halt: 7, 7;
+    pub timer, set_timer: 26, 16;
+    pub length_counter, set_length_counter: 31, 27;
 }
 
-#[derive(Resource)]
-struct TriangleId(Uuid);
-
-struct TriangleDsp {
-    uuid: Uuid,
-    hz: Shared,
+#[derive(Default)]
+pub struct Triangle {
+    pub reg: TriangleRegister,
+    pub length_counter: u8,
+    pub linear_counter_reload: bool,
+    pub linear_counter: u8,
+    timer_counter: u32,
+    pub sequence_step: usize,
 }
 
-impl TriangleDsp {
-    fn new(hz: Shared) -> Self {
-        Self {
-            uuid: Uuid::new_v4(),
-            hz,
+impl Triangle {
+    /// writes one of the 4 registers of the channel, `enabled` being its $4015 bit
+    pub fn write(&mut self, reg: u16, data: u8, enabled: bool) {
+        let shift = (reg & 0x03) * 8;
+        self.reg.0 &= !(0xFFu32 << shift);
+        self.reg.0 |= (data as u32) << shift;
+        if reg & 0x03 == 0x03 {
+            if enabled {
+                self.length_counter = LENGTH_COUNTER_TABLE[self.reg.length_counter() as usize];
+            }
+            self.linear_counter_reload = true;
         }
     }
-}
 
-impl DspGraph for TriangleDsp {
-    fn id(&self) -> Uuid {
-        self.uuid
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.reg.linear_counter() as u8;
+        } else {
+            self.linear_counter = self.linear_counter.saturating_sub(1);
+        }
+        if self.reg.linear_control() == 0 {
+            self.linear_counter_reload = false;
+        }
     }
 
-    fn generate_graph(&self) -> Box<dyn AudioUnit> {
-        Box::new(var(&self.hz) >> triangle() >> declick_s(0.1) >> split::<U2>() * 0.4)
+    pub fn clock_length_counter(&mut self) {
+        if self.reg.length_counter_halt() == 0 {
+            self.length_counter = self.length_counter.saturating_sub(1);
+        }
     }
-}
 
-pub struct TrianglePlugin;
-
-impl Plugin for TrianglePlugin {
-    fn build(&self, app: &mut App) {
-        let hz = shared(440.0);
-        let pulse_var = TriangleVar { hz: hz.clone() };
-
-        let pulse_dsp = TriangleDsp::new(hz.clone());
-        let pulse_id = pulse_dsp.id();
-
-        app.add_dsp_source(pulse_dsp, SourceType::Dynamic)
-            .insert_resource(pulse_var)
-            .insert_resource(TriangleId(pulse_id))
-            .add_systems(PostStartup, setup_triangle)
-            .add_systems(FixedPostUpdate, update_triangle);
+    /// clocked once per CPU cycle, the sequencer only moves while both counters are
+    /// non zero
+    pub fn tick(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.reg.timer();
+            if self.linear_counter > 0 && self.length_counter > 0 {
+                self.sequence_step = (self.sequence_step + 1) & 0x1F;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
     }
-}
-
-fn setup_triangle(
-    mut commands: Commands,
-    mut assets: ResMut<Assets<DspSource>>,
-    dsp_manager: Res<DspManager>,
-    triangle_id: Res<TriangleId>,
-) {
-    let source = assets.add(
-        dsp_manager
-            .get_graph_by_id(&triangle_id.0)
-            .expect("Triangle DSP source not found"),
-    );
-
-    commands.spawn((
-        AudioSourceBundle {
-            source,
-            settings: PlaybackSettings {
-                paused: true,
-                ..default()
-            },
-        },
-        TriangleMarker,
-    ));
-}
-
-fn update_triangle(
-    apu: Query<&Apu>,
-    sink: Query<(&mut AudioSink, &TriangleMarker)>,
-    triangle_var: Res<TriangleVar>,
-) {
-    if let (Ok(apu), Ok((sink, _))) = (apu.get_single(), sink.get_single()) {
-        let triangle = &apu.triangle;
-        let hz = CPU_HZ / (32.0 * ((triangle.reg.timer() as f32) + 1.0));
-        triangle_var.hz.set(hz);
-        let enabled = apu.status.triangle();
 
-        if !enabled || triangle.length_counter == 0 || triangle.linear_counter == 0 {
-            sink.pause();
+    /// current level of the channel, between 0 and 15
+    pub fn output(&self) -> u8 {
+        // ultrasonic periods hold the middle of the waveform rather than aliasing
+        if self.reg.timer() < 2 {
+            7
         } else {
-            sink.play();
+            SEQUENCE[self.sequence_step]
         }
     }
 }
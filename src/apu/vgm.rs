@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use super::stream::SAMPLE_RATE;
+
+const HEADER_SIZE: usize = 0xC0;
+
+/// Logs APU register writes with their timing to a VGM 1.61 file. DMC sample bytes are
+/// sent to the player as RAM data blocks before they are played.
+pub struct VgmLogger {
+    path: PathBuf,
+    data: Vec<u8>,
+    /// CPU clock, stored in the header for the player to emulate the right region
+    cpu_hz: u64,
+    cycles: u64,
+    samples: u64,
+    fds: bool,
+    /// $8000-$FFFF as last sent to the player
+    memory: Vec<Option<u8>>,
+    /// start address and bytes of a RAM block not written yet
+    block: Option<(u16, Vec<u8>)>,
+    finished: bool,
+}
+
+impl VgmLogger {
+    pub fn new(path: &Path, cpu_hz: f64) -> Self {
+        info!("Logging APU writes to {}", path.display());
+        Self {
+            path: path.to_path_buf(),
+            data: Vec::new(),
+            cpu_hz: cpu_hz.round() as u64,
+            cycles: 0,
+            samples: 0,
+            fds: false,
+            memory: vec![None; 0x8000],
+            block: None,
+            finished: false,
+        }
+    }
+
+    /// clocked once per CPU cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    /// Logs a write to the 2A03 registers at $4000-$4017, or to the Disk System sound
+    /// registers. Other addresses are ignored.
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let reg = match addr {
+            0x4000..=0x401F => addr - 0x4000,
+            0x4080..=0x409E => addr - 0x4080 + 0x20,
+            0x4023 => 0x3F,
+            0x4040..=0x407F => addr,
+            _ => return,
+        };
+        if reg >= 0x20 {
+            self.fds = true;
+        }
+        self.flush();
+        self.data.extend_from_slice(&[0xB4, reg as u8, data]);
+    }
+
+    /// Records a byte of the DMC sample memory, only sent when the player does not have it.
+    pub fn write_memory(&mut self, addr: u16, data: u8) {
+        let index = (addr & 0x7FFF) as usize;
+        if self.memory[index] == Some(data) {
+            return;
+        }
+        self.memory[index] = Some(data);
+        match &mut self.block {
+            Some((start, bytes)) if start.wrapping_add(bytes.len() as u16) == addr => {
+                bytes.push(data)
+            }
+            _ => {
+                self.flush();
+                self.block = Some((addr, vec![data]));
+            }
+        }
+    }
+
+    fn write_block(&mut self) {
+        if let Some((start, bytes)) = self.block.take() {
+            // data block of type $C2, NES APU RAM, prefixed with its start address
+            self.data.extend_from_slice(&[0x67, 0x66, 0xC2]);
+            self.data
+                .extend_from_slice(&(bytes.len() as u32 + 2).to_le_bytes());
+            self.data.extend_from_slice(&start.to_le_bytes());
+            self.data.extend_from_slice(&bytes);
+        }
+    }
+
+    /// emits the pending RAM block and the wait commands up to the current cycle
+    fn flush(&mut self) {
+        self.write_block();
+        let target = self.cycles * SAMPLE_RATE as u64 / self.cpu_hz;
+        let mut wait = target - self.samples;
+        self.samples = target;
+        while wait > 0 {
+            match wait {
+                735 => self.data.push(0x62),
+                882 => self.data.push(0x63),
+                1..=16 => self.data.push(0x70 + wait as u8 - 1),
+                _ => {
+                    let samples = wait.min(0xFFFF) as u16;
+                    self.data.push(0x61);
+                    self.data.extend_from_slice(&samples.to_le_bytes());
+                    wait -= samples as u64;
+                    continue;
+                }
+            }
+            wait = 0;
+        }
+    }
+
+    /// terminates the command stream and returns the whole file
+    fn end(&mut self) -> Vec<u8> {
+        self.flush();
+        self.data.push(0x66);
+
+        let mut bytes = vec![0x00; HEADER_SIZE];
+        let mut field = |offset: usize, value: u32| {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        field(0x04, (HEADER_SIZE + self.data.len() - 4) as u32);
+        field(0x08, 0x161);
+        field(0x18, self.samples as u32);
+        field(0x24, 60);
+        // relative to the field itself
+        field(0x34, (HEADER_SIZE - 0x34) as u32);
+        field(0x84, self.cpu_hz as u32 | (self.fds as u32) << 31);
+        bytes[0..4].copy_from_slice(b"Vgm ");
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// writes the file, only the first call has an effect
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let bytes = self.end();
+        std::fs::write(&self.path, bytes)?;
+        info!("Saved VGM log to {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for VgmLogger {
+    fn drop(&mut self) {
+        self.finish()
+            .unwrap_or_else(|err| error!("Failed to save VGM log: {}", err));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VgmLogger;
+
+    #[test]
+    fn vgm_commands() {
+        let mut logger = VgmLogger::new(std::path::Path::new("test.vgm"), 1789773.0);
+        logger.write(0x4015, 0x01);
+        for _ in 0..1789773 {
+            logger.tick();
+        }
+        logger.write_memory(0xFFFF, 0xAA);
+        logger.write_memory(0x8000, 0x55);
+        logger.write_memory(0x8000, 0x55);
+        logger.write(0x4000, 0xBF);
+        logger.write(0x4080, 0x80);
+        logger.finished = true;
+
+        let bytes = logger.end();
+        assert_eq!(&bytes[0..4], b"Vgm ");
+        assert_eq!(bytes[0x18..0x1C], 44100u32.to_le_bytes());
+        assert_eq!(bytes[0x84..0x88], (1789773u32 | 0x80000000).to_le_bytes());
+        assert_eq!(
+            bytes[0xC0..],
+            [
+                0xB4, 0x15, 0x01, // $4015
+                0x61, 0x44, 0xAC, // one second
+                0x67, 0x66, 0xC2, 0x03, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xAA, // RAM at $FFFF
+                0x67, 0x66, 0xC2, 0x03, 0x00, 0x00, 0x00, 0x00, 0x80, 0x55, // RAM at $8000
+                0xB4, 0x00, 0xBF, // $4000
+                0xB4, 0x20, 0x80, // $4080
+                0x66,
+            ]
+        );
+    }
+}
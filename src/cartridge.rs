@@ -3,25 +3,34 @@ use std::io::Read;
 use bevy::prelude::*;
 use bevy_egui::egui::{ScrollArea, Separator};
 use bevy_egui::{egui, EguiContexts};
-use mapper::{build_mapper, Mapper};
+use mapper::build_mapper;
+pub use mapper::Mapper;
 
+mod database;
 mod mapper;
 
 use thiserror::Error;
 
-use crate::nes::NesMarker;
+use crate::{mem::Mem, nes::NesMarker, region::Region, vs::VsPpu};
 
 #[derive(Default, Debug, PartialEq)]
 pub struct CartridgeHeader {
-    prg_rom_banks: u8,
+    prg_rom_banks: u16,
     prg_ram_banks: u8,
-    chr_rom_banks: u8,
-    mapper_id: u8,
+    prg_ram_size: usize,
+    chr_rom_banks: u16,
+    chr_ram_size: usize,
+    mapper_id: u16,
+    submapper: u8,
     four_screen: bool,
     trainer: bool,
     battery: bool,
     console_type: ConsoleType,
     mirroring: Mirroring,
+    /// `None` for multi-region boards and headers without timing information
+    region: Option<Region>,
+    /// Vs. System PPU type, only given by NES 2.0 headers
+    vs_ppu: Option<VsPpu>,
 }
 
 #[derive(Default, Debug, PartialEq, Clone, Copy)]
@@ -31,6 +40,32 @@ pub enum Mirroring {
     Vertical = 0x02,
     OneScreenLo = 0x00,
     OneScreenHi = 0x01,
+    FourScreen = 0x04,
+}
+
+/// Backing memory of one of the four 1 KiB nametable slots at $2000-$2FFF.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NametableSource {
+    /// one of the two pages of the console internal VRAM
+    Ciram(usize),
+    /// a page of VRAM located on the cartridge board
+    CartridgeRam(usize),
+    /// a 1 KiB page of CHR-ROM
+    ChrRom(usize),
+}
+
+impl Mirroring {
+    pub fn nametable(&self, addr: u16) -> NametableSource {
+        match self {
+            Mirroring::Horizontal => NametableSource::Ciram(((addr & 0x0800) >> 11) as usize),
+            Mirroring::Vertical => NametableSource::Ciram(((addr & 0x0400) >> 10) as usize),
+            Mirroring::OneScreenLo => NametableSource::Ciram(0),
+            Mirroring::OneScreenHi => NametableSource::Ciram(1),
+            Mirroring::FourScreen => {
+                NametableSource::CartridgeRam(((addr & 0x0C00) >> 10) as usize)
+            }
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -54,6 +89,7 @@ impl CartridgeHeader {
     pub fn with_mirroring(mirroring: Mirroring) -> Self {
         let mut h = Self::default();
         h.mirroring = mirroring;
+        h.four_screen = mirroring == Mirroring::FourScreen;
         h
     }
 
@@ -73,14 +109,20 @@ impl CartridgeHeader {
     fn parse_ines(flags: &[u8; 16]) -> Result<Self, HeaderError> {
         debug!("Parsing iNES header");
         Ok(CartridgeHeader {
-            prg_rom_banks: flags[4],
-            chr_rom_banks: flags[5],
+            prg_rom_banks: flags[4] as u16,
+            chr_rom_banks: flags[5] as u16,
             prg_ram_banks: flags[8],
-            mapper_id: ((flags[6] & 0xF0) >> 4) | (flags[7] & 0xF0),
+            // a size of 0 means 8 KiB for compatibility
+            prg_ram_size: flags[8].max(1) as usize * 0x2000,
+            chr_ram_size: if flags[5] == 0 { 0x2000 } else { 0 },
+            mapper_id: (((flags[6] & 0xF0) >> 4) | (flags[7] & 0xF0)) as u16,
+            submapper: 0,
             four_screen: flags[6] & 0x08 != 0,
             trainer: flags[6] & 0x04 != 0,
             battery: flags[6] & 0x02 == 0,
-            mirroring: if flags[6] & 0x01 != 0 {
+            mirroring: if flags[6] & 0x08 != 0 {
+                Mirroring::FourScreen
+            } else if flags[6] & 0x01 != 0 {
                 Mirroring::Vertical
             } else {
                 Mirroring::Horizontal
@@ -90,11 +132,56 @@ impl CartridgeHeader {
                 0x02 => ConsoleType::Playchoice,
                 _ => ConsoleType::Nes,
             },
+            region: None,
+            vs_ppu: None,
         })
     }
 
-    fn parse_nes2(_bytes: &[u8; 16]) -> Result<CartridgeHeader, HeaderError> {
-        unimplemented!("nes2.0 header parsing not implemented");
+    fn parse_nes2(flags: &[u8; 16]) -> Result<CartridgeHeader, HeaderError> {
+        debug!("Parsing NES 2.0 header");
+        let mut header = Self::parse_ines(flags)?;
+        header.prg_rom_banks = rom_banks(flags[4], flags[9] & 0x0F, 0x4000);
+        header.chr_rom_banks = rom_banks(flags[5], flags[9] >> 4, 0x2000);
+        header.mapper_id |= ((flags[8] & 0x0F) as u16) << 8;
+        header.submapper = flags[8] >> 4;
+        // bytes 10 and 11 hold the volatile and non volatile RAM sizes as shift counts
+        header.prg_ram_size = ram_size(flags[10]);
+        header.prg_ram_banks = header.prg_ram_size.div_ceil(0x2000) as u8;
+        header.chr_ram_size = ram_size(flags[11]);
+        header.console_type = match flags[7] & 0x03 {
+            0x01 => ConsoleType::VsSystem,
+            0x02 => ConsoleType::Playchoice,
+            0x03 => ConsoleType::Extended,
+            _ => ConsoleType::Nes,
+        };
+        header.region = match flags[12] & 0x03 {
+            0x00 => Some(Region::Ntsc),
+            0x01 => Some(Region::Pal),
+            0x03 => Some(Region::Dendy),
+            _ => None,
+        };
+        if header.console_type == ConsoleType::VsSystem {
+            header.vs_ppu = Some(VsPpu::from_header(flags[13]));
+        }
+        Ok(header)
+    }
+}
+
+fn ram_size(shifts: u8) -> usize {
+    [shifts & 0x0F, shifts >> 4]
+        .iter()
+        .filter(|&&shift| shift != 0)
+        .map(|&shift| 64usize << shift)
+        .sum()
+}
+
+/// NES 2.0 ROM sizes, an MSB nibble of 0xF switches to the exponent-multiplier notation.
+fn rom_banks(lsb: u8, msb: u8, bank_size: usize) -> u16 {
+    if msb == 0x0F {
+        let size = (1usize << (lsb >> 2)) * ((lsb & 0x03) as usize * 2 + 1);
+        size.div_ceil(bank_size) as u16
+    } else {
+        ((msb as u16) << 8) | lsb as u16
     }
 }
 
@@ -102,19 +189,65 @@ impl CartridgeHeader {
 pub struct Cartridge {
     header: CartridgeHeader,
     mapper: Box<dyn Mapper>,
+    vram: Vec<Mem<0x400>>,
 }
 
 impl Cartridge {
     #[cfg(test)]
     pub fn testing(header: Option<CartridgeHeader>) -> Self {
-        let mapper = mapper::dummy();
-        let header = header.unwrap_or(CartridgeHeader::default());
+        Self::testing_with_mapper(header.unwrap_or_default(), mapper::dummy())
+    }
 
-        Self { header, mapper }
+    #[cfg(test)]
+    pub fn testing_with_mapper(header: CartridgeHeader, mapper: Box<dyn Mapper>) -> Self {
+        let vram = board_vram(&header);
+
+        Self {
+            header,
+            mapper,
+            vram,
+        }
     }
 
     pub fn mirroring(&self) -> Mirroring {
-        self.mapper.mirroring().unwrap_or(self.header.mirroring)
+        if self.header.four_screen {
+            Mirroring::FourScreen
+        } else {
+            self.mapper.mirroring().unwrap_or(self.header.mirroring)
+        }
+    }
+
+    /// resolve the memory backing the nametable slot of `addr`, the mapper can override
+    /// any slot, otherwise the mirroring mode decides.
+    pub fn nametable(&self, addr: u16) -> NametableSource {
+        self.mapper
+            .nametable(addr)
+            .unwrap_or_else(|| self.mirroring().nametable(addr))
+    }
+
+    /// read a nametable byte held by the cartridge, CIRAM sources are left to the PPU.
+    pub fn nametable_read(&self, source: NametableSource, addr: u16) -> Option<u8> {
+        match source {
+            NametableSource::Ciram(_) => None,
+            NametableSource::CartridgeRam(page) => self.vram.get(page).map(|page| page.read(addr)),
+            NametableSource::ChrRom(page) => self.mapper.chr_rom_read(page, addr),
+        }
+    }
+
+    #[must_use]
+    pub fn nametable_write(&mut self, source: NametableSource, addr: u16, data: u8) -> bool {
+        match source {
+            NametableSource::CartridgeRam(page) => match self.vram.get_mut(page) {
+                Some(page) => {
+                    page.write(addr, data);
+                    true
+                }
+                None => false,
+            },
+            // CHR-ROM pages are read only but the write still lands on the cartridge
+            NametableSource::ChrRom(_) => true,
+            NametableSource::Ciram(_) => false,
+        }
     }
 
     pub fn cpu_read(&self, addr: u16) -> Option<u8> {
@@ -135,13 +268,30 @@ impl Cartridge {
         self.mapper.ppu_map_write(addr, data)
     }
 
+    /// CPU/PPU timing the game was made for, as given by the header
+    pub fn region(&self) -> Option<Region> {
+        self.header.region
+    }
+
+    /// PPU of the Vs. System board, `None` for home console games
+    pub fn vs_ppu(&self) -> Option<VsPpu> {
+        (self.header.console_type == ConsoleType::VsSystem)
+            .then(|| self.header.vs_ppu.unwrap_or_default())
+    }
+
+    /// force bus conflicts on or off regardless of what the header says.
+    pub fn set_bus_conflicts(&mut self, enabled: bool) {
+        info!("Bus conflicts overridden: {}", enabled);
+        self.mapper.set_bus_conflicts(enabled);
+    }
+
     pub fn from_file(file: &str) -> Result<Self, HeaderError> {
         let f = std::fs::File::open(file)?;
         let mut reader = std::io::BufReader::new(f);
 
         let mut buffer = [0; 16];
         reader.read_exact(&mut buffer)?;
-        let header = CartridgeHeader::from_bytes(&buffer)?;
+        let mut header = CartridgeHeader::from_bytes(&buffer)?;
 
         if header.trainer {
             debug!("Reading trainer");
@@ -149,9 +299,41 @@ impl Cartridge {
             reader.read_exact(&mut trainer)?;
         }
 
-        info!("Mapper ID {}", header.mapper_id);
-        let mapper = build_mapper(&header, reader)?;
-        Ok(Self { mapper, header })
+        info!(
+            "Mapper ID {} (submapper {})",
+            header.mapper_id, header.submapper
+        );
+        let mut rom = Vec::new();
+        reader.read_to_end(&mut rom)?;
+        let prg_size = (header.prg_rom_banks as usize * 0x4000).min(rom.len());
+        let prg_crc = database::crc32(&rom[..prg_size]);
+        info!("PRG-ROM CRC32: {:08X}", prg_crc);
+
+        let entry = database::lookup(prg_crc).unwrap_or_default();
+        header.region = header.region.or(entry.region);
+        header.vs_ppu = header.vs_ppu.or(entry.vs_ppu);
+        if header.vs_ppu.is_some() {
+            header.console_type = ConsoleType::VsSystem;
+        }
+        let mut mapper = build_mapper(&header, rom.as_slice())?;
+        if let Some(enabled) = entry.bus_conflicts {
+            mapper.set_bus_conflicts(enabled);
+        }
+        let vram = board_vram(&header);
+        Ok(Self {
+            mapper,
+            header,
+            vram,
+        })
+    }
+}
+
+/// extra nametable memory present on the board, four-screen carts bring 4 KiB of their own.
+fn board_vram(header: &CartridgeHeader) -> Vec<Mem<0x400>> {
+    if header.four_screen {
+        vec![Mem::default(); 4]
+    } else {
+        Vec::new()
     }
 }
 
@@ -166,6 +348,7 @@ pub fn cartridge_gui(
             .show(contexts.ctx_mut(), |ui| match maybe_cartridge {
                 Some(cartridge) => {
                     ui.heading(format!("mapper {}", cartridge.header.mapper_id));
+                    ui.monospace(format!("mirroring: {:?}", cartridge.mirroring()));
                     ui.separator();
                     cartridge.mapper.ui(ui);
                     ui.separator();
@@ -0,0 +1,123 @@
+use std::path::Path;
+
+use bevy::log::{info, warn};
+use clap::ValueEnum;
+
+use crate::{region::Region, vs::VsPpu};
+
+/// Per-game settings that iNES 1.0 headers cannot describe, one game per line as the
+/// CRC32 of its PRG-ROM followed by `key=value` pairs. `#` starts a comment.
+const DATABASE_PATH: &str = "assets/db/games.txt";
+
+#[derive(Default, Debug, PartialEq)]
+pub struct GameEntry {
+    pub bus_conflicts: Option<bool>,
+    /// timing of games released for a single region, for headers without it
+    pub region: Option<Region>,
+    /// PPU of Vs. System games, iNES 1.0 headers cannot tell the RP2C04 and RC2C05 apart
+    pub vs_ppu: Option<VsPpu>,
+}
+
+impl GameEntry {
+    fn parse(fields: &str) -> Self {
+        let mut entry = Self::default();
+        for field in fields.split_whitespace() {
+            match field.split_once('=') {
+                Some(("bus_conflicts", value)) => entry.bus_conflicts = value.parse().ok(),
+                Some(("region", value)) => entry.region = Region::from_str(value, true).ok(),
+                Some(("vs_ppu", value)) => entry.vs_ppu = VsPpu::from_str(value, true).ok(),
+                _ => warn!("Unknown game database field {}", field),
+            }
+        }
+        entry
+    }
+}
+
+/// CRC32 (IEEE) as used by the usual NES game databases.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+fn find(database: &str, prg_crc: u32) -> Option<GameEntry> {
+    database
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter_map(|line| {
+            let (crc, fields) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let crc = u32::from_str_radix(crc, 16).ok()?;
+            (crc == prg_crc).then(|| GameEntry::parse(fields))
+        })
+        .next()
+}
+
+/// Looks the game up in the database file, a missing file meaning no overrides.
+pub fn lookup(prg_crc: u32) -> Option<GameEntry> {
+    let database = std::fs::read_to_string(Path::new(DATABASE_PATH)).ok()?;
+    let entry = find(&database, prg_crc);
+    if let Some(entry) = &entry {
+        info!("Game database entry for {:08X}: {:?}", prg_crc, entry);
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, find, GameEntry};
+    use crate::{region::Region, vs::VsPpu};
+
+    #[test]
+    fn game_entries() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let database = "\
+            # a comment\n\
+            0BADF00D bus_conflicts=true # some game\n\
+            cafe0001 bus_conflicts=false\n\
+            cafe0002 region=pal bus_conflicts=false\n";
+        assert_eq!(
+            find(database, 0x0BADF00D),
+            Some(GameEntry {
+                bus_conflicts: Some(true),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            find(database, 0xCAFE0001).and_then(|entry| entry.bus_conflicts),
+            Some(false)
+        );
+        assert_eq!(
+            find(database, 0xCAFE0002),
+            Some(GameEntry {
+                bus_conflicts: Some(false),
+                region: Some(Region::Pal),
+                vs_ppu: None,
+            })
+        );
+        assert_eq!(find(database, 0x12345678), None);
+    }
+
+    #[test]
+    fn vs_ppu_entries() {
+        let database = "\
+            cafe0003 vs_ppu=rp2c04-0003 # Vs. Super Mario Bros.\n\
+            cafe0004 vs_ppu=RC2C05-02\n\
+            cafe0005 vs_ppu=rp2c07\n";
+        assert_eq!(
+            find(database, 0xCAFE0003).and_then(|entry| entry.vs_ppu),
+            Some(VsPpu::Rp2c04V3)
+        );
+        assert_eq!(
+            find(database, 0xCAFE0004).and_then(|entry| entry.vs_ppu),
+            Some(VsPpu::Rc2c05V2)
+        );
+        // unknown PPUs are left to the header
+        assert_eq!(
+            find(database, 0xCAFE0005).map(|entry| entry.vs_ppu),
+            Some(None)
+        );
+    }
+}
@@ -1,12 +1,15 @@
 use std::io::BufRead;
 
-use super::{CartridgeHeader, HeaderError, Mirroring};
+use super::{CartridgeHeader, HeaderError, Mirroring, NametableSource};
 use bevy_egui::egui::Ui;
 
+mod axrom;
+mod cnrom;
 mod dummy;
 mod mmc1;
 mod nrom;
 mod uxrom;
+mod vs_unisystem;
 
 pub trait Mapper: Send + Sync {
     fn cpu_map_read(&self, addr: u16) -> Option<u8>;
@@ -15,6 +18,40 @@ pub trait Mapper: Send + Sync {
     fn ppu_map_write(&mut self, addr: u16, data: u8) -> bool;
     fn mirroring(&self) -> Option<Mirroring>;
     fn ui(&self, ui: &mut Ui);
+
+    /// override the memory backing the nametable slot of `addr`, `None` defers to mirroring.
+    fn nametable(&self, _addr: u16) -> Option<NametableSource> {
+        None
+    }
+
+    /// read from a 1 KiB CHR-ROM page mapped as a nametable.
+    fn chr_rom_read(&self, _page: usize, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// only discrete latch boards can have bus conflicts, others ignore this.
+    fn set_bus_conflicts(&mut self, _enabled: bool) {}
+}
+
+/// On discrete boards without conflict prevention the PRG-ROM keeps driving the data bus
+/// while the CPU writes to it, so the latch receives the AND of the written value and
+/// the ROM byte at that address.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct BusConflicts(bool);
+
+impl BusConflicts {
+    /// NES 2.0 submapper 2 is used by UxROM, CNROM and AxROM to flag AND-type bus conflicts,
+    /// submapper 0 and 1 behave as if the board prevents them.
+    pub fn from_header(header: &CartridgeHeader) -> Self {
+        Self(header.submapper == 2)
+    }
+
+    pub fn apply(&self, rom: Option<u8>, data: u8) -> u8 {
+        match (self.0, rom) {
+            (true, Some(rom)) => data & rom,
+            _ => data,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -30,6 +67,9 @@ pub fn build_mapper(
         0x00 => nrom::build_nrom_mapper(cartridge, reader),
         0x01 => mmc1::build_mmc1_mapper(cartridge, reader),
         0x02 => uxrom::build_uxrom_mapper(cartridge, reader),
+        0x03 => cnrom::build_cnrom_mapper(cartridge, reader),
+        0x07 => axrom::build_axrom_mapper(cartridge, reader),
+        0x63 => vs_unisystem::build_vs_unisystem_mapper(cartridge, reader),
         _ => todo!("mapper {} is not implemented yet", cartridge.mapper_id),
     };
 
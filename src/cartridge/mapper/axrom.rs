@@ -0,0 +1,156 @@
+use std::io::BufRead;
+
+use bevy::log::info;
+
+use super::{BusConflicts, Mapper};
+use crate::{
+    cartridge::{CartridgeHeader, Mirroring},
+    mem::Mem,
+};
+
+pub fn build_axrom_mapper(header: &CartridgeHeader, mut reader: impl BufRead) -> Box<dyn Mapper> {
+    // AxROM switches 32 KiB at a time, an odd number of 16 KiB iNES units leaves a
+    // half bank that is mirrored to fill the whole window
+    info!("PRG banks: {}", header.prg_rom_banks);
+    let mut prg_rom = vec![0x00; header.prg_rom_banks as usize * 0x4000];
+    reader.read_exact(&mut prg_rom).unwrap();
+    let prg_banks = prg_rom
+        .chunks(0x8000)
+        .map(|chunk| {
+            let mut bank = Mem::default();
+            for (i, byte) in bank.as_mut_slice().iter_mut().enumerate() {
+                *byte = chunk[i % chunk.len()];
+            }
+            bank
+        })
+        .collect();
+
+    info!("using CHR RAM");
+    let mut mapper = Axrom::new(prg_banks);
+    mapper.bus_conflicts = BusConflicts::from_header(header);
+    Box::new(mapper)
+}
+
+pub struct Axrom {
+    prg_banks: Vec<Mem<0x8000>>,
+    chr_ram: Mem<0x2000>,
+    bank_select: usize,
+    page_select: bool,
+    bus_conflicts: BusConflicts,
+}
+
+impl Axrom {
+    pub fn new(prg_banks: Vec<Mem<0x8000>>) -> Self {
+        Self {
+            prg_banks,
+            chr_ram: Mem::default(),
+            bank_select: 0,
+            page_select: false,
+            bus_conflicts: BusConflicts::default(),
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_map_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => self
+                .prg_banks
+                .get(self.bank_select)
+                .map(|bank| bank.read(addr)),
+            _ => None,
+        }
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x8000..=0xFFFF => {
+                let data = self.bus_conflicts.apply(self.cpu_map_read(addr), data);
+                self.bank_select = (data as usize & 0x07) % self.prg_banks.len().max(1);
+                self.page_select = data & 0x10 != 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> Option<u8> {
+        if addr < 0x2000 {
+            Some(self.chr_ram.read(addr))
+        } else {
+            None
+        }
+    }
+
+    fn ppu_map_write(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x2000 {
+            self.chr_ram.write(addr, data);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.page_select {
+            false => Mirroring::OneScreenLo,
+            true => Mirroring::OneScreenHi,
+        })
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = BusConflicts(enabled);
+    }
+
+    fn ui(&self, ui: &mut bevy_egui::egui::Ui) {
+        ui.monospace(format!("Selected bank : {}", self.bank_select));
+        ui.monospace(format!("Nametable     : {}", self.page_select as u8));
+        ui.monospace(format!("Bus conflicts : {}", self.bus_conflicts.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_axrom_mapper, Axrom, BusConflicts, Mapper};
+    use crate::{
+        cartridge::{CartridgeHeader, Mirroring},
+        mem::Mem,
+    };
+
+    #[test]
+    fn half_bank_rom() {
+        let header = CartridgeHeader {
+            prg_rom_banks: 1,
+            ..Default::default()
+        };
+        let mut prg_rom = vec![0x00; 0x4000];
+        prg_rom[0x3FFC] = 0x42;
+        let mapper = build_axrom_mapper(&header, prg_rom.as_slice());
+        assert_eq!(mapper.cpu_map_read(0xBFFC), Some(0x42));
+        assert_eq!(mapper.cpu_map_read(0xFFFC), Some(0x42));
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let banks = (0..8u8)
+            .map(|id| {
+                let mut bank = Mem::default();
+                bank.as_mut_slice().fill(0x10 | id);
+                bank
+            })
+            .collect();
+        let mut mapper = Axrom::new(banks);
+        assert!(mapper.cpu_map_write(0x8000, 0x03));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x13));
+
+        mapper.bus_conflicts = BusConflicts(true);
+        // bank 3 holds 0x13, clearing bit 2 of the written bank number
+        assert!(mapper.cpu_map_write(0x8000, 0x06));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x12));
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenLo));
+        // the nametable bit goes through as the ROM has it set
+        assert!(mapper.cpu_map_write(0x8000, 0x17));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x12));
+        assert_eq!(mapper.mirroring(), Some(Mirroring::OneScreenHi));
+    }
+}
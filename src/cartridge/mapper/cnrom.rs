@@ -0,0 +1,126 @@
+use std::io::BufRead;
+
+use bevy::log::info;
+
+use super::{BusConflicts, Mapper};
+use crate::{
+    cartridge::{CartridgeHeader, Mirroring},
+    mem::Mem,
+};
+
+pub fn build_cnrom_mapper(header: &CartridgeHeader, mut reader: impl BufRead) -> Box<dyn Mapper> {
+    info!("PRG banks: {}", header.prg_rom_banks);
+    let mut prg_banks = vec![Mem::default(); header.prg_rom_banks as usize];
+    for bank in prg_banks.iter_mut() {
+        reader.read_exact(bank.as_mut_slice()).unwrap();
+    }
+
+    info!("CHR banks: {}", header.chr_rom_banks);
+    let mut chr_banks = vec![Mem::default(); header.chr_rom_banks as usize];
+    for bank in chr_banks.iter_mut() {
+        reader.read_exact(bank.as_mut_slice()).unwrap();
+    }
+
+    let mut mapper = Cnrom::new(prg_banks, chr_banks);
+    mapper.bus_conflicts = BusConflicts::from_header(header);
+    Box::new(mapper)
+}
+
+pub struct Cnrom {
+    prg_banks: Vec<Mem<0x4000>>,
+    chr_banks: Vec<Mem<0x2000>>,
+    chr_select: usize,
+    bus_conflicts: BusConflicts,
+}
+
+impl Cnrom {
+    pub fn new(prg_banks: Vec<Mem<0x4000>>, chr_banks: Vec<Mem<0x2000>>) -> Self {
+        Self {
+            prg_banks,
+            chr_banks,
+            chr_select: 0,
+            bus_conflicts: BusConflicts::default(),
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_map_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            // 16 KiB boards mirror their only bank at $C000
+            0x8000..=0xFFFF => {
+                let bank_id = ((addr & 0x4000) >> 14) as usize % self.prg_banks.len().max(1);
+                self.prg_banks.get(bank_id).map(|bank| bank.read(addr))
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x8000..=0xFFFF => {
+                let data = self.bus_conflicts.apply(self.cpu_map_read(addr), data);
+                self.chr_select = data as usize % self.chr_banks.len().max(1);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> Option<u8> {
+        if addr < 0x2000 {
+            self.chr_banks
+                .get(self.chr_select)
+                .map(|bank| bank.read(addr))
+        } else {
+            None
+        }
+    }
+
+    fn ppu_map_write(&mut self, addr: u16, _data: u8) -> bool {
+        addr < 0x2000
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = BusConflicts(enabled);
+    }
+
+    fn ui(&self, ui: &mut bevy_egui::egui::Ui) {
+        ui.monospace(format!("CHR bank      : {}", self.chr_select));
+        ui.monospace(format!("Bus conflicts : {}", self.bus_conflicts.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BusConflicts, Cnrom, Mapper};
+    use crate::mem::Mem;
+
+    #[test]
+    fn bus_conflicts() {
+        let mut prg_bank = Mem::default();
+        prg_bank.as_mut_slice().fill(0x03);
+        prg_bank.write(0x8010, 0x01);
+        let chr_banks = (0..4u8)
+            .map(|id| {
+                let mut bank = Mem::default();
+                bank.as_mut_slice().fill(id);
+                bank
+            })
+            .collect();
+        let mut mapper = Cnrom::new(vec![prg_bank], chr_banks);
+        assert!(mapper.cpu_map_write(0x8010, 0x02));
+        assert_eq!(mapper.ppu_map_read(0x0000), Some(0x02));
+
+        mapper.bus_conflicts = BusConflicts(true);
+        assert!(mapper.cpu_map_write(0x8000, 0x03));
+        assert_eq!(mapper.ppu_map_read(0x0000), Some(0x03));
+        // the ROM holds 0x01 at $8010
+        assert!(mapper.cpu_map_write(0x8010, 0x02));
+        assert_eq!(mapper.ppu_map_read(0x0000), Some(0x00));
+    }
+}
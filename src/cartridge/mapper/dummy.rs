@@ -4,6 +4,7 @@ use crate::{cartridge::Mirroring, mem::Mem};
 #[derive(Default)]
 pub struct DummyMapper {
     bank: Mem<0x8000>,
+    chr_ram: Mem<0x2000>,
 }
 
 impl Mapper for DummyMapper {
@@ -14,11 +15,14 @@ impl Mapper for DummyMapper {
         self.bank.write(addr, data);
         true
     }
-    fn ppu_map_read(&self, _addr: u16) -> Option<u8> {
-        None
+    fn ppu_map_read(&self, addr: u16) -> Option<u8> {
+        (addr < 0x2000).then(|| self.chr_ram.read(addr))
     }
-    fn ppu_map_write(&mut self, _addr: u16, _data: u8) -> bool {
-        false
+    fn ppu_map_write(&mut self, addr: u16, data: u8) -> bool {
+        if addr < 0x2000 {
+            self.chr_ram.write(addr, data);
+        }
+        addr < 0x2000
     }
 
     fn mirroring(&self) -> Option<Mirroring> {
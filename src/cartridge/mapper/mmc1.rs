@@ -23,15 +23,65 @@ pub fn build_mmc1_mapper(header: &CartridgeHeader, mut reader: impl BufRead) ->
         reader.read_exact(&mut bank.as_mut_slice()).unwrap();
     }
 
-    let chr_ram = if header.chr_rom_banks == 0 {
-        info!("using CHR RAM");
-        Some(Mem::default())
+    // CHR-RAM is split in 4 KiB pages, the unit of the CHR bank registers
+    let chr_ram_pages = if header.chr_rom_banks == 0 {
+        header.chr_ram_size.div_ceil(0x1000)
     } else {
-        info!("No CHR RAM");
-        None
+        0
     };
+    info!("CHR RAM: {} KiB", chr_ram_pages * 4);
+    let chr_ram = vec![Mem::default(); chr_ram_pages];
 
-    Box::new(Mmc1::new(prg_banks, chr_banks, chr_ram, header.mirroring))
+    let board = Board::from_header(header);
+    info!("MMC1 board: {:?}", board);
+
+    Box::new(Mmc1::new(
+        board,
+        prg_banks,
+        chr_banks,
+        chr_ram,
+        header.mirroring,
+    ))
+}
+
+/// The MMC1 is found on many SxROM boards, the larger ones reuse the CHR bank
+/// registers to drive extra PRG-ROM and PRG-RAM address lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Board {
+    /// SKROM, SLROM and other boards where the CHR registers only select CHR
+    Standard,
+    /// 8 KiB of CHR-RAM, CHR bit 4 disables PRG-RAM
+    Snrom,
+    /// 16 KiB of PRG-RAM, CHR bit 3 selects the 8 KiB PRG-RAM bank
+    Sorom,
+    /// 512 KiB of PRG-ROM, CHR bit 4 selects the 256 KiB outer PRG bank
+    Surom,
+    /// SUROM with 32 KiB of PRG-RAM, CHR bits 2-3 select the 8 KiB PRG-RAM bank
+    Sxrom,
+    /// 32 KiB of PRG-ROM without banking (NES 2.0 submapper 5)
+    Serom,
+}
+
+impl Board {
+    fn from_header(header: &CartridgeHeader) -> Self {
+        let prg_rom_size = header.prg_rom_banks as usize * 0x4000;
+        match (header.submapper, prg_rom_size, header.prg_ram_size) {
+            (5, _, _) => Board::Serom,
+            (_, 0x80000, 0x8000..) => Board::Sxrom,
+            (_, 0x80000, _) => Board::Surom,
+            (_, _, 0x4000) => Board::Sorom,
+            _ if header.chr_rom_banks == 0 => Board::Snrom,
+            _ => Board::Standard,
+        }
+    }
+
+    fn prg_ram_banks(&self) -> usize {
+        match self {
+            Board::Sorom => 2,
+            Board::Sxrom => 4,
+            _ => 1,
+        }
+    }
 }
 
 bitfield! {
@@ -54,33 +104,38 @@ impl ControlRegister {
 }
 
 pub struct Mmc1 {
+    board: Board,
     control_register: ControlRegister,
     shift_register: u8,
     shift_count: u8,
     chr_bank_hi: usize,
     chr_bank_lo: usize,
     prg_bank: usize,
-    prg_ram: Mem<0x2000>,
-    chr_ram: Option<Mem<0x4000>>,
+    prg_ram_enabled: bool,
+    prg_ram: Vec<Mem<0x2000>>,
+    chr_ram: Vec<Mem<0x1000>>,
     prg_banks: Vec<Mem<0x4000>>,
     chr_banks: Vec<Mem<0x2000>>,
 }
 
 impl Mmc1 {
     pub fn new(
+        board: Board,
         prg_rom_banks: Vec<Mem<0x4000>>,
         chr_rom_banks: Vec<Mem<0x2000>>,
-        chr_ram: Option<Mem<0x4000>>,
+        chr_ram: Vec<Mem<0x1000>>,
         mirroring: Mirroring,
     ) -> Self {
         Self {
+            board,
             control_register: ControlRegister(0x1C | mirroring as u8),
             shift_register: 0,
             shift_count: 0,
             chr_bank_hi: 0,
             chr_bank_lo: 0,
             prg_bank: 0,
-            prg_ram: Mem::default(),
+            prg_ram_enabled: true,
+            prg_ram: vec![Mem::default(); board.prg_ram_banks()],
             chr_ram,
             prg_banks: prg_rom_banks,
             chr_banks: chr_rom_banks,
@@ -95,33 +150,68 @@ impl Mmc1 {
         self.shift_register = 0;
         loaded_value
     }
+
+    /// 256 KiB outer PRG bank, in 16 KiB units, driven by CHR bit 4 on SUROM and SXROM
+    fn prg_outer_bank(&self) -> usize {
+        match self.board {
+            Board::Surom | Board::Sxrom => self.chr_bank_lo & 0x10,
+            _ => 0,
+        }
+    }
+
+    /// 16 KiB PRG-ROM bank mapped at `addr`
+    fn prg_rom_bank(&self, addr: u16) -> usize {
+        let upper = (addr & 0x4000 != 0) as usize;
+        let bank = match (self.board, self.control_register.prg_mode()) {
+            (Board::Serom, _) => upper,
+            // full 32 KiB bank
+            (_, 0) | (_, 1) => (self.prg_bank & 0x0E) | upper,
+            // first bank fixed
+            (_, 2) if upper == 0 => 0,
+            (_, 2) => self.prg_bank,
+            // last bank fixed
+            (_, _) if upper == 0 => self.prg_bank,
+            (_, _) => 0x0F,
+        };
+        (bank | self.prg_outer_bank()) % self.prg_banks.len().max(1)
+    }
+
+    fn prg_ram_bank(&self) -> usize {
+        match self.board {
+            Board::Sorom => (self.chr_bank_lo >> 3) & 0x01,
+            Board::Sxrom => (self.chr_bank_lo >> 2) & 0x03,
+            _ => 0,
+        }
+    }
+
+    fn prg_ram_accessible(&self) -> bool {
+        match self.board {
+            Board::Snrom => self.prg_ram_enabled && self.chr_bank_lo & 0x10 == 0,
+            _ => self.prg_ram_enabled,
+        }
+    }
+
+    /// 4 KiB CHR bank mapped at `addr`
+    fn chr_bank(&self, addr: u16) -> usize {
+        match (self.control_register.chr_mode(), addr) {
+            (0, _) => (self.chr_bank_lo & 0x1E) | ((addr >> 12) & 0x01) as usize,
+            (_, 0x0000..=0x0FFF) => self.chr_bank_lo,
+            _ => self.chr_bank_hi,
+        }
+    }
 }
 
 impl Mapper for Mmc1 {
     fn cpu_map_read(&self, addr: u16) -> Option<u8> {
-        match (addr, self.control_register.prg_mode()) {
-            (0x6000..=0x7FFF, _) => Some(self.prg_ram.read(addr)),
-            // full bank
-            (0x8000..=0xBFFF, 0) | (0x8000..=0xBFFF, 1) => self
-                .prg_banks
-                .get(self.prg_bank & 0xFE)
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_accessible() => self
+                .prg_ram
+                .get(self.prg_ram_bank())
                 .map(|bank| bank.read(addr)),
-            (0xC000..=0xFFFF, 0) | (0xC000..=0xFFFF, 1) => self
+            0x8000..=0xFFFF => self
                 .prg_banks
-                .get(self.prg_bank & 0xFE + 1)
+                .get(self.prg_rom_bank(addr))
                 .map(|bank| bank.read(addr)),
-            // first bank fixed
-            (0x8000..=0xBFFF, 2) => self.prg_banks.first().map(|bank| bank.read(addr)),
-            (0xC000..=0xFFFF, 2) => self
-                .prg_banks
-                .get(self.prg_bank)
-                .map(|bank| bank.read(addr)),
-            // last bank fixed
-            (0x8000..=0xBFFF, 3) => self
-                .prg_banks
-                .get(self.prg_bank)
-                .map(|bank| bank.read(addr)),
-            (0xC000..=0xFFFF, 3) => self.prg_banks.last().map(|bank| bank.read(addr)),
             _ => None,
         }
     }
@@ -129,7 +219,12 @@ impl Mapper for Mmc1 {
     fn cpu_map_write(&mut self, addr: u16, data: u8) -> bool {
         match (addr, data, self.shift_count) {
             (0x6000..=0x7FFF, _, _) => {
-                self.prg_ram.write(addr, data);
+                if self.prg_ram_accessible() {
+                    let bank_id = self.prg_ram_bank();
+                    if let Some(bank) = self.prg_ram.get_mut(bank_id) {
+                        bank.write(addr, data);
+                    }
+                }
                 true
             }
             (0x8000..=0xFFFF, data, _) if data & 0x80 != 0 => {
@@ -156,7 +251,9 @@ impl Mapper for Mmc1 {
                 true
             }
             (0xE000..=0xFFFF, _, _) => {
-                self.prg_bank = (self.load_shift_register(data) & 0x0F) as usize;
+                let value = self.load_shift_register(data);
+                self.prg_bank = (value & 0x0F) as usize;
+                self.prg_ram_enabled = value & 0x10 == 0;
                 true
             }
             _ => false,
@@ -164,35 +261,26 @@ impl Mapper for Mmc1 {
     }
 
     fn ppu_map_read(&self, addr: u16) -> Option<u8> {
-        if let Some(chr_ram) = self.chr_ram.as_ref() {
-            if addr < 0x2000 {
-                Some(chr_ram.read(addr))
-            } else {
-                None
-            }
+        if addr >= 0x2000 {
+            return None;
+        }
+        let bank = self.chr_bank(addr);
+        let offset = (((bank & 0x01) as u16) << 12) | (addr & 0x0FFF);
+        if !self.chr_ram.is_empty() {
+            Some(self.chr_ram[bank % self.chr_ram.len()].read(addr))
         } else {
-            match (addr, self.control_register.chr_mode()) {
-                (0x0000..=0x1FFF, 0) => self
-                    .chr_banks
-                    .get(self.chr_bank_lo)
-                    .map(|bank| bank.read(addr)),
-                (0x0000..=0x0FFF, 1) => self
-                    .chr_banks
-                    .get(self.chr_bank_lo & 0xFE)
-                    .map(|bank| bank.read(addr)),
-                (0x1000..=0x1FFF, 1) => self
-                    .chr_banks
-                    .get(self.chr_bank_hi & 0xFE)
-                    .map(|bank| bank.read(addr)),
-                _ => None,
-            }
+            self.chr_banks
+                .get((bank >> 1) % self.chr_banks.len().max(1))
+                .map(|bank| bank.read(offset))
         }
     }
 
     fn ppu_map_write(&mut self, addr: u16, data: u8) -> bool {
         if addr < 0x2000 {
-            if let Some(chr_ram) = self.chr_ram.as_mut() {
-                chr_ram.write(addr, data);
+            let bank = self.chr_bank(addr);
+            let pages = self.chr_ram.len();
+            if let Some(page) = self.chr_ram.get_mut(bank % pages.max(1)) {
+                page.write(addr, data);
                 true
             } else {
                 false
@@ -213,6 +301,7 @@ impl Mapper for Mmc1 {
     }
 
     fn ui(&self, ui: &mut bevy_egui::egui::Ui) {
+        ui.monospace(format!("board          : {:?}", self.board));
         ui.monospace(format!("shift register : {:#07b}", self.shift_register));
         ui.monospace(format!("shift count    : {}", self.shift_count));
         ui.monospace(format!(
@@ -220,13 +309,17 @@ impl Mapper for Mmc1 {
             self.control_register.prg_mode()
         ));
         ui.monospace(format!("prg selected   : {}", self.prg_bank));
+        ui.monospace(format!("prg outer bank : {}", self.prg_outer_bank() >> 4));
+        ui.monospace(format!("prg ram        : {}", self.prg_ram_accessible()));
+        ui.monospace(format!("prg ram bank   : {}", self.prg_ram_bank()));
 
-        ui.label("PRG banks");
+        ui.label("PRG RAM");
         ui.monospace("         0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F");
         ui.add(Separator::default().spacing(2.0));
         let text_style = egui::TextStyle::Monospace;
         let row_height = ui.text_style_height(&text_style);
         let total_rows = 0x2000 / 16;
+        let prg_ram = &self.prg_ram[self.prg_ram_bank()];
         ui.push_id("prg_memory", |ui| {
             ScrollArea::vertical()
                 .auto_shrink(false)
@@ -236,7 +329,7 @@ impl Mapper for Mmc1 {
                         let start = (0x2000 + row * 16) as u16;
                         let end = start + 16;
                         let row_text = (start..end)
-                            .map(|addr| format!("{:02X}", self.prg_ram.read(addr)))
+                            .map(|addr| format!("{:02X}", prg_ram.read(addr)))
                             .collect::<Vec<_>>()
                             .join(" ");
                         ui.monospace(format!("${:#06X}: {}", start, row_text));
@@ -245,3 +338,100 @@ impl Mapper for Mmc1 {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Board, Mapper, Mmc1};
+    use crate::{cartridge::Mirroring, mem::Mem};
+
+    fn mmc1(board: Board, prg_banks: u8) -> Mmc1 {
+        let prg_banks = (0..prg_banks)
+            .map(|id| {
+                let mut bank = Mem::default();
+                bank.as_mut_slice().fill(id);
+                bank
+            })
+            .collect();
+        Mmc1::new(
+            board,
+            prg_banks,
+            Vec::new(),
+            vec![Mem::default(); 2],
+            Mirroring::Horizontal,
+        )
+    }
+
+    fn serial_write(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            assert!(mapper.cpu_map_write(addr, (value >> bit) & 0x01));
+        }
+    }
+
+    #[test]
+    fn surom_outer_bank() {
+        let mut mapper = mmc1(Board::Surom, 32);
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x00));
+        assert_eq!(mapper.cpu_map_read(0xC000), Some(0x0F));
+
+        serial_write(&mut mapper, 0xA000, 0x10);
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x10));
+        assert_eq!(mapper.cpu_map_read(0xC000), Some(0x1F));
+
+        serial_write(&mut mapper, 0xE000, 0x03);
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x13));
+    }
+
+    #[test]
+    fn prg_ram_enable() {
+        let mut mapper = mmc1(Board::Snrom, 16);
+        assert!(mapper.cpu_map_write(0x6000, 0xAA));
+        assert_eq!(mapper.cpu_map_read(0x6000), Some(0xAA));
+
+        serial_write(&mut mapper, 0xE000, 0x10);
+        assert_eq!(mapper.cpu_map_read(0x6000), None);
+        assert!(mapper.cpu_map_write(0x6000, 0x55));
+
+        serial_write(&mut mapper, 0xE000, 0x00);
+        assert_eq!(mapper.cpu_map_read(0x6000), Some(0xAA));
+
+        serial_write(&mut mapper, 0xA000, 0x10);
+        assert_eq!(mapper.cpu_map_read(0x6000), None);
+    }
+
+    #[test]
+    fn sxrom_prg_ram_banks() {
+        let mut mapper = mmc1(Board::Sxrom, 32);
+        assert!(mapper.cpu_map_write(0x6000, 0x01));
+        serial_write(&mut mapper, 0xA000, 0x0C);
+        assert_eq!(mapper.cpu_map_read(0x6000), Some(0x00));
+        assert!(mapper.cpu_map_write(0x6000, 0x02));
+
+        serial_write(&mut mapper, 0xA000, 0x00);
+        assert_eq!(mapper.cpu_map_read(0x6000), Some(0x01));
+        serial_write(&mut mapper, 0xA000, 0x0C);
+        assert_eq!(mapper.cpu_map_read(0x6000), Some(0x02));
+    }
+
+    #[test]
+    fn chr_ram_size() {
+        let mut mapper = mmc1(Board::Snrom, 16);
+        // 4 KiB CHR mode
+        serial_write(&mut mapper, 0x8000, 0x1C);
+        serial_write(&mut mapper, 0xA000, 0x01);
+        assert!(mapper.ppu_map_write(0x0010, 0x11));
+        // 8 KiB of RAM wrap around every 2 banks
+        serial_write(&mut mapper, 0xA000, 0x03);
+        assert_eq!(mapper.ppu_map_read(0x0010), Some(0x11));
+
+        mapper.chr_ram = vec![Mem::default(); 8];
+        serial_write(&mut mapper, 0xA000, 0x01);
+        assert!(mapper.ppu_map_write(0x0010, 0x11));
+        serial_write(&mut mapper, 0xA000, 0x03);
+        assert_eq!(mapper.ppu_map_read(0x0010), Some(0x00));
+        serial_write(&mut mapper, 0xA000, 0x09);
+        assert_eq!(mapper.ppu_map_read(0x0010), Some(0x11));
+
+        mapper.chr_ram.clear();
+        assert!(!mapper.ppu_map_write(0x0010, 0x11));
+    }
+}
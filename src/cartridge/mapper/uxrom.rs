@@ -2,7 +2,7 @@ use std::io::BufRead;
 
 use bevy::log::info;
 
-use super::Mapper;
+use super::{BusConflicts, Mapper};
 use crate::{
     cartridge::{CartridgeHeader, Mirroring},
     mem::Mem,
@@ -25,7 +25,9 @@ pub fn build_uxrom_mapper(header: &CartridgeHeader, mut reader: impl BufRead) ->
         None
     };
 
-    Box::new(Uxrom::new(prg_banks, chr_bank, prg_ram_bank))
+    let mut mapper = Uxrom::new(prg_banks, chr_bank, prg_ram_bank);
+    mapper.bus_conflicts = BusConflicts::from_header(header);
+    Box::new(mapper)
 }
 
 pub struct Uxrom {
@@ -33,6 +35,7 @@ pub struct Uxrom {
     chr_bank: Mem<0x2000>,
     vram: Option<Mem<0x2000>>,
     bank_select: usize,
+    bus_conflicts: BusConflicts,
 }
 
 impl Uxrom {
@@ -42,6 +45,7 @@ impl Uxrom {
             chr_bank,
             vram,
             bank_select: 0,
+            bus_conflicts: BusConflicts::default(),
         }
     }
 }
@@ -70,6 +74,7 @@ impl Mapper for Uxrom {
                 }
             }
             0x8000..=0xFFFF => {
+                let data = self.bus_conflicts.apply(self.cpu_map_read(addr), data);
                 self.bank_select = data as usize & 0x07;
                 true
             }
@@ -98,7 +103,43 @@ impl Mapper for Uxrom {
         None
     }
 
+    fn set_bus_conflicts(&mut self, enabled: bool) {
+        self.bus_conflicts = BusConflicts(enabled);
+    }
+
     fn ui(&self, ui: &mut bevy_egui::egui::Ui) {
         ui.monospace(format!("Selected bank : {}", self.bank_select));
+        ui.monospace(format!("Bus conflicts : {}", self.bus_conflicts.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BusConflicts, Mapper, Uxrom};
+    use crate::mem::Mem;
+
+    fn numbered_banks() -> Vec<Mem<0x4000>> {
+        (0..8u8)
+            .map(|id| {
+                let mut bank = Mem::default();
+                bank.as_mut_slice().fill(id);
+                bank
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let mut mapper = Uxrom::new(numbered_banks(), Mem::default(), None);
+        assert!(mapper.cpu_map_write(0xC000, 0x05));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x05));
+
+        mapper.bus_conflicts = BusConflicts(true);
+        // the fixed bank holds 0x07 so the written value goes through untouched
+        assert!(mapper.cpu_map_write(0xC000, 0x03));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x03));
+        // the switchable bank holds 0x03 at this point
+        assert!(mapper.cpu_map_write(0x8000, 0x06));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x02));
     }
 }
@@ -0,0 +1,128 @@
+use std::io::BufRead;
+
+use bevy::log::info;
+
+use super::Mapper;
+use crate::{
+    cartridge::{CartridgeHeader, Mirroring},
+    mem::Mem,
+};
+
+pub fn build_vs_unisystem_mapper(
+    header: &CartridgeHeader,
+    mut reader: impl BufRead,
+) -> Box<dyn Mapper> {
+    info!("PRG banks: {}", header.prg_rom_banks);
+    let mut prg_rom = vec![0x00; header.prg_rom_banks as usize * 0x4000];
+    reader.read_exact(&mut prg_rom).unwrap();
+
+    info!("CHR banks: {}", header.chr_rom_banks);
+    let mut chr_banks = vec![Mem::default(); header.chr_rom_banks as usize];
+    for bank in chr_banks.iter_mut() {
+        reader.read_exact(bank.as_mut_slice()).unwrap();
+    }
+
+    Box::new(VsUnisystem {
+        prg_rom,
+        chr_banks,
+        prg_ram: Mem::default(),
+        bank_select: false,
+    })
+}
+
+/// Mapper 99, the Vs. UniSystem board. Bit 2 of $4016 writes selects the CHR bank, and on
+/// 40 KiB games the PRG bank at $8000-$9FFF as well.
+pub struct VsUnisystem {
+    prg_rom: Vec<u8>,
+    chr_banks: Vec<Mem<0x2000>>,
+    /// 2 KiB shared with the second CPU on DualSystem cabinets
+    prg_ram: Mem<0x800>,
+    bank_select: bool,
+}
+
+impl Mapper for VsUnisystem {
+    fn cpu_map_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram.read(addr)),
+            // the extra 8 KiB of 40 KiB games sit after the fixed 32 KiB
+            0x8000..=0x9FFF if self.bank_select && self.prg_rom.len() > 0x8000 => {
+                self.prg_rom.get(0x8000 + (addr & 0x1FFF) as usize).copied()
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn cpu_map_write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4016 => {
+                self.bank_select = data & 0x04 != 0;
+                false
+            }
+            0x6000..=0x7FFF => {
+                self.prg_ram.write(addr, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ppu_map_read(&self, addr: u16) -> Option<u8> {
+        if addr < 0x2000 {
+            let bank = self.bank_select as usize % self.chr_banks.len().max(1);
+            self.chr_banks.get(bank).map(|bank| bank.read(addr))
+        } else {
+            None
+        }
+    }
+
+    fn ppu_map_write(&mut self, addr: u16, _data: u8) -> bool {
+        addr < 0x2000
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    fn ui(&self, ui: &mut bevy_egui::egui::Ui) {
+        ui.monospace(format!("Bank select : {}", self.bank_select as u8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mapper, VsUnisystem};
+    use crate::mem::Mem;
+
+    #[test]
+    fn bank_select() {
+        // 40 KiB of PRG, each 8 KiB bank filled with its index
+        let prg_rom = (0..5u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_banks = (0..2u8)
+            .map(|id| {
+                let mut bank = Mem::default();
+                bank.as_mut_slice().fill(0x10 | id);
+                bank
+            })
+            .collect();
+        let mut mapper = VsUnisystem {
+            prg_rom,
+            chr_banks,
+            prg_ram: Mem::default(),
+            bank_select: false,
+        };
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x00));
+        assert_eq!(mapper.cpu_map_read(0xE000), Some(0x03));
+        assert_eq!(mapper.ppu_map_read(0x0000), Some(0x10));
+
+        // the write still reaches the controllers
+        assert!(!mapper.cpu_map_write(0x4016, 0x04));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x04));
+        assert_eq!(mapper.cpu_map_read(0xA000), Some(0x01));
+        assert_eq!(mapper.ppu_map_read(0x1FFF), Some(0x11));
+
+        assert!(!mapper.cpu_map_write(0x4016, 0x01));
+        assert_eq!(mapper.cpu_map_read(0x8000), Some(0x00));
+        assert_eq!(mapper.ppu_map_read(0x0000), Some(0x10));
+    }
+}
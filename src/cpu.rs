@@ -1,6 +1,10 @@
-use std::{fmt::UpperHex, time::Duration};
+use std::fmt::UpperHex;
 
-use crate::cpu_bus::{CpuBusQuery, DmaStatus};
+use crate::{
+    cpu_bus::{CpuBusQuery, DmaStatus},
+    nsf::Nsf,
+    region::Region,
+};
 use addr_mode::AddrMode;
 use bevy::{ecs::query::QueryData, prelude::*, utils::HashSet};
 use bevy_egui::{
@@ -14,31 +18,60 @@ mod addr_mode;
 mod instr;
 mod op;
 
-const MASTER_CLOCK_HZ: f64 = 21_477_272.0;
+/// longest real time emulated in one update, a stalled app skips ahead instead of racing
+/// to catch up
+const MAX_UPDATE_DELTA: f64 = 1.0 / 15.0;
 
 #[derive(Component)]
 pub struct SystemClock {
     enabled: bool,
+    region: Region,
+    /// PPU cycles
     pub cycles: usize,
-    timer: Timer,
+    pub cpu_cycles: usize,
+    /// master clock cycles accumulated towards the next CPU cycle
+    cpu_phase: u8,
+    /// PPU cycles owed to the emulation by the time elapsed so far
+    pending_cycles: f64,
 }
 
 impl Default for SystemClock {
     fn default() -> Self {
         Self {
             enabled: false,
+            region: Region::default(),
             cycles: 0,
-            timer: Timer::new(
-                Duration::from_secs_f64(1.0 / (MASTER_CLOCK_HZ / 4.0)),
-                TimerMode::Repeating,
-            ),
+            cpu_phase: 0,
+            cpu_cycles: 0,
+            pending_cycles: 0.0,
         }
     }
 }
 
 impl SystemClock {
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
     fn reset(&mut self) {
-        self.cycles = usize::MAX - 8;
+        self.cycles = 0;
+        self.cpu_cycles = 0;
+        // the first CPU cycle lands on the second PPU cycle after reset
+        self.cpu_phase = self.region.cpu_divider() - 2 * self.region.ppu_divider();
+    }
+
+    /// Advances the master clock by one PPU cycle, returns true when the CPU is clocked
+    /// as well. NTSC and Dendy consoles clock the CPU every 3 PPU cycles, PAL every 3.2.
+    fn ppu_cycle(&mut self) -> bool {
+        self.cycles = self.cycles.wrapping_add(1);
+        self.cpu_phase += self.region.ppu_divider();
+        if self.cpu_phase >= self.region.cpu_divider() {
+            self.cpu_phase -= self.region.cpu_divider();
+            self.cpu_cycles = self.cpu_cycles.wrapping_add(1);
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -171,6 +204,18 @@ impl<'w> CpuQueryReadOnlyItem<'w> {
 }
 
 impl<'w> CpuQueryItem<'w> {
+    pub fn running(&self) -> bool {
+        self.clock.enabled
+    }
+
+    pub fn set_running(&mut self, running: bool) {
+        self.clock.enabled = running;
+    }
+
+    pub fn nsf(&mut self) -> Option<&mut Nsf> {
+        self.bus.slot().nsf.as_deref_mut()
+    }
+
     pub fn next_frame(&mut self) -> bool {
         while !self.bus.frame_complete() {
             self.clock(None);
@@ -178,19 +223,19 @@ impl<'w> CpuQueryItem<'w> {
         return false;
     }
     pub fn clock(&mut self, breakpoints: Option<&BreakPointState>) -> bool {
-        self.clock.cycles = self.clock.cycles.wrapping_add(1);
-        self.bus.tick(self.clock.cycles);
-        if self.clock.cycles % 3 == 0 {
+        let cpu_cycle = self.clock.ppu_cycle();
+        self.bus.tick(cpu_cycle);
+        if cpu_cycle && !self.bus.dmc_stalled() {
             if self.bus.dma() == DmaStatus::Inactive {
                 self.tick();
                 if breakpoints.is_some_and(|bp| bp.check(self.cpu.pc)) {
                     return false;
                 }
             } else {
-                match (self.bus.dma(), self.clock.cycles % 2 == 0) {
-                    (DmaStatus::Idling, odd_cycle) if odd_cycle => self.bus.start_dma(),
-                    (DmaStatus::Transfering, odd_cycle) if !odd_cycle => self.bus.dma_read(),
-                    (DmaStatus::Transfering, odd_cycle) if odd_cycle => self.bus.dma_write(),
+                match (self.bus.dma(), self.clock.cpu_cycles.is_multiple_of(2)) {
+                    (DmaStatus::Idling, even_cycle) if even_cycle => self.bus.start_dma(),
+                    (DmaStatus::Transfering, even_cycle) if !even_cycle => self.bus.dma_read(),
+                    (DmaStatus::Transfering, even_cycle) if even_cycle => self.bus.dma_write(),
                     _ => {}
                 }
             }
@@ -293,21 +338,36 @@ impl BreakPointState {
     }
 }
 
+/// Frames left to emulate before the app exits, set by `--record-frames` so recordings
+/// get a fixed length. The emulation then starts from reset on its own.
+#[derive(Resource)]
+pub struct FrameLimit(pub u32);
+
 pub struct CpuPlugin;
 
 impl Plugin for CpuPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<BreakPointState>()
             .insert_resource(BreakPointState::default())
-            .insert_resource(Time::<Fixed>::from_hz(240.0))
-            .add_systems(FixedUpdate, run_emulation);
+            .add_systems(
+                PostStartup,
+                start_frame_limit.run_if(resource_exists::<FrameLimit>),
+            )
+            .add_systems(Update, run_emulation);
+    }
+}
+
+fn start_frame_limit(mut query: Query<CpuQuery>) {
+    if let Ok(mut query) = query.get_single_mut() {
+        query.reset();
+        query.set_running(true);
     }
 }
 
 pub fn cpu_gui(mut query: Query<CpuQuery>, mut contexts: EguiContexts) {
     egui::Window::new("CPU Info").show(&contexts.ctx_mut(), |ui| {
         if let Ok(mut query) = query.get_single_mut() {
-            ui.monospace(format!("Cycles : {}", query.clock.cycles / 3));
+            ui.monospace(format!("Cycles : {}", query.clock.cpu_cycles));
             ui.horizontal(|ui| {
                 ui.monospace("Status: ");
                 ui.monospace(RichText::new("C").color(if query.cpu.status.carry() {
@@ -459,15 +519,41 @@ impl UpperHex for Operand {
     }
 }
 
-fn run_emulation(mut query: Query<CpuQuery>, time: Res<Time>, breakpoints: Res<BreakPointState>) {
+/// Runs once per displayed frame, emulating the time elapsed since the previous one. The
+/// frames are paced by the display refresh rate, the audio stream keeps up through dynamic
+/// rate control in the APU.
+fn run_emulation(
+    mut query: Query<CpuQuery>,
+    time: Res<Time>,
+    breakpoints: Res<BreakPointState>,
+    mut limit: Option<ResMut<FrameLimit>>,
+    mut exit: EventWriter<AppExit>,
+) {
     if let Ok(mut query) = query.get_single_mut() {
         if query.clock.enabled {
-            query.clock.timer.tick(time.delta());
-            for _ in 0..query.clock.timer.times_finished_this_tick() as usize {
+            let delta = time.delta_seconds_f64().min(MAX_UPDATE_DELTA);
+            let region = query.clock.region;
+            query.clock.pending_cycles +=
+                delta * region.master_clock_hz() / region.ppu_divider() as f64;
+            while query.clock.pending_cycles >= 1.0 {
+                query.clock.pending_cycles -= 1.0;
                 if !query.clock(Some(&breakpoints)) {
                     query.clock.enabled = false;
+                    query.clock.pending_cycles = 0.0;
                     break;
                 }
+                if let Some(limit) = limit.as_deref_mut() {
+                    if query.bus.frame_complete() {
+                        limit.0 = limit.0.saturating_sub(1);
+                    }
+                    if limit.0 == 0 {
+                        info!("Frame limit reached, exiting");
+                        query.bus.stop_recordings();
+                        query.clock.enabled = false;
+                        exit.send(AppExit::Success);
+                        break;
+                    }
+                }
             }
         }
     }
@@ -475,7 +561,15 @@ fn run_emulation(mut query: Query<CpuQuery>, time: Res<Time>, breakpoints: Res<B
 
 #[cfg(test)]
 mod tests {
-    use crate::{cartridge::Cartridge, cpu::CpuQuery, nes::NesBundle};
+    use std::time::Duration;
+
+    use crate::{
+        apu::Apu,
+        cartridge::Cartridge,
+        cpu::{run_emulation, BreakPointState, CpuQuery, FrameLimit, SystemClock},
+        nes::NesBundle,
+        region::Region,
+    };
     use bevy::prelude::*;
 
     macro_rules! setup {
@@ -502,4 +596,67 @@ mod tests {
         assert_eq!(query.bus.cpu_read(0x01FC), Some(0x34));
         assert_eq!(query.bus.cpu_read(0x01FB), Some(0b0010_0100));
     }
+
+    #[test]
+    fn pal_clock_ratio() {
+        let mut clock = SystemClock::default();
+        clock.set_region(Region::Pal);
+        let cpu_cycles = (0..16).filter(|_| clock.ppu_cycle()).count();
+        assert_eq!(cpu_cycles, 5);
+
+        let mut clock = SystemClock::default();
+        clock.set_region(Region::Dendy);
+        let cpu_cycles = (0..15).filter(|_| clock.ppu_cycle()).count();
+        assert_eq!(cpu_cycles, 5);
+    }
+
+    #[test]
+    fn frame_limit() {
+        let mut app = App::new();
+        app.add_event::<AppExit>()
+            .init_resource::<Time>()
+            .init_resource::<BreakPointState>()
+            .insert_resource(FrameLimit(3))
+            .add_systems(Update, run_emulation);
+        let cart = Cartridge::testing(None);
+        app.world_mut().spawn((NesBundle::default(), cart));
+        let mut query = app.world_mut().query::<CpuQuery>();
+        query.single_mut(app.world_mut()).set_running(true);
+
+        // each update emulates about one frame
+        let mut updates = 0;
+        while app.world().resource::<Events<AppExit>>().is_empty() {
+            app.world_mut()
+                .resource_mut::<Time>()
+                .advance_by(Duration::from_micros(16_639));
+            app.update();
+            updates += 1;
+            assert!(updates < 10, "the frame limit should stop the emulation");
+        }
+        assert_eq!(app.world().resource::<FrameLimit>().0, 0);
+        assert!(!query.single_mut(app.world_mut()).running());
+    }
+
+    #[test]
+    fn vgm_without_fds() {
+        let path = std::env::temp_dir().join("nes-rs-cartridge-log.vgm");
+        let mut app = App::new();
+        app.world_mut()
+            .spawn((NesBundle::default(), Cartridge::testing(None)));
+        let mut apu = app.world_mut().query::<&mut Apu>();
+        apu.single_mut(app.world_mut()).start_vgm(&path);
+        let mut query = app.world_mut().query::<CpuQuery>();
+        let mut query = query.single_mut(app.world_mut());
+        query.bus.cpu_write(0x4000, 0xBF);
+        // cartridge registers where the Disk System has its sound and I/O
+        query.bus.cpu_write(0x4023, 0x83);
+        query.bus.cpu_write(0x4040, 0x3F);
+        query.bus.cpu_write(0x4080, 0x80);
+        query.bus.stop_recordings();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes[0x87] & 0x80, 0x00, "no FDS flag");
+        assert_eq!(bytes[0xC0..], [0xB4, 0x00, 0xBF, 0x66]);
+    }
 }
@@ -5,14 +5,15 @@ use bevy_egui::{
 };
 pub use dma::{Dma, DmaStatus};
 
-use crate::{apu::Apu, ppu::PpuQuery};
+use crate::{apu::Apu, ppu::PpuQuery, slot::SlotQueryItem, vs::VsSystem};
 
 mod dma;
 
+/// Standard controllers on both ports, read through $4016 and $4017.
 #[derive(Component, Default)]
 pub struct Controller {
-    state: u8,
-    shifter: u8,
+    state: [u8; 2],
+    shifter: [u8; 2],
 }
 
 impl Controller {
@@ -20,25 +21,34 @@ impl Controller {
         self.shifter = self.state;
     }
 
-    fn read_shifter(&mut self) -> u8 {
-        let result = (self.shifter & 0x80 != 0) as u8;
-        self.shifter <<= 1;
+    fn read_shifter(&mut self, port: usize) -> u8 {
+        let result = (self.shifter[port] & 0x80 != 0) as u8;
+        self.shifter[port] <<= 1;
         result
     }
 }
 
 pub fn update_controller_state(mut query: Query<&mut Controller>, keys: Res<ButtonInput<KeyCode>>) {
     if let Ok(mut controller) = query.get_single_mut() {
-        controller.state = 0x00;
+        controller.state = [0x00; 2];
         keys.get_pressed().for_each(|key| match key {
-            KeyCode::KeyZ => controller.state |= 0x80, // A
-            KeyCode::KeyX => controller.state |= 0x40, // B
-            KeyCode::KeyA => controller.state |= 0x20, // select
-            KeyCode::KeyS => controller.state |= 0x10, // start
-            KeyCode::ArrowUp => controller.state |= 0x08,
-            KeyCode::ArrowDown => controller.state |= 0x04,
-            KeyCode::ArrowLeft => controller.state |= 0x02,
-            KeyCode::ArrowRight => controller.state |= 0x01,
+            KeyCode::KeyZ => controller.state[0] |= 0x80, // A
+            KeyCode::KeyX => controller.state[0] |= 0x40, // B
+            KeyCode::KeyA => controller.state[0] |= 0x20, // select
+            KeyCode::KeyS => controller.state[0] |= 0x10, // start
+            KeyCode::ArrowUp => controller.state[0] |= 0x08,
+            KeyCode::ArrowDown => controller.state[0] |= 0x04,
+            KeyCode::ArrowLeft => controller.state[0] |= 0x02,
+            KeyCode::ArrowRight => controller.state[0] |= 0x01,
+            // player 2, also the second start button of Vs. System cabinets
+            KeyCode::KeyM => controller.state[1] |= 0x80, // A
+            KeyCode::KeyN => controller.state[1] |= 0x40, // B
+            KeyCode::Digit7 => controller.state[1] |= 0x20, // select
+            KeyCode::Digit8 => controller.state[1] |= 0x10, // start
+            KeyCode::KeyI => controller.state[1] |= 0x08,
+            KeyCode::KeyK => controller.state[1] |= 0x04,
+            KeyCode::KeyJ => controller.state[1] |= 0x02,
+            KeyCode::KeyL => controller.state[1] |= 0x01,
             _ => {}
         });
     }
@@ -72,6 +82,7 @@ pub struct CpuBusQuery {
     controller: &'static mut Controller,
     ppu: PpuQuery,
     apu: &'static mut Apu,
+    vs: Option<&'static VsSystem>,
 }
 
 impl<'w> CpuBusQueryReadOnlyItem<'w> {
@@ -79,6 +90,7 @@ impl<'w> CpuBusQueryReadOnlyItem<'w> {
         match addr {
             0x0000..=0x1FFF => self.wram.read(addr),
             0x2000..=0x3FFF => self.ppu.cpu_read(addr),
+            0x4015 => self.apu.peek(addr).unwrap_or(0x00),
             0x4020..=0xFFFF => self.ppu.cpu_read(addr),
             _ => 0x00,
         }
@@ -90,21 +102,60 @@ impl<'w> CpuBusQueryItem<'w> {
         self.ppu.reset();
     }
 
+    pub fn slot(&mut self) -> &mut SlotQueryItem<'w> {
+        &mut self.ppu.slot
+    }
+
     pub fn frame_complete(&mut self) -> bool {
         self.ppu.frame_complete()
     }
 
+    /// finishes the WAV recording and VGM log in progress
+    pub fn stop_recordings(&mut self) {
+        self.apu.stop_recording();
+        self.apu.stop_vgm();
+    }
+
     pub fn nmi(&mut self) -> bool {
         self.ppu.nmi()
     }
 
-    pub fn irq(&mut self) -> bool {
-        self.apu.irq()
+    pub fn irq(&self) -> bool {
+        self.apu.irq() || self.ppu.slot.irq()
     }
 
-    pub fn tick(&mut self, cycles: usize) -> bool {
+    /// clocks the PPU, and the APU and cartridge when `cpu_cycle` is set
+    pub fn tick(&mut self, cpu_cycle: bool) {
         self.ppu.tick();
-        return self.apu.tick(cycles);
+        if cpu_cycle {
+            let level = self.ppu.slot.tick();
+            self.apu.cpu_tick(level);
+            self.dmc_dma();
+        }
+    }
+
+    /// Fetches the next DMC sample byte when needed. The CPU is halted for 4 cycles, or 2
+    /// when the fetch lands during an OAM DMA.
+    fn dmc_dma(&mut self) {
+        if let Some(addr) = self.apu.dmc_dma_request() {
+            let data = self.cpu_read(addr).unwrap_or(0x00);
+            self.apu.dmc_dma_complete(data);
+            self.dma.dmc_stall = if self.dma.status == DmaStatus::Transfering {
+                2
+            } else {
+                4
+            };
+        }
+    }
+
+    /// true while the CPU is halted by a DMC fetch
+    pub fn dmc_stalled(&mut self) -> bool {
+        if self.dma.dmc_stall > 0 {
+            self.dma.dmc_stall -= 1;
+            true
+        } else {
+            false
+        }
     }
 
     pub fn dma(&self) -> DmaStatus {
@@ -119,9 +170,15 @@ impl<'w> CpuBusQueryItem<'w> {
         match addr {
             0x0000..=0x1FFF => Some(self.wram.read(addr)),
             0x2000..=0x3FFF => self.ppu.cpu_read(addr),
+            0x4015 => self.apu.cpu_read(addr),
             0x4016 => {
                 debug!("Controller read");
-                Some(self.controller.read_shifter())
+                let data = self.controller.read_shifter(0);
+                Some(self.vs.map_or(data, |vs| vs.read_4016(data)))
+            }
+            0x4017 => {
+                let data = self.controller.read_shifter(1);
+                Some(self.vs.map_or(data, |vs| vs.read_4017(data)))
             }
             0x4020..=0xFFFF => self.ppu.cpu_read(addr),
             _ => None,
@@ -133,6 +190,10 @@ impl<'w> CpuBusQueryItem<'w> {
             0x2000..=0x3FFF => {
                 self.ppu.cpu_write(addr, data);
             }
+            0x4015 if data & 0x10 != 0 => {
+                self.log_dmc_sample();
+                self.apu.cpu_write(addr, data);
+            }
             0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.cpu_write(addr, data),
             0x4014 => {
                 self.dma.page = data;
@@ -142,12 +203,36 @@ impl<'w> CpuBusQueryItem<'w> {
             0x4016 => {
                 debug!("Controller write: {:#X}", data);
                 self.controller.store_shifter();
+                // the Vs. UniSystem boards switch banks with the other bits
+                if self.vs.is_some() {
+                    self.ppu.slot.cpu_write(addr, data);
+                }
+            }
+            0x4020..=0xFFFF => {
+                // cartridges map their own registers over the Disk System ones
+                if self.ppu.slot.has_fds() {
+                    self.apu.vgm_write(addr, data);
+                }
+                self.ppu.cpu_write(addr, data);
             }
-            0x4020..=0xFFFF => self.ppu.cpu_write(addr, data),
             _ => {}
         };
     }
 
+    /// Sends the DMC sample to the VGM log before it starts playing, so the player has
+    /// all of it when the channel fetches it.
+    fn log_dmc_sample(&mut self) {
+        let Some((start, length)) = self.apu.vgm_dmc_sample() else {
+            return;
+        };
+        for offset in 0..length {
+            // the address wraps around to $8000
+            let addr = start.wrapping_add(offset) | 0x8000;
+            let data = self.ppu.slot.cpu_read(addr).unwrap_or(0x00);
+            self.apu.vgm_memory(addr, data);
+        }
+    }
+
     pub fn dma_read(&mut self) {
         let data = self.cpu_read(((self.dma.page as u16) << 8) | (self.dma.addr as u16));
         self.dma.data = data.unwrap_or(0x00);
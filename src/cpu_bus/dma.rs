@@ -6,6 +6,8 @@ pub struct Dma {
     pub addr: u8,
     pub data: u8,
     pub status: DmaStatus,
+    /// CPU cycles left to the DMC sample fetch
+    pub dmc_stall: u8,
 }
 
 #[derive(Default, Clone, Copy, PartialEq, Eq)]
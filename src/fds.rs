@@ -0,0 +1,399 @@
+use std::path::{Path, PathBuf};
+
+use audio::FdsAudio;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use disk::DiskDrive;
+use thiserror::Error;
+
+use crate::{cartridge::Mirroring, mem::Mem};
+
+mod audio;
+mod disk;
+
+#[derive(Debug, Error)]
+pub enum FdsError {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("invalid disk image")]
+    InvalidImage,
+}
+
+/// Famicom Disk System RAM adapter, plugged in the cartridge slot in place of a
+/// `Cartridge`. It holds the BIOS, 32 KiB of PRG-RAM, 8 KiB of CHR-RAM, the disk drive
+/// interface and the wavetable sound channel.
+#[derive(Component)]
+pub struct Fds {
+    bios: Mem<0x2000>,
+    prg_ram: Mem<0x8000>,
+    chr_ram: Mem<0x2000>,
+    drive: DiskDrive,
+    audio: FdsAudio,
+    save_path: PathBuf,
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+    mirroring: Mirroring,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    /// $4026, external connector
+    ext_output: u8,
+}
+
+impl Fds {
+    /// Loads a disk image with the BIOS found at `bios`. Modified sides are written back
+    /// next to the image with a `.sav` extension, which is loaded instead of the image
+    /// when it exists.
+    pub fn from_files(image: &str, bios: &str) -> Result<Self, FdsError> {
+        let bios_bytes = std::fs::read(bios)?;
+        if bios_bytes.len() != 0x2000 {
+            return Err(FdsError::InvalidImage);
+        }
+        let save_path = Path::new(image).with_extension("sav");
+        let image_bytes = match std::fs::read(&save_path) {
+            Ok(bytes) => {
+                info!("Loaded disk save: {}", save_path.display());
+                bytes
+            }
+            Err(_) => std::fs::read(image)?,
+        };
+
+        let sides = disk::load_image(&image_bytes)?;
+        Ok(Self::new(&bios_bytes, sides, save_path))
+    }
+
+    fn new(bios: &[u8], sides: Vec<Vec<u8>>, save_path: PathBuf) -> Self {
+        let mut fds = Self {
+            bios: Mem::default(),
+            prg_ram: Mem::default(),
+            chr_ram: Mem::default(),
+            drive: DiskDrive::new(sides),
+            audio: FdsAudio::default(),
+            save_path,
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            mirroring: Mirroring::Horizontal,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            ext_output: 0,
+        };
+        fds.bios.as_mut_slice().copy_from_slice(bios);
+        fds
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn save(&mut self) -> Result<(), FdsError> {
+        std::fs::write(&self.save_path, self.drive.image())?;
+        info!("Saved disk to {}", self.save_path.display());
+        Ok(())
+    }
+
+    /// Writes the disk back when the game changed it since the last save.
+    pub fn save_modified(&mut self) {
+        if self.drive.modified() {
+            self.save()
+                .unwrap_or_else(|err| error!("Failed to save disk: {}", err));
+        }
+    }
+
+    /// Ejects the disk, saving it first so the writes survive side switches.
+    pub fn eject(&mut self) {
+        self.save_modified();
+        self.drive.eject();
+    }
+
+    /// Reads without the side effects of acknowledging IRQs, for debugging views.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => Some(self.timer_irq as u8 | ((self.drive.transfer_complete() as u8) << 1)),
+            0x4031 => Some(self.drive.read_data()),
+            0x4032 => Some(self.drive.status()),
+            0x4033 => Some(0x80 | (self.ext_output & 0x7F)),
+            0x4040..=0x4097 if self.sound_io_enabled => self.audio.read(addr),
+            0x6000..=0xDFFF => Some(self.prg_ram.read(addr - 0x6000)),
+            0xE000..=0xFFFF => Some(self.bios.read(addr)),
+            _ => None,
+        }
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = match addr {
+            0x4030..=0x4033 if !self.disk_io_enabled => return None,
+            _ => self.peek(addr),
+        };
+        match addr {
+            0x4030 => {
+                self.timer_irq = false;
+                self.drive.acknowledge();
+            }
+            0x4031 => self.drive.acknowledge(),
+            _ => {}
+        }
+        data
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => {
+                self.timer_reload = (self.timer_reload & 0xFF00) | data as u16;
+                self.timer_irq = false;
+            }
+            0x4021 => {
+                self.timer_reload = (self.timer_reload & 0x00FF) | ((data as u16) << 8);
+                self.timer_irq = false;
+            }
+            0x4022 => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0 && self.disk_io_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = data & 0x01 != 0;
+                self.sound_io_enabled = data & 0x02 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.drive.acknowledge();
+                }
+            }
+            0x4024 if self.disk_io_enabled => self.drive.write_data(data),
+            0x4025 if self.disk_io_enabled => {
+                self.mirroring = if data & 0x08 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.drive.write_control(data);
+            }
+            0x4026 if self.disk_io_enabled => self.ext_output = data,
+            0x4040..=0x4092 if self.sound_io_enabled => self.audio.write(addr, data),
+            0x6000..=0xDFFF => self.prg_ram.write(addr - 0x6000, data),
+            _ => {}
+        }
+    }
+
+    pub fn ppu_read(&self, addr: u16) -> u8 {
+        self.chr_ram.read(addr)
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr_ram.write(addr, data);
+    }
+
+    /// Clocks the timer IRQ, the disk drive and the sound channel for one CPU cycle, and
+    /// returns the level of the expansion audio.
+    pub fn tick(&mut self) -> f32 {
+        if self.timer_enabled {
+            if self.timer_counter == 0 {
+                self.timer_irq = true;
+                self.timer_counter = self.timer_reload;
+                if !self.timer_repeat {
+                    self.timer_enabled = false;
+                }
+            } else {
+                self.timer_counter -= 1;
+            }
+        }
+        self.drive.tick();
+        self.audio.tick();
+        self.audio.output()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.timer_irq || self.drive.irq()
+    }
+}
+
+pub fn fds_gui(mut query: Query<&mut Fds>, mut contexts: EguiContexts) {
+    egui::Window::new("Disk System").show(contexts.ctx_mut(), |ui| {
+        let Ok(mut fds) = query.get_single_mut() else {
+            ui.label("No disk system found");
+            return;
+        };
+        match fds.drive.side() {
+            Some(side) => ui.label(format!(
+                "disk {} side {}",
+                side / 2 + 1,
+                if side % 2 == 0 { 'A' } else { 'B' }
+            )),
+            None => ui.label("no disk inserted"),
+        };
+        ui.monospace(format!("mirroring: {:?}", fds.mirroring));
+        ui.separator();
+        if ui.button("Eject").clicked() {
+            fds.eject();
+        }
+        ui.horizontal_wrapped(|ui| {
+            for side in 0..fds.drive.side_count() {
+                let label = format!("{}{}", side / 2 + 1, if side % 2 == 0 { 'A' } else { 'B' });
+                // the BIOS only notices a new side after the previous one was ejected
+                let inserted = fds.drive.side();
+                if ui
+                    .add_enabled(
+                        inserted.is_none(),
+                        egui::SelectableLabel::new(inserted == Some(side), label),
+                    )
+                    .clicked()
+                {
+                    fds.drive.insert(side);
+                }
+            }
+        });
+        ui.separator();
+        if ui
+            .add_enabled(fds.drive.modified(), egui::Button::new("Save disk"))
+            .clicked()
+        {
+            fds.save_modified();
+        }
+    });
+}
+
+/// Saves the modified disk when the emulator exits.
+pub fn save_disk_on_exit(mut exit: EventReader<AppExit>, mut query: Query<&mut Fds>) {
+    if exit.read().next().is_none() {
+        return;
+    }
+    if let Ok(mut fds) = query.get_single_mut() {
+        fds.save_modified();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        disk::{add_gaps, SIDE_SIZE},
+        Fds,
+    };
+    use crate::cartridge::Mirroring;
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+
+    fn fds() -> Fds {
+        let mut side = vec![0x00; SIDE_SIZE];
+        side[0] = 0x01;
+        Fds::new(&[0x00; 0x2000], vec![add_gaps(&side)], "test.sav".into())
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4020, 0x03);
+        fds.cpu_write(0x4021, 0x00);
+        // one-shot: fires once the counter went from 3 down to 0
+        fds.cpu_write(0x4022, 0x02);
+        for _ in 0..3 {
+            fds.tick();
+        }
+        assert!(!fds.irq());
+        fds.tick();
+        assert!(fds.irq());
+        assert_eq!(fds.cpu_read(0x4030).map(|data| data & 0x01), Some(0x01));
+        assert!(!fds.irq());
+        for _ in 0..20 {
+            fds.tick();
+        }
+        assert!(!fds.irq());
+
+        // repeat: reloads and fires every 4 cycles
+        fds.cpu_write(0x4022, 0x03);
+        for _ in 0..2 {
+            for _ in 0..4 {
+                fds.tick();
+            }
+            assert!(fds.irq());
+            fds.cpu_read(0x4030);
+            assert!(!fds.irq());
+        }
+    }
+
+    #[test]
+    fn disk_io_disabled() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4020, 0x00);
+        fds.cpu_write(0x4022, 0x03);
+        fds.tick();
+        assert!(fds.irq());
+
+        // turning disk I/O off stops the timer and hides the disk registers
+        fds.cpu_write(0x4023, 0x00);
+        assert!(!fds.irq());
+        assert_eq!(fds.cpu_read(0x4030), None);
+        assert_eq!(fds.cpu_read(0x4032), None);
+        fds.cpu_write(0x4025, 0x00);
+        assert_eq!(fds.mirroring(), Mirroring::Horizontal);
+        fds.cpu_write(0x4022, 0x03);
+        fds.tick();
+        assert!(!fds.irq());
+
+        fds.cpu_write(0x4023, 0x01);
+        fds.cpu_write(0x4025, 0x00);
+        assert_eq!(fds.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn transfer_status() {
+        let mut fds = fds();
+        fds.cpu_write(0x4023, 0x01);
+        // IRQ on transfer, disk ready, read mode, motor on
+        fds.cpu_write(0x4025, 0xC5);
+        let mut cycles = 0;
+        while !fds.irq() {
+            fds.tick();
+            cycles += 1;
+            assert!(cycles < 1_000_000, "the first block should be read");
+        }
+        assert_eq!(fds.peek(0x4030).map(|data| data & 0x02), Some(0x02));
+        assert_eq!(fds.cpu_read(0x4031), Some(0x01));
+        assert!(!fds.irq());
+        assert_eq!(fds.peek(0x4030).map(|data| data & 0x02), Some(0x00));
+    }
+
+    #[test]
+    fn saves_modified_disk() {
+        let path = std::env::temp_dir().join("nes-rs-fds-save.sav");
+        let _ = std::fs::remove_file(&path);
+        let mut fds = fds();
+        fds.save_path = path.clone();
+        let write_byte = |fds: &mut Fds| {
+            fds.cpu_write(0x4023, 0x01);
+            // write mode, disk ready, motor on
+            fds.cpu_write(0x4025, 0x41);
+            fds.cpu_write(0x4024, 0x5A);
+            while !fds.drive.modified() {
+                fds.tick();
+            }
+        };
+
+        write_byte(&mut fds);
+        fds.eject();
+        assert!(!fds.drive.modified());
+        assert_eq!(std::fs::read(&path).unwrap().len(), SIDE_SIZE);
+        std::fs::remove_file(&path).unwrap();
+
+        fds.drive.insert(0);
+        write_byte(&mut fds);
+        let mut app = App::new();
+        app.add_event::<AppExit>();
+        app.world_mut().spawn(fds);
+        app.world_mut().run_system_once(super::save_disk_on_exit);
+        assert!(!path.exists(), "only saved on exit");
+        app.world_mut().send_event(AppExit::Success);
+        app.world_mut().run_system_once(super::save_disk_on_exit);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+/// gain applied by the master volume setting of $4089
+const MASTER_VOLUME: [u32; 4] = [36, 24, 17, 14];
+
+/// modulation table entries, 4 resets the counter
+const MOD_ADJUST: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+/// Shared behavior of the volume and modulation units, a 12 bit frequency and an
+/// envelope moving the gain by one every 8 * (speed + 1) * master speed cycles.
+#[derive(Default)]
+struct Unit {
+    speed: u8,
+    gain: u8,
+    envelope_off: bool,
+    increase: bool,
+    frequency: u16,
+    timer: u32,
+}
+
+impl Unit {
+    fn write(&mut self, addr: u16, data: u8, master_speed: u8) {
+        match addr & 0x03 {
+            0x00 => {
+                self.speed = data & 0x3F;
+                self.increase = data & 0x40 != 0;
+                self.envelope_off = data & 0x80 != 0;
+                self.reset_timer(master_speed);
+                if self.envelope_off {
+                    self.gain = self.speed;
+                }
+            }
+            0x02 => self.frequency = (self.frequency & 0x0F00) | data as u16,
+            0x03 => self.frequency = (self.frequency & 0x00FF) | (((data & 0x0F) as u16) << 8),
+            _ => {}
+        }
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn tick_envelope(&mut self, master_speed: u8) -> bool {
+        if self.envelope_off || master_speed == 0 {
+            return false;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.reset_timer(master_speed);
+            if self.increase && self.gain < 32 {
+                self.gain += 1;
+            } else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Wavetable sound channel of the RAM adapter, registers $4040-$408A.
+pub struct FdsAudio {
+    wave_table: [u8; 0x40],
+    wave_write: bool,
+    wave_halt: bool,
+    envelopes_halt: bool,
+    wave_accumulator: u32,
+    wave_position: usize,
+    master_volume: usize,
+    master_speed: u8,
+    volume: Unit,
+    modulator: Unit,
+    mod_table: [u8; 0x40],
+    mod_position: usize,
+    mod_halt: bool,
+    mod_counter: i8,
+    mod_accumulator: u16,
+    mod_output: i32,
+    output: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self {
+            wave_table: [0; 0x40],
+            wave_write: false,
+            wave_halt: true,
+            envelopes_halt: false,
+            wave_accumulator: 0,
+            wave_position: 0,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: Unit::default(),
+            modulator: Unit::default(),
+            mod_table: [0; 0x40],
+            mod_position: 0,
+            mod_halt: true,
+            mod_counter: 0,
+            mod_accumulator: 0,
+            mod_output: 0,
+            output: 0,
+        }
+    }
+}
+
+impl FdsAudio {
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave_table[(addr & 0x3F) as usize]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.modulator.gain),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => {
+                self.wave_table[(addr & 0x3F) as usize] = data & 0x3F;
+            }
+            0x4080 | 0x4082 => self.volume.write(addr, data, self.master_speed),
+            0x4083 => {
+                self.envelopes_halt = data & 0x40 != 0;
+                self.wave_halt = data & 0x80 != 0;
+                if self.envelopes_halt {
+                    self.volume.reset_timer(self.master_speed);
+                    self.modulator.reset_timer(self.master_speed);
+                }
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                self.volume.write(addr, data, self.master_speed);
+            }
+            0x4084 | 0x4086 => self.modulator.write(addr, data, self.master_speed),
+            0x4085 => self.set_mod_counter((data & 0x7F) as i8),
+            0x4087 => {
+                self.modulator.write(addr, data, self.master_speed);
+                self.mod_halt = data & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // every write fills two consecutive entries of the table
+            0x4088 if self.mod_halt => {
+                self.mod_table[self.mod_position] = data & 0x07;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = data & 0x07;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.master_volume = (data & 0x03) as usize;
+                self.wave_write = data & 0x80 != 0;
+            }
+            0x408A => self.master_speed = data,
+            _ => {}
+        }
+    }
+
+    /// 7 bit signed modulation counter
+    fn set_mod_counter(&mut self, value: i8) {
+        self.mod_counter = match value {
+            64.. => value.wrapping_sub(-128),
+            ..-64 => value.wrapping_add(-128),
+            _ => value,
+        };
+    }
+
+    fn update_mod_output(&mut self) {
+        let mut temp = self.mod_counter as i32 * self.modulator.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        temp *= self.volume.frequency as i32;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        self.mod_output = temp;
+    }
+
+    fn tick_modulator(&mut self) -> bool {
+        if self.mod_halt || self.modulator.frequency == 0 {
+            return false;
+        }
+        let (accumulator, overflow) = self
+            .mod_accumulator
+            .overflowing_add(self.modulator.frequency);
+        self.mod_accumulator = accumulator;
+        if overflow {
+            let entry = self.mod_table[self.mod_position];
+            if entry == 4 {
+                self.set_mod_counter(0);
+            } else {
+                self.set_mod_counter(self.mod_counter.wrapping_add(MOD_ADJUST[entry as usize]));
+            }
+            self.mod_position = (self.mod_position + 1) & 0x3F;
+        }
+        overflow
+    }
+
+    /// clocked once per CPU cycle
+    pub fn tick(&mut self) {
+        if !self.wave_halt && !self.envelopes_halt {
+            self.volume.tick_envelope(self.master_speed);
+            if self.modulator.tick_envelope(self.master_speed) {
+                self.update_mod_output();
+            }
+        }
+        if self.tick_modulator() {
+            self.update_mod_output();
+        }
+
+        if !self.wave_halt && !self.wave_write {
+            let pitch = self.volume.frequency as i32 + self.mod_output;
+            if pitch > 0 {
+                self.wave_accumulator = (self.wave_accumulator + pitch as u32) & 0xFFFF;
+                self.wave_position = ((self.wave_accumulator >> 10) & 0x3F) as usize;
+            }
+        }
+
+        // the output is held while the wave RAM is being written
+        if !self.wave_write {
+            let level = (self.volume.gain.min(32) as u32) * MASTER_VOLUME[self.master_volume];
+            self.output = ((self.wave_table[self.wave_position] as u32 * level) / 1152) as u8;
+        }
+    }
+
+    /// current output level between 0.0 and 1.0
+    pub fn output(&self) -> f32 {
+        self.output as f32 / 63.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FdsAudio;
+
+    #[test]
+    fn wave_writes() {
+        let mut audio = FdsAudio::default();
+        // wave RAM is read only until $4089 bit 7 is set
+        audio.write(0x4040, 0x3F);
+        assert_eq!(audio.read(0x4040), Some(0x00));
+        audio.write(0x4089, 0x80);
+        audio.write(0x4040, 0x3F);
+        audio.write(0x407F, 0xFF);
+        assert_eq!(audio.read(0x4040), Some(0x3F));
+        assert_eq!(audio.read(0x407F), Some(0x3F));
+
+        // full volume, wave running at frequency 0 so it stays on the first entry
+        audio.write(0x4080, 0xA0);
+        audio.write(0x4083, 0x00);
+        audio.write(0x4089, 0x00);
+        audio.tick();
+        assert_eq!(audio.output(), 1.0);
+
+        // the output holds its level while the table is written
+        audio.write(0x4089, 0x80);
+        audio.write(0x4040, 0x00);
+        audio.tick();
+        assert_eq!(audio.output(), 1.0);
+        audio.write(0x4089, 0x00);
+        audio.tick();
+        assert_eq!(audio.output(), 0.0);
+    }
+}
@@ -0,0 +1,383 @@
+use super::FdsError;
+
+/// size of one disk side in a .fds image
+pub const SIDE_SIZE: usize = 65500;
+
+/// size of a side once the gaps between blocks are restored
+const SIDE_CAPACITY: usize = 68000;
+
+/// gap of zeros before the first block, 28300 bits
+const LEADING_GAP: usize = 28300 / 8;
+
+/// gap of zeros between two blocks, 976 bits
+const BLOCK_GAP: usize = 976 / 8;
+
+/// cycles between the motor reaching the start of the disk and the first byte
+const END_OF_HEAD_DELAY: u32 = 50000;
+
+/// cycles needed to transfer one byte
+const BYTE_DELAY: u32 = 150;
+
+/// Length of the block starting with `block_type`, `file_size` being the size announced
+/// by the last file header block.
+fn block_length(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        0x01 => Some(56),
+        0x02 => Some(2),
+        0x03 => Some(16),
+        0x04 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+/// Splits a .fds image into disk sides, dropping the optional fwNES header.
+pub fn load_image(bytes: &[u8]) -> Result<Vec<Vec<u8>>, FdsError> {
+    let bytes = match bytes {
+        [b'F', b'D', b'S', 0x1A, ..] => &bytes[16..],
+        _ => bytes,
+    };
+    if bytes.is_empty() || bytes.len() % SIDE_SIZE != 0 {
+        return Err(FdsError::InvalidImage);
+    }
+    Ok(bytes.chunks(SIDE_SIZE).map(add_gaps).collect())
+}
+
+/// Rebuilds the raw disk layout the drive sees: each block is preceded by a gap and a
+/// start mark and followed by its CRC.
+pub fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0x00; LEADING_GAP];
+    let mut position = 0;
+    let mut file_size = 0;
+    while position < side.len() {
+        let Some(length) = block_length(side[position], file_size) else {
+            break;
+        };
+        let Some(block) = side.get(position..position + length) else {
+            break;
+        };
+        if block[0] == 0x03 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        disk.push(0x80);
+        disk.extend_from_slice(block);
+        // the BIOS does not check the CRC of blocks read from the disk
+        disk.extend_from_slice(&[0x4D, 0x62]);
+        disk.extend(std::iter::repeat_n(0x00, BLOCK_GAP));
+        position += length;
+    }
+    disk.resize(SIDE_CAPACITY.max(disk.len()), 0x00);
+    disk
+}
+
+/// Inverse of `add_gaps`, turns a raw disk side back into its .fds representation.
+pub fn strip_gaps(disk: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut position = 0;
+    let mut file_size = 0;
+    loop {
+        while position < disk.len() && disk[position] == 0x00 {
+            position += 1;
+        }
+        // skip the start mark
+        position += 1;
+        let Some(length) = disk
+            .get(position)
+            .and_then(|block_type| block_length(*block_type, file_size))
+        else {
+            break;
+        };
+        let Some(block) = disk.get(position..position + length) else {
+            break;
+        };
+        if block[0] == 0x03 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        side.extend_from_slice(block);
+        position += length + 2;
+    }
+    side.resize(SIDE_SIZE, 0x00);
+    side
+}
+
+/// Serial transfer between the RAM adapter and the disk drive, one byte at a time while
+/// the motor is on.
+#[derive(Default)]
+pub struct DiskDrive {
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    modified: bool,
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    irq_enabled: bool,
+    irq: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    position: usize,
+    delay: u32,
+    crc: u16,
+    read_data: u8,
+    write_data: u8,
+}
+
+impl DiskDrive {
+    pub fn new(sides: Vec<Vec<u8>>) -> Self {
+        Self {
+            side: (!sides.is_empty()).then_some(0),
+            sides,
+            end_of_head: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    pub fn insert(&mut self, side: usize) {
+        if side < self.sides.len() {
+            self.side = Some(side);
+            self.end_of_head = true;
+        }
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.scanning = false;
+    }
+
+    /// all sides back in the .fds layout
+    pub fn image(&mut self) -> Vec<u8> {
+        self.modified = false;
+        self.sides
+            .iter()
+            .flat_map(|side| strip_gaps(side))
+            .collect()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+
+    /// $4025
+    pub fn write_control(&mut self, data: u8) {
+        self.motor_on = data & 0x01 != 0;
+        self.reset_transfer = data & 0x02 != 0;
+        self.read_mode = data & 0x04 != 0;
+        self.crc_control = data & 0x10 != 0;
+        self.disk_ready = data & 0x40 != 0;
+        self.irq_enabled = data & 0x80 != 0;
+        self.irq = false;
+    }
+
+    /// $4024
+    pub fn write_data(&mut self, data: u8) {
+        self.write_data = data;
+        self.transfer_complete = false;
+        self.irq = false;
+    }
+
+    /// bit 1 of $4030
+    pub fn transfer_complete(&self) -> bool {
+        self.transfer_complete
+    }
+
+    /// reading $4030 acknowledges the transfer
+    pub fn acknowledge(&mut self) {
+        self.transfer_complete = false;
+        self.irq = false;
+    }
+
+    /// $4031
+    pub fn read_data(&self) -> u8 {
+        self.read_data
+    }
+
+    /// $4032
+    pub fn status(&self) -> u8 {
+        let inserted = self.side.is_some();
+        0x40 | (!inserted as u8)
+            | (((!inserted || !self.scanning) as u8) << 1)
+            | ((!inserted as u8) << 2)
+    }
+
+    fn update_crc(&mut self, data: u8) {
+        for bit in 0..8 {
+            let carry = self.crc & 0x01 != 0;
+            self.crc >>= 1;
+            if carry {
+                self.crc ^= 0x8408;
+            }
+            if data & (1 << bit) != 0 {
+                self.crc ^= 0x8000;
+            }
+        }
+    }
+
+    /// clocked once per CPU cycle
+    pub fn tick(&mut self) {
+        let Some(side) = self.side else {
+            return;
+        };
+        if !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = END_OF_HEAD_DELAY;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut trigger_irq = self.irq_enabled;
+        if self.read_mode {
+            let data = self.sides[side][self.position];
+            if !self.previous_crc_control {
+                self.update_crc(data);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if data != 0x00 && !self.gap_ended {
+                // the start mark itself is not transferred
+                self.gap_ended = true;
+                trigger_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.irq |= trigger_irq;
+            }
+        } else {
+            let mut data = 0x00;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.irq |= trigger_irq;
+            }
+            if !self.disk_ready {
+                data = 0x00;
+            }
+            if !self.crc_control {
+                self.update_crc(data);
+            } else {
+                if !self.previous_crc_control {
+                    self.update_crc(0x00);
+                    self.update_crc(0x00);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.sides[side][self.position] = data;
+            self.modified = true;
+            self.gap_ended = false;
+        }
+
+        self.previous_crc_control = self.crc_control;
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_gaps, strip_gaps, DiskDrive, BYTE_DELAY, END_OF_HEAD_DELAY, SIDE_SIZE};
+
+    #[test]
+    fn gaps_round_trip() {
+        let mut side = vec![0x00; SIDE_SIZE];
+        side[0] = 0x01;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        side[56] = 0x02;
+        side[57] = 0x01;
+        // file header announcing 4 bytes of data
+        side[58] = 0x03;
+        side[71] = 0x04;
+        side[74] = 0x04;
+        side[75..79].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+
+        let disk = add_gaps(&side);
+        assert!(disk[..3537].iter().all(|byte| *byte == 0x00));
+        assert_eq!(disk[3537], 0x80);
+        assert_eq!(disk[3538], 0x01);
+        assert_eq!(strip_gaps(&disk), side);
+    }
+
+    #[test]
+    fn transfer_timing() {
+        let mut side = vec![0x00; SIDE_SIZE];
+        side[0] = 0x01;
+        side[1] = 0x2A;
+        let mut drive = DiskDrive::new(vec![add_gaps(&side)]);
+        // IRQ on transfer, disk ready, read mode, motor on
+        drive.write_control(0xC5);
+
+        // one cycle to spin up, then the gap before the start mark is read without
+        // transfers, each byte taking a cycle and the delay before the next one
+        let start_mark = disk_position(&drive, 0x80);
+        let first_byte = 2 + END_OF_HEAD_DELAY + (start_mark as u32 + 1) * (BYTE_DELAY + 1);
+        for _ in 0..first_byte - 1 {
+            drive.tick();
+            assert!(!drive.irq());
+        }
+        assert!(drive.transfer_complete(), "the start mark was read");
+        drive.acknowledge();
+        drive.tick();
+        assert!(drive.irq());
+        assert!(drive.transfer_complete());
+        assert_eq!(drive.read_data(), 0x01);
+
+        drive.acknowledge();
+        assert!(!drive.irq() && !drive.transfer_complete());
+        for _ in 0..BYTE_DELAY {
+            drive.tick();
+        }
+        assert!(!drive.irq());
+        drive.tick();
+        assert!(drive.irq());
+        assert_eq!(drive.read_data(), 0x2A);
+
+        // no IRQ without the enable bit
+        drive.write_control(0x45);
+        for _ in 0..=BYTE_DELAY {
+            drive.tick();
+        }
+        assert!(!drive.irq());
+        assert!(drive.transfer_complete());
+    }
+
+    fn disk_position(drive: &DiskDrive, data: u8) -> usize {
+        drive.sides[0]
+            .iter()
+            .position(|byte| *byte == data)
+            .unwrap()
+    }
+}
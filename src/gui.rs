@@ -2,13 +2,22 @@ use bevy::{input::common_conditions::input_toggle_active, prelude::*};
 use bevy_egui::{egui, EguiContexts};
 
 use crate::{
+    apu::{
+        apu_gui,
+        recorder::{recorder_gui, RecorderState},
+    },
     cartridge::cartridge_gui,
     cpu::{cpu_gui, disassembly_gui},
     cpu_bus::wram_gui,
+    fds::fds_gui,
+    nsf::nsf_gui,
     ppu::{
-        draw_pattern_buffer, init_pattern_buffer, oam_gui, pattern_gui, ppu_gui,
-        update_pattern_buffer,
+        draw_nametable_buffer, draw_pattern_buffer, draw_sprite_buffer, init_nametable_buffer,
+        init_pattern_buffer, init_sprite_buffer, nametable_gui, palette_gui, pattern_gui, ppu_gui,
+        sprite_gui, sprite_overlay, update_nametable_buffer, update_pattern_buffer,
+        update_sprite_buffer,
     },
+    vs::vs_gui,
 };
 
 pub struct GuiPlugin;
@@ -16,7 +25,15 @@ pub struct GuiPlugin;
 impl Plugin for GuiPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<GuiState>()
-            .add_systems(Startup, init_pattern_buffer)
+            .init_resource::<RecorderState>()
+            .add_systems(
+                Startup,
+                (
+                    init_pattern_buffer,
+                    init_nametable_buffer,
+                    init_sprite_buffer,
+                ),
+            )
             .add_systems(
                 Update,
                 (
@@ -25,9 +42,16 @@ impl Plugin for GuiPlugin {
                     disassembly_gui.run_if(disassembly_gui_enabled),
                     wram_gui.run_if(wram_gui_enabled),
                     cartridge_gui.run_if(cartridge_gui_enabled),
+                    fds_gui.run_if(fds_gui_enabled),
+                    nsf_gui.run_if(nsf_gui_enabled),
+                    vs_gui.run_if(vs_gui_enabled),
+                    recorder_gui.run_if(recorder_gui_enabled),
                     ppu_gui.run_if(ppu_gui_enabled),
+                    palette_gui.run_if(palette_gui_enabled),
                     pattern_gui.run_if(pattern_gui_enabled),
-                    oam_gui.run_if(oam_gui_enabled),
+                    nametable_gui.run_if(nametable_gui_enabled),
+                    sprite_gui.run_if(sprite_gui_enabled),
+                    apu_gui.run_if(apu_gui_enabled),
                 )
                     .run_if(input_toggle_active(false, KeyCode::KeyU)),
             )
@@ -38,6 +62,23 @@ impl Plugin for GuiPlugin {
                     draw_pattern_buffer.after(update_pattern_buffer),
                 )
                     .run_if(pattern_gui_enabled),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    update_nametable_buffer,
+                    draw_nametable_buffer.after(update_nametable_buffer),
+                )
+                    .run_if(nametable_gui_enabled),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    update_sprite_buffer,
+                    draw_sprite_buffer.after(update_sprite_buffer),
+                    sprite_overlay,
+                )
+                    .run_if(sprite_gui_enabled),
             );
     }
 }
@@ -48,9 +89,16 @@ pub struct GuiState {
     wram: bool,
     disassembly: bool,
     cartridge: bool,
+    fds: bool,
+    nsf: bool,
+    vs: bool,
+    recorder: bool,
     ppu: bool,
+    palette: bool,
     pattern: bool,
-    oam: bool,
+    nametable: bool,
+    sprite: bool,
+    apu: bool,
 }
 
 fn cpu_gui_enabled(state: Res<GuiState>) -> bool {
@@ -69,16 +117,44 @@ fn cartridge_gui_enabled(state: Res<GuiState>) -> bool {
     state.cartridge
 }
 
+fn fds_gui_enabled(state: Res<GuiState>) -> bool {
+    state.fds
+}
+
+fn nsf_gui_enabled(state: Res<GuiState>) -> bool {
+    state.nsf
+}
+
+fn vs_gui_enabled(state: Res<GuiState>) -> bool {
+    state.vs
+}
+
+fn recorder_gui_enabled(state: Res<GuiState>) -> bool {
+    state.recorder
+}
+
 fn ppu_gui_enabled(state: Res<GuiState>) -> bool {
     state.ppu
 }
 
+fn palette_gui_enabled(state: Res<GuiState>) -> bool {
+    state.palette
+}
+
 fn pattern_gui_enabled(state: Res<GuiState>) -> bool {
     state.pattern
 }
 
-fn oam_gui_enabled(state: Res<GuiState>) -> bool {
-    state.oam
+fn nametable_gui_enabled(state: Res<GuiState>) -> bool {
+    state.nametable
+}
+
+fn sprite_gui_enabled(state: Res<GuiState>) -> bool {
+    state.sprite
+}
+
+fn apu_gui_enabled(state: Res<GuiState>) -> bool {
+    state.apu
 }
 
 fn side_panel(mut contexts: EguiContexts, mut state: ResMut<GuiState>) {
@@ -104,17 +180,41 @@ fn side_panel(mut contexts: EguiContexts, mut state: ResMut<GuiState>) {
                 if ui.selectable_label(state.cartridge, "Cartridge").clicked() {
                     state.cartridge = !state.cartridge;
                 }
+                if ui.selectable_label(state.fds, "Disk System").clicked() {
+                    state.fds = !state.fds;
+                }
+                if ui.selectable_label(state.nsf, "NSF Player").clicked() {
+                    state.nsf = !state.nsf;
+                }
+                if ui.selectable_label(state.vs, "Vs. System").clicked() {
+                    state.vs = !state.vs;
+                }
+                if ui
+                    .selectable_label(state.recorder, "Audio Recording")
+                    .clicked()
+                {
+                    state.recorder = !state.recorder;
+                }
                 if ui.selectable_label(state.ppu, "PPU").clicked() {
                     state.ppu = !state.ppu;
                 }
+                if ui.selectable_label(state.palette, "Palettes").clicked() {
+                    state.palette = !state.palette;
+                }
                 if ui
                     .selectable_label(state.pattern, "Pattern table")
                     .clicked()
                 {
                     state.pattern = !state.pattern;
                 }
-                if ui.selectable_label(state.oam, "OAM Memory").clicked() {
-                    state.oam = !state.oam;
+                if ui.selectable_label(state.nametable, "Nametables").clicked() {
+                    state.nametable = !state.nametable;
+                }
+                if ui.selectable_label(state.sprite, "Sprites").clicked() {
+                    state.sprite = !state.sprite;
+                }
+                if ui.selectable_label(state.apu, "APU").clicked() {
+                    state.apu = !state.apu;
                 }
             });
         });
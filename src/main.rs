@@ -1,6 +1,5 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, window::PresentMode};
 use bevy_egui::EguiPlugin;
-use bevy_fundsp::prelude::*;
 use bevy_pixel_buffer::pixel_buffer::PixelBufferPlugins;
 use clap::Parser;
 use gui::GuiPlugin;
@@ -10,17 +9,32 @@ mod apu;
 mod cartridge;
 mod cpu;
 mod cpu_bus;
+mod fds;
 mod gui;
 mod mem;
 mod nes;
+mod nsf;
 mod ppu;
+mod region;
+mod slot;
+mod vs;
 
 fn main() {
     let args = nes::ArgsResource::parse();
 
     App::new()
-        .add_plugins((DefaultPlugins, PixelBufferPlugins, EguiPlugin))
-        .add_plugins(DspPlugin::default())
+        .add_plugins((
+            // frames are presented at the display refresh rate
+            DefaultPlugins.set(WindowPlugin {
+                primary_window: Some(Window {
+                    present_mode: PresentMode::AutoVsync,
+                    ..default()
+                }),
+                ..default()
+            }),
+            PixelBufferPlugins,
+            EguiPlugin,
+        ))
         .add_plugins((GuiPlugin, NesPlugin::new(args)))
         .run();
 }
@@ -2,11 +2,15 @@ use bevy::prelude::*;
 use clap::Parser;
 
 use crate::{
-    apu::{Apu, ApuPlugin},
+    apu::{Apu, ApuPlugin, FilterProfile},
     cartridge::Cartridge,
-    cpu::{Cpu, CpuPlugin, SystemClock},
+    cpu::{Cpu, CpuPlugin, FrameLimit, SystemClock},
     cpu_bus::{update_controller_state, Controller, Dma, Wram},
+    fds::{save_disk_on_exit, Fds},
+    nsf::Nsf,
     ppu::{PalettePlugin, Ppu, PpuPlugin},
+    region::Region,
+    vs::{update_vs_inputs, VsPpu, VsSystem},
 };
 
 #[derive(Default, Component)]
@@ -24,12 +28,62 @@ pub struct NesBundle {
     controller: Controller,
 }
 
+impl NesBundle {
+    pub fn set_region(&mut self, region: Region) {
+        self.system_clock.set_region(region);
+        self.ppu.region = region;
+        self.apu.set_region(region);
+    }
+}
+
 #[derive(Parser, Resource, Clone)]
 #[command(version, about, long_about = None)]
 pub struct ArgsResource {
     #[arg(short, long)]
     /// optional path to a rom file.
     pub rom: Option<String>,
+
+    #[arg(long)]
+    /// force bus conflict emulation on or off for discrete mapper boards, over the NES 2.0
+    /// submapper and the game database.
+    pub bus_conflicts: Option<bool>,
+
+    #[arg(long)]
+    /// path to the Famicom Disk System BIOS, required to load .fds disk images.
+    pub fds_bios: Option<String>,
+
+    #[arg(long, value_enum, default_value_t)]
+    /// analog filtering of the audio output.
+    pub audio_filter: FilterProfile,
+
+    #[arg(long, value_enum)]
+    /// console timing, detected from the NES 2.0 header or the NSF file by default.
+    pub region: Option<Region>,
+
+    #[arg(long)]
+    /// record the audio output to a 16 bit WAV file from startup.
+    pub record_wav: Option<String>,
+
+    #[arg(long, requires = "record_wav")]
+    /// also record each channel to its own WAV file next to the recording.
+    pub record_stems: bool,
+
+    #[arg(long)]
+    /// log APU register writes from startup to a VGM file, saved on exit.
+    pub record_vgm: Option<String>,
+
+    #[arg(long)]
+    /// run from reset for this many frames, then finish the recordings and exit.
+    pub record_frames: Option<u32>,
+
+    #[arg(long, value_enum)]
+    /// run the rom as a Vs. System game with this PPU, read from the NES 2.0 header or the
+    /// game database by default.
+    pub vs_ppu: Option<VsPpu>,
+
+    #[arg(long)]
+    /// blend the picture through an NTSC composite signal filter, 602 pixels wide.
+    pub ntsc_filter: bool,
 }
 
 pub struct NesPlugin {
@@ -47,20 +101,67 @@ impl Plugin for NesPlugin {
         app.insert_resource(self.args.clone())
             .add_plugins((CpuPlugin, PpuPlugin, PalettePlugin, ApuPlugin))
             .add_systems(Startup, init_nes)
-            .add_systems(FixedPreUpdate, update_controller_state);
+            .add_systems(PreUpdate, (update_controller_state, update_vs_inputs))
+            .add_systems(Last, save_disk_on_exit);
     }
 }
 
 fn init_nes(mut commands: Commands, args: Res<ArgsResource>) {
-    match &args.rom {
+    let (mut entity, region, vs_ppu) = match &args.rom {
+        Some(rom_path) if rom_path.to_lowercase().ends_with(".fds") => {
+            let bios_path = args
+                .fds_bios
+                .as_ref()
+                .expect("An FDS BIOS path should be given to load disk images.");
+            let fds = Fds::from_files(rom_path, bios_path)
+                .expect("Rom path should point to a valid disk image.");
+            info!("Loaded disk image: {}", rom_path);
+            // the Disk System was only sold in Japan
+            (commands.spawn(fds), Region::Ntsc, None)
+        }
+        Some(rom_path)
+            if rom_path.to_lowercase().ends_with(".nsf")
+                || rom_path.to_lowercase().ends_with(".nsfe") =>
+        {
+            let nsf = Nsf::from_file(rom_path).expect("Rom path should point to a valid NSF file.");
+            info!("Loaded NSF: {}", rom_path);
+            let region = nsf.region();
+            (commands.spawn(nsf), region, None)
+        }
         Some(rom_path) => {
-            let cartridge = Cartridge::from_file(&rom_path)
-                .expect("Rom path should point to a valid rom file.");
+            let mut cartridge =
+                Cartridge::from_file(rom_path).expect("Rom path should point to a valid rom file.");
+            if let Some(enabled) = args.bus_conflicts {
+                cartridge.set_bus_conflicts(enabled);
+            }
             info!("Loaded rom: {}", rom_path);
-            commands.spawn((NesBundle::default(), cartridge));
-        }
-        None => {
-            commands.spawn(NesBundle::default());
+            let region = cartridge.region().unwrap_or_default();
+            let vs_ppu = args.vs_ppu.or(cartridge.vs_ppu());
+            (commands.spawn(cartridge), region, vs_ppu)
         }
+        None => (commands.spawn_empty(), Region::default(), None),
+    };
+    let region = args.region.unwrap_or(region);
+    info!("Region: {:?}", region);
+
+    let mut nes = NesBundle::default();
+    nes.set_region(region);
+    if let Some(ppu) = vs_ppu {
+        info!("Vs. System PPU: {:?}", ppu);
+        nes.ppu.vs_ppu = Some(ppu);
+        entity.insert(VsSystem::new(ppu));
+    }
+    nes.apu.set_filter_profile(args.audio_filter);
+    if let Some(path) = &args.record_wav {
+        nes.apu
+            .start_recording(std::path::Path::new(path), args.record_stems)
+            .expect("Recording path should be writable.");
+    }
+    if let Some(path) = &args.record_vgm {
+        nes.apu.start_vgm(std::path::Path::new(path));
+    }
+    entity.insert(nes);
+    if let Some(frames) = args.record_frames {
+        commands.insert_resource(FrameLimit(frames));
     }
 }
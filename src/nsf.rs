@@ -0,0 +1,326 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use file::NsfFile;
+use thiserror::Error;
+
+use crate::{cpu::CpuQuery, mem::Mem, region::Region};
+
+mod file;
+
+const DRIVER_ADDR: u16 = 0x4100;
+const DRIVER_INIT: usize = 0x3B;
+const DRIVER_PLAY: usize = 0x43;
+const DRIVER_IRQ: u16 = DRIVER_ADDR + 0x48;
+
+/// Small program standing in for a game: it clears the RAM, silences the APU, calls INIT
+/// with the song and region registers, then calls PLAY every time the play timer expires.
+#[rustfmt::skip]
+const DRIVER: [u8; 0x49] = [
+    0x78,             // SEI
+    0xD8,             // CLD
+    0xA2, 0xFF,       // LDX #$FF
+    0x9A,             // TXS
+    0xA9, 0x00,       // LDA #$00
+    0xAA,             // TAX
+    0x95, 0x00,       // clear: STA $00,X
+    0x9D, 0x00, 0x01, // STA $0100,X
+    0x9D, 0x00, 0x02, // STA $0200,X
+    0x9D, 0x00, 0x03, // STA $0300,X
+    0x9D, 0x00, 0x04, // STA $0400,X
+    0x9D, 0x00, 0x05, // STA $0500,X
+    0x9D, 0x00, 0x06, // STA $0600,X
+    0x9D, 0x00, 0x07, // STA $0700,X
+    0xE8,             // INX
+    0xD0, 0xE6,       // BNE clear
+    0xA2, 0x13,       // LDX #$13
+    0x9D, 0x00, 0x40, // apu: STA $4000,X
+    0xCA,             // DEX
+    0x10, 0xFA,       // BPL apu
+    0xA9, 0x0F,       // LDA #$0F
+    0x8D, 0x15, 0x40, // STA $4015
+    0xA9, 0x40,       // LDA #$40
+    0x8D, 0x17, 0x40, // STA $4017
+    0xAD, 0xF0, 0x41, // LDA $41F0
+    0xAE, 0xF1, 0x41, // LDX $41F1
+    0x20, 0x00, 0x00, // JSR init
+    0xAD, 0xF2, 0x41, // idle: LDA $41F2
+    0xF0, 0xFB,       // BEQ idle
+    0x20, 0x00, 0x00, // JSR play
+    0x4C, 0x3D, 0x41, // JMP idle
+    0x40,             // RTI
+];
+
+#[derive(Debug, Error)]
+pub enum NsfError {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("invalid NSF file")]
+    InvalidFile,
+    #[error("unsupported NSFe chunk")]
+    UnsupportedChunk,
+}
+
+/// NSF music player plugged in the cartridge slot. The song data is mapped in 4 KiB banks
+/// at $8000-$FFFF, and a driver at $4100 replaces the reset vector.
+#[derive(Component)]
+pub struct Nsf {
+    file: NsfFile,
+    driver: [u8; DRIVER.len()],
+    prg: Vec<u8>,
+    banks: [u8; 8],
+    work_ram: Mem<0x2000>,
+    song: u8,
+    play_period: u32,
+    play_counter: u32,
+    play_pending: bool,
+}
+
+impl Nsf {
+    pub fn from_file(path: &str) -> Result<Self, NsfError> {
+        let file = NsfFile::parse(&std::fs::read(path)?)?;
+        if file.extra_chips != 0 {
+            warn!(
+                "Expansion audio chips {:#04X} are not emulated",
+                file.extra_chips
+            );
+        }
+        Ok(Self::new(file))
+    }
+
+    fn new(file: NsfFile) -> Self {
+        let mut driver = DRIVER;
+        driver[DRIVER_INIT..DRIVER_INIT + 2].copy_from_slice(&file.init_addr.to_le_bytes());
+        driver[DRIVER_PLAY..DRIVER_PLAY + 2].copy_from_slice(&file.play_addr.to_le_bytes());
+
+        // without bankswitching the data is loaded as is at the load address, which is
+        // the same as banks 0 to 7 of data padded from $8000
+        let (padding, banks) = if file.bankswitched() {
+            (file.load_addr as usize & 0x0FFF, file.banks)
+        } else {
+            (
+                file.load_addr.saturating_sub(0x8000) as usize,
+                [0, 1, 2, 3, 4, 5, 6, 7],
+            )
+        };
+        let mut prg = vec![0x00; padding];
+        prg.extend_from_slice(&file.data);
+
+        let play_speed = if file.pal {
+            file.pal_play_speed
+        } else {
+            file.play_speed
+        };
+        let cpu_hz = Self::file_region(&file).cpu_hz();
+        let play_period = (play_speed.max(1) as f64 * cpu_hz / 1_000_000.0) as u32;
+        Self {
+            song: file.starting_song,
+            driver,
+            prg,
+            banks,
+            work_ram: Mem::default(),
+            play_period,
+            play_counter: play_period,
+            play_pending: false,
+            file,
+        }
+    }
+
+    fn file_region(file: &NsfFile) -> Region {
+        if file.pal {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        Self::file_region(&self.file)
+    }
+
+    /// Restores the initial state for `song`, the CPU has to be reset afterwards.
+    pub fn select(&mut self, song: u8) {
+        self.song = song % self.file.total_songs.max(1);
+        self.work_ram = Mem::default();
+        if self.file.bankswitched() {
+            self.banks = self.file.banks;
+        }
+        self.play_counter = self.play_period;
+        self.play_pending = false;
+    }
+
+    /// Reads without the side effect of acknowledging the play timer.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4100..=0x41EF => self.driver.get((addr - DRIVER_ADDR) as usize).copied(),
+            0x41F0 => Some(self.song),
+            0x41F1 => Some(self.file.pal as u8),
+            0x41F2 => Some(self.play_pending as u8),
+            0x5FF8..=0x5FFF => Some(self.banks[(addr & 0x07) as usize]),
+            0x6000..=0x7FFF => Some(self.work_ram.read(addr)),
+            0xFFFA | 0xFFFE => Some(DRIVER_IRQ as u8),
+            0xFFFB | 0xFFFF => Some((DRIVER_IRQ >> 8) as u8),
+            0xFFFC => Some(DRIVER_ADDR as u8),
+            0xFFFD => Some((DRIVER_ADDR >> 8) as u8),
+            0x8000..=0xFFFF => {
+                let bank = self.banks[((addr - 0x8000) >> 12) as usize] as usize;
+                Some(
+                    self.prg
+                        .get(bank * 0x1000 + (addr & 0x0FFF) as usize)
+                        .copied()
+                        .unwrap_or(0x00),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr);
+        if addr == 0x41F2 {
+            self.play_pending = false;
+        }
+        data
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5FF8..=0x5FFF => self.banks[(addr & 0x07) as usize] = data,
+            0x6000..=0x7FFF => self.work_ram.write(addr, data),
+            _ => {}
+        }
+    }
+
+    /// clocked once per CPU cycle, raises the play request at the file's rate
+    pub fn tick(&mut self) {
+        self.play_counter -= 1;
+        if self.play_counter == 0 {
+            self.play_counter = self.play_period;
+            self.play_pending = true;
+        }
+    }
+}
+
+pub fn nsf_gui(mut query: Query<CpuQuery>, mut contexts: EguiContexts) {
+    egui::Window::new("NSF Player").show(contexts.ctx_mut(), |ui| {
+        let Ok(mut query) = query.get_single_mut() else {
+            ui.label("No CPU found");
+            return;
+        };
+        let Some(nsf) = query.nsf() else {
+            ui.label("No NSF loaded");
+            return;
+        };
+        ui.heading(&nsf.file.title);
+        ui.label(&nsf.file.artist);
+        ui.label(&nsf.file.copyright);
+        ui.separator();
+
+        let song = nsf.song;
+        let total_songs = nsf.file.total_songs;
+        let mut selected = None;
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                for track in 0..total_songs {
+                    let mut label = nsf.file.track_title(track);
+                    let time = nsf.file.track_times.get(track as usize);
+                    if let Some(time) = time.filter(|time| **time >= 0) {
+                        label += &format!(" ({}:{:02})", time / 60000, time / 1000 % 60);
+                    }
+                    if ui.selectable_label(track == song, label).clicked() {
+                        selected = Some(track);
+                    }
+                }
+            });
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            if ui.button("previous").clicked() {
+                selected = Some(song.checked_sub(1).unwrap_or(total_songs.saturating_sub(1)));
+            }
+            let running = query.running();
+            if ui.button(if running { "pause" } else { "play" }).clicked() {
+                query.set_running(!running);
+            }
+            if ui.button("next").clicked() {
+                selected = Some(song + 1);
+            }
+        });
+
+        if let Some(track) = selected {
+            if let Some(nsf) = query.nsf() {
+                nsf.select(track);
+            }
+            query.reset();
+            query.set_running(true);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file::NsfFile, Nsf};
+
+    fn nsfe(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut bytes = b"NSFE".to_vec();
+        for (id, data) in chunks {
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(*id);
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn nsfe_chunks() {
+        let bytes = nsfe(&[
+            (
+                b"INFO",
+                &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x00, 0x00, 0x02, 0x01],
+            ),
+            (b"DATA", &[0xEA; 16]),
+            (b"auth", b"Title\0Artist\0Copyright\0Ripper\0"),
+            (b"tlbl", b"Intro\0\0"),
+            (b"NEND", &[]),
+        ]);
+        let file = NsfFile::parse(&bytes).unwrap();
+        assert_eq!(file.init_addr, 0x8003);
+        assert_eq!(file.play_addr, 0x8006);
+        assert_eq!(file.total_songs, 2);
+        assert_eq!(file.starting_song, 1);
+        assert_eq!(file.title, "Title");
+        assert_eq!(file.artist, "Artist");
+        assert_eq!(file.track_title(0), "Intro");
+        assert_eq!(file.track_title(1), "Track 2");
+        assert!(NsfFile::parse(&nsfe(&[(b"ABCD", &[]), (b"NEND", &[])])).is_err());
+    }
+
+    #[test]
+    fn bankswitching() {
+        let mut data = vec![0x00; 0x3000];
+        data[0x0000] = 0x11;
+        data[0x1000] = 0x22;
+        data[0x2000] = 0x33;
+        let mut nsf = Nsf::new(NsfFile {
+            total_songs: 1,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8003,
+            play_speed: 16639,
+            banks: [0, 1, 2, 0, 0, 0, 0, 0],
+            data,
+            ..Default::default()
+        });
+
+        assert_eq!(nsf.cpu_read(0x8000), Some(0x11));
+        assert_eq!(nsf.cpu_read(0xA000), Some(0x33));
+        nsf.cpu_write(0x5FFA, 0x01);
+        assert_eq!(nsf.cpu_read(0xA000), Some(0x22));
+
+        // the reset vector points to the driver, which calls INIT
+        assert_eq!(nsf.cpu_read(0xFFFC), Some(0x00));
+        assert_eq!(nsf.cpu_read(0xFFFD), Some(0x41));
+        assert_eq!(nsf.cpu_read(0x413B), Some(0x00));
+        assert_eq!(nsf.cpu_read(0x413C), Some(0x80));
+    }
+}
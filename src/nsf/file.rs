@@ -0,0 +1,160 @@
+use super::NsfError;
+
+/// Metadata and song data of an NSF or NSFe file.
+#[derive(Default, Debug, PartialEq)]
+pub struct NsfFile {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub total_songs: u8,
+    /// 0 based
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    /// play routine period in microseconds
+    pub play_speed: u16,
+    pub pal_play_speed: u16,
+    pub banks: [u8; 8],
+    pub pal: bool,
+    pub extra_chips: u8,
+    pub track_titles: Vec<String>,
+    /// track durations in milliseconds, negative when unknown
+    pub track_times: Vec<i32>,
+    pub data: Vec<u8>,
+}
+
+impl NsfFile {
+    pub fn parse(bytes: &[u8]) -> Result<Self, NsfError> {
+        match bytes {
+            [b'N', b'E', b'S', b'M', 0x1A, ..] => Self::parse_nsf(bytes),
+            [b'N', b'S', b'F', b'E', ..] => Self::parse_nsfe(bytes),
+            _ => Err(NsfError::InvalidFile),
+        }
+    }
+
+    fn parse_nsf(bytes: &[u8]) -> Result<Self, NsfError> {
+        if bytes.len() < 0x80 {
+            return Err(NsfError::InvalidFile);
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        Ok(Self {
+            total_songs: bytes[0x06],
+            starting_song: bytes[0x07].saturating_sub(1),
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            title: string(&bytes[0x0E..0x2E]),
+            artist: string(&bytes[0x2E..0x4E]),
+            copyright: string(&bytes[0x4E..0x6E]),
+            play_speed: word(0x6E),
+            banks: bytes[0x70..0x78].try_into().unwrap(),
+            pal_play_speed: word(0x78),
+            // bit 1 marks a dual region tune, which plays fine on NTSC
+            pal: bytes[0x7A] & 0x03 == 0x01,
+            extra_chips: bytes[0x7B],
+            track_titles: Vec::new(),
+            track_times: Vec::new(),
+            data: bytes[0x80..].to_vec(),
+        })
+    }
+
+    /// NSFe is a chunk based format, each chunk being a 32 bit length, a 4 character id
+    /// and the data. Chunks with an uppercase first letter are required to be understood.
+    fn parse_nsfe(bytes: &[u8]) -> Result<Self, NsfError> {
+        let mut file = Self {
+            play_speed: 16639,
+            pal_play_speed: 19997,
+            ..Default::default()
+        };
+        let mut info = false;
+        let mut position = 4;
+        loop {
+            let header = bytes
+                .get(position..position + 8)
+                .ok_or(NsfError::InvalidFile)?;
+            let length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let id = &header[4..8];
+            let chunk = bytes
+                .get(position + 8..position + 8 + length)
+                .ok_or(NsfError::InvalidFile)?;
+            position += 8 + length;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 9 {
+                        return Err(NsfError::InvalidFile);
+                    }
+                    info = true;
+                    file.load_addr = u16::from_le_bytes([chunk[0], chunk[1]]);
+                    file.init_addr = u16::from_le_bytes([chunk[2], chunk[3]]);
+                    file.play_addr = u16::from_le_bytes([chunk[4], chunk[5]]);
+                    file.pal = chunk[6] & 0x03 == 0x01;
+                    file.extra_chips = chunk[7];
+                    file.total_songs = chunk.get(8).copied().unwrap_or(1);
+                    file.starting_song = chunk.get(9).copied().unwrap_or(0);
+                }
+                b"DATA" => file.data = chunk.to_vec(),
+                b"BANK" => {
+                    for (bank, data) in file.banks.iter_mut().zip(chunk) {
+                        *bank = *data;
+                    }
+                }
+                b"RATE" if chunk.len() >= 2 => {
+                    file.play_speed = u16::from_le_bytes([chunk[0], chunk[1]]);
+                    if let [_, _, lo, hi, ..] = chunk {
+                        file.pal_play_speed = u16::from_le_bytes([*lo, *hi]);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = chunk.split(|byte| *byte == 0x00).map(string);
+                    file.title = strings.next().unwrap_or_default();
+                    file.artist = strings.next().unwrap_or_default();
+                    file.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    file.track_titles = chunk
+                        .split(|byte| *byte == 0x00)
+                        .map(string)
+                        .take(file.total_songs as usize)
+                        .collect();
+                }
+                b"time" => {
+                    file.track_times = chunk
+                        .chunks_exact(4)
+                        .map(|time| i32::from_le_bytes(time.try_into().unwrap()))
+                        .collect();
+                }
+                b"NEND" => break,
+                [b'A'..=b'Z', ..] => return Err(NsfError::UnsupportedChunk),
+                _ => {}
+            }
+        }
+        if !info || file.data.is_empty() {
+            return Err(NsfError::InvalidFile);
+        }
+        Ok(file)
+    }
+
+    /// NSF banks are only used when one of the initial bank values is not zero
+    pub fn bankswitched(&self) -> bool {
+        self.banks.iter().any(|bank| *bank != 0)
+    }
+
+    pub fn track_title(&self, track: u8) -> String {
+        self.track_titles
+            .get(track as usize)
+            .filter(|title| !title.is_empty())
+            .cloned()
+            .unwrap_or_else(|| format!("Track {}", track + 1))
+    }
+}
+
+/// null terminated string
+fn string(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0x00)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
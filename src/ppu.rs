@@ -1,27 +1,33 @@
 use bevy::{ecs::query::QueryData, prelude::*};
-use bevy_egui::{
-    egui::{self, ScrollArea},
-    EguiContexts,
-};
+use bevy_egui::{egui, EguiContexts};
 use bitfield::bitfield;
+pub use nametable_buffer::{
+    draw_nametable_buffer, init_nametable_buffer, nametable_gui, update_nametable_buffer,
+};
 pub use pattern_buffer::{
     draw_pattern_buffer, init_pattern_buffer, pattern_gui, update_pattern_buffer,
 };
 use screen_buffer::ScreenBufferPlugin;
-
-use crate::{
-    cartridge::{Cartridge, Mirroring},
-    mem::Mem,
+pub use sprite_buffer::{
+    draw_sprite_buffer, init_sprite_buffer, sprite_gui, sprite_overlay, update_sprite_buffer,
 };
 
+use crate::{cartridge::NametableSource, mem::Mem, region::Region, slot::SlotQuery, vs::VsPpu};
+
 use oam::{Oam, OamEntry};
 
+/// about 600 ms, the time the PPU data bus takes to lose its value
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+mod nametable_buffer;
+mod ntsc;
 mod oam;
 mod palette;
 mod pattern_buffer;
 mod screen_buffer;
+mod sprite_buffer;
 
-pub use palette::PalettePlugin;
+pub use palette::{palette_gui, PalettePlugin};
 
 #[derive(Debug)]
 pub struct PpuRegisters {
@@ -106,36 +112,51 @@ pub struct ScanlineSprites {
     length: u8,
 }
 
-impl ScanlineSprites {
-    fn clear(&mut self) {
-        self.length = 0;
-        self.sprites.fill(OamEntry(0xFFFFFFFF));
-    }
-
-    fn add_sprite(&mut self, sprite: OamEntry) {
-        if self.length < 8 {
-            let old_sprite = unsafe { self.sprites.get_unchecked_mut(self.length as usize) };
-            *old_sprite = sprite;
-        }
-        self.length += 1;
-    }
+/// Progress of the sprite evaluation of dots 65 to 256, OAM is read on odd dots and the
+/// secondary OAM written on even ones.
+#[derive(Default)]
+struct SpriteEvaluation {
+    /// OAM entry being checked
+    n: u8,
+    /// byte of the entry being copied
+    m: u8,
+    /// next byte of the secondary OAM to write
+    secondary_addr: u8,
+    latch: u8,
+    done: bool,
+    sprite_zero: bool,
 }
 
 #[derive(Component)]
 pub struct Ppu {
-    pub screen_buffer: [[u8; 256]; 240],
-    temp_screen_buffer: [[u8; 256]; 240],
+    /// palette indices with the emphasis bits of PPUMASK on bits 6 to 8
+    pub screen_buffer: Box<[[u16; 256]; 240]>,
+    temp_screen_buffer: Box<[[u16; 256]; 240]>,
+    /// color subcarrier phase of the first pixel of `screen_buffer`, in twelfths of a cycle
+    pub screen_phase: u8,
+    temp_screen_phase: u8,
+    /// subcarrier phase of the current dot, 8 twelfths further on each dot
+    subcarrier_phase: u8,
     registers: PpuRegisters,
     name_table: [Mem<0x400>; 2],
     palette_table: [u8; 0x20],
     oam: Oam,
     scanline_sprites: ScanlineSprites,
+    secondary_oam: [u8; 0x20],
+    sprite_eval: SpriteEvaluation,
     cycle: i16,
     scanline: i16,
     frame_complete: bool,
     data_buffer: u8,
     addr_latch: bool,
     nmi: bool,
+    /// value left on the PPU data bus, returned by write-only registers
+    io_latch: u8,
+    /// frames left before each bit of the I/O latch decays to 0
+    io_latch_decay: [u8; 8],
+    /// set by a $2002 read on the dot before vblank starts
+    suppress_vblank: bool,
+    odd_frame: bool,
     vram_addr: LoopyRegister,
     tram_addr: LoopyRegister,
     fine_x: u8,
@@ -151,24 +172,36 @@ pub struct Ppu {
     bg_shifter_attrib_hi: u16,
     sprite_shifter_pattern_lo: [u8; 8],
     sprite_shifter_pattern_hi: [u8; 8],
+    pub region: Region,
+    /// PPU of a Vs. System board, which changes the palette and the register layout
+    pub vs_ppu: Option<VsPpu>,
 }
 
 impl Default for Ppu {
     fn default() -> Self {
         Self {
-            screen_buffer: [[0; 256]; 240],
-            temp_screen_buffer: [[0; 256]; 240],
+            screen_buffer: Box::new([[0; 256]; 240]),
+            temp_screen_buffer: Box::new([[0; 256]; 240]),
+            screen_phase: 0,
+            temp_screen_phase: 0,
+            subcarrier_phase: 0,
             registers: PpuRegisters::default(),
             name_table: [Mem::<0x400>::default(), Mem::<0x400>::default()],
             palette_table: [0; 0x20],
             oam: Oam::default(),
             scanline_sprites: ScanlineSprites::default(),
+            secondary_oam: [0xFF; 0x20],
+            sprite_eval: SpriteEvaluation::default(),
             cycle: 0,
             scanline: 0,
             frame_complete: false,
             data_buffer: 0x00,
             addr_latch: false,
             nmi: false,
+            io_latch: 0x00,
+            io_latch_decay: [0; 8],
+            suppress_vblank: false,
+            odd_frame: false,
             vram_addr: LoopyRegister::default(),
             tram_addr: LoopyRegister::default(),
             fine_x: 0,
@@ -184,6 +217,8 @@ impl Default for Ppu {
             bg_shifter_attrib_hi: 0x0000,
             sprite_shifter_pattern_lo: [0x00; 8],
             sprite_shifter_pattern_hi: [0x00; 8],
+            region: Region::default(),
+            vs_ppu: None,
         }
     }
 }
@@ -191,6 +226,21 @@ impl Default for Ppu {
 impl Ppu {
     pub fn swap_screen_buffer(&mut self) {
         std::mem::swap(&mut self.screen_buffer, &mut self.temp_screen_buffer);
+        self.screen_phase = self.temp_screen_phase;
+    }
+
+    /// reads the console VRAM, `None` when the nametable is located in the cartridge slot
+    fn nametable_read(&self, source: NametableSource, addr: u16) -> Option<u8> {
+        match source {
+            NametableSource::Ciram(page) => Some(self.name_table[page].read(addr)),
+            _ => None,
+        }
+    }
+
+    fn nametable_write(&mut self, source: NametableSource, addr: u16, data: u8) {
+        if let NametableSource::Ciram(page) = source {
+            self.name_table[page].write(addr, data);
+        }
     }
 
     fn increment_scroll_x(&mut self) {
@@ -273,13 +323,152 @@ impl Ppu {
             }
         }
     }
+
+    /// Sets the bits of `mask` of the I/O latch to `data`, which refreshes their decay.
+    fn drive_io_latch(&mut self, data: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (data & mask);
+        for (bit, decay) in self.io_latch_decay.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *decay = IO_LATCH_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_io_latch(&mut self) {
+        for (bit, decay) in self.io_latch_decay.iter_mut().enumerate() {
+            if *decay > 0 {
+                *decay -= 1;
+                if *decay == 0 {
+                    self.io_latch &= !(1 << bit);
+                }
+            }
+        }
+    }
+
+    /// $2004 reads, which see the sprite evaluation and fetches during rendering
+    fn oam_data(&self) -> u8 {
+        if self.rendering_enabled() && self.scanline < 240 {
+            match self.cycle {
+                1..=64 => 0xFF,
+                65..=256 => self.sprite_eval.latch,
+                257..=320 => {
+                    let step = (self.cycle - 257) as usize;
+                    self.secondary_oam[step / 8 * 4 + (step % 8).min(3)]
+                }
+                _ => self.secondary_oam[0],
+            }
+        } else {
+            let data = self.oam.read_byte(self.registers.oam_addr);
+            // bits 2 to 4 of the attribute byte do not exist
+            if self.registers.oam_addr & 0x03 == 0x02 {
+                data & 0xE3
+            } else {
+                data
+            }
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.registers.mask.render_background() || self.registers.mask.render_sprites()
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.registers.ctrl.sprite_size() {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// whether a sprite at `y` is on the next scanline
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let diff = self.scanline - y as i16;
+        diff >= 0 && diff < self.sprite_height()
+    }
+
+    /// Clears the secondary OAM on dots 1 to 64 then fills it with the first 8 sprites
+    /// of the next scanline.
+    fn evaluate_sprites(&mut self) {
+        match self.cycle {
+            1..=64 => {
+                if self.cycle == 1 {
+                    self.sprite_eval = SpriteEvaluation::default();
+                }
+                if self.cycle % 2 == 0 {
+                    self.secondary_oam[(self.cycle / 2 - 1) as usize] = 0xFF;
+                }
+            }
+            65..=256 if self.cycle % 2 == 1 => {
+                let eval = &mut self.sprite_eval;
+                eval.latch = self
+                    .oam
+                    .read_byte(eval.n.wrapping_mul(4).wrapping_add(eval.m));
+            }
+            65..=256 => self.sprite_evaluation_step(),
+            _ => {}
+        }
+    }
+
+    fn sprite_evaluation_step(&mut self) {
+        if self.sprite_eval.done {
+            return;
+        }
+        let in_range = self.sprite_in_range(self.sprite_eval.latch);
+        let eval = &mut self.sprite_eval;
+        if eval.secondary_addr < 0x20 {
+            self.secondary_oam[eval.secondary_addr as usize] = eval.latch;
+            if eval.m == 0 && !in_range {
+                eval.n += 1;
+            } else {
+                if eval.n == 0 {
+                    eval.sprite_zero = true;
+                }
+                eval.secondary_addr += 1;
+                eval.m = (eval.m + 1) & 0x03;
+                if eval.m == 0 {
+                    eval.n += 1;
+                }
+            }
+        } else if in_range {
+            self.registers.status.set_sprite_overflow(true);
+            eval.done = true;
+        } else {
+            // hardware bug: m is incremented along with n, so the tile, attribute and x
+            // bytes of the next entries get checked as Y coordinates
+            eval.n += 1;
+            eval.m = (eval.m + 1) & 0x03;
+        }
+        if eval.n == 64 {
+            eval.done = true;
+        }
+    }
+
+    fn sprite_pattern_addr(&self, sprite: OamEntry) -> u16 {
+        let height = self.sprite_height() as u16;
+        let mut row = (self.scanline as u16).wrapping_sub(sprite.y() as u16) & (height - 1);
+        if sprite.attribute() & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+        self.sprite_row_addr(sprite.tile_id() as u8, row)
+    }
+
+    /// address of the low plane of a sprite pattern row, rows 8 to 15 being the bottom
+    /// tile of 8x16 sprites
+    fn sprite_row_addr(&self, tile: u8, row: u16) -> u16 {
+        let tile = tile as u16;
+        if self.registers.ctrl.sprite_size() {
+            ((tile & 0x01) << 12) | (((tile & 0xFE) + (row >> 3)) << 4) | (row & 0x07)
+        } else {
+            ((self.registers.ctrl.pattern_sprite() as u16) << 12) | (tile << 4) | row
+        }
+    }
 }
 
 #[derive(QueryData)]
 #[query_data(mutable)]
 pub struct PpuQuery {
     ppu: &'static mut Ppu,
-    cartridge: Option<&'static mut Cartridge>,
+    pub slot: SlotQuery,
 }
 
 impl<'w> PpuQueryItem<'w> {
@@ -308,9 +497,6 @@ impl<'w> PpuQueryItem<'w> {
 
     pub fn tick(&mut self) {
         if self.ppu.scanline >= -1 && self.ppu.scanline < 240 {
-            if self.ppu.scanline == 0 && self.ppu.cycle == 0 {
-                self.ppu.cycle = 1;
-            }
             if self.ppu.scanline == -1 && self.ppu.cycle == 1 {
                 self.ppu.registers.status.set_vblank(false);
                 self.ppu.registers.status.set_sprite_zero_hit(false);
@@ -378,100 +564,35 @@ impl<'w> PpuQueryItem<'w> {
             if self.ppu.scanline == -1 && self.ppu.cycle >= 280 && self.ppu.cycle < 305 {
                 self.ppu.transfer_addr_y();
             }
-            if self.ppu.cycle == 257 && self.ppu.scanline >= 0 {
-                self.ppu.scanline_sprites.clear();
-                self.ppu.sprite_zero_possible = false;
-                for entry_id in 0..64 {
-                    if self.ppu.scanline_sprites.length == 8
-                        && self.ppu.registers.status.sprite_overflow()
-                    {
-                        break;
-                    }
-                    let entry = self.ppu.oam.get_entry(entry_id);
-                    let diff = self.ppu.scanline - (entry.y() as i16);
-                    let sprite_size = match self.ppu.registers.ctrl.sprite_size() {
-                        true => 16,
-                        false => 8,
-                    };
-                    if (diff >= 0) && diff < sprite_size {
-                        if entry_id == 0 {
-                            self.ppu.sprite_zero_possible = true;
-                        }
-                        if self.ppu.scanline_sprites.length < 8 {
-                            self.ppu.scanline_sprites.add_sprite(entry);
-                        } else {
-                            self.ppu.registers.status.set_sprite_overflow(true);
-                        }
-                    }
-                }
+            let rendering = self.ppu.rendering_enabled();
+            // the pre-render line fetches sprites without evaluating any
+            if rendering && self.ppu.scanline >= 0 {
+                self.ppu.evaluate_sprites();
             }
-            if self.ppu.cycle == 340 {
-                for i in 0..self.ppu.scanline_sprites.length {
-                    let sprite = self.ppu.scanline_sprites.sprites[i as usize];
-                    let sprite_pattern_addr_lo = if !self.ppu.registers.ctrl.sprite_size() {
-                        if sprite.attribute() & 0x80 == 0x00 {
-                            ((self.ppu.registers.ctrl.pattern_sprite() as u16) << 12)
-                                | ((sprite.tile_id() as u16) << 4)
-                                | ((self.ppu.scanline as u16) - (sprite.y() as u16))
-                        } else {
-                            ((self.ppu.registers.ctrl.pattern_sprite() as u16) << 12)
-                                | ((sprite.tile_id() as u16) << 4)
-                                | (7 - ((self.ppu.scanline as u16) - (sprite.y() as u16)))
-                        }
-                    } else {
-                        if sprite.attribute() & 0x80 == 0x00 {
-                            if (self.ppu.scanline as u16) - (sprite.y() as u16) < 8 {
-                                (((sprite.tile_id() as u16) & 0x01) << 12)
-                                    | (((sprite.tile_id() as u16) & 0xFE) << 4)
-                                    | (((self.ppu.scanline as u16) - (sprite.y() as u16)) & 0x07)
-                            } else {
-                                (((sprite.tile_id() as u16) & 0x01) << 12)
-                                    | ((((sprite.tile_id() as u16) & 0xFE) + 1) << 4)
-                                    | (((self.ppu.scanline as u16) - (sprite.y() as u16)) & 0x07)
-                            }
-                        } else {
-                            if (self.ppu.scanline as u16) - (sprite.y() as u16) < 8 {
-                                (((sprite.tile_id() as u16) & 0x01) << 12)
-                                    | ((((sprite.tile_id() as u16) & 0xFE) + 1) << 4)
-                                    | (7 - (((self.ppu.scanline as u16) - (sprite.y() as u16))
-                                        & 0x07))
-                            } else {
-                                (((sprite.tile_id() as u16) & 0x01) << 12)
-                                    | (((sprite.tile_id() as u16) & 0xFE) << 4)
-                                    | (7 - (((self.ppu.scanline as u16) - (sprite.y() as u16))
-                                        & 0x07))
-                            }
-                        }
-                    };
-                    let sprite_pattern_addr_hi = sprite_pattern_addr_lo.wrapping_add(8);
-                    let (sprite_pattern_bits_lo, sprite_pattern_bits_hi) =
-                        if sprite.attribute() & 0x40 != 0 {
-                            (
-                                self.ppu_read(sprite_pattern_addr_lo).reverse_bits(),
-                                self.ppu_read(sprite_pattern_addr_hi).reverse_bits(),
-                            )
-                        } else {
-                            (
-                                self.ppu_read(sprite_pattern_addr_lo),
-                                self.ppu_read(sprite_pattern_addr_hi),
-                            )
-                        };
-                    self.ppu.sprite_shifter_pattern_lo[i as usize] = sprite_pattern_bits_lo;
-                    self.ppu.sprite_shifter_pattern_hi[i as usize] = sprite_pattern_bits_hi;
-                }
+            if rendering && self.ppu.cycle >= 257 && self.ppu.cycle <= 320 {
+                self.fetch_sprites();
             }
         }
 
         if self.ppu.scanline == 240 {}
 
-        if self.ppu.scanline == 241 && self.ppu.cycle == 1 {
-            self.ppu.registers.status.set_vblank(true);
-            if self.ppu.registers.ctrl.nmi() {
-                self.ppu.nmi = true;
+        if self.ppu.scanline == self.ppu.region.vblank_scanline() && self.ppu.cycle == 1 {
+            if !self.ppu.suppress_vblank {
+                self.ppu.registers.status.set_vblank(true);
+                if self.ppu.registers.ctrl.nmi() {
+                    self.ppu.nmi = true;
+                }
             }
+            self.ppu.suppress_vblank = false;
         }
 
-        let (bg_pixel, bg_palette) = if self.ppu.registers.mask.render_background() {
+        // the leftmost 8 pixels of each layer can be hidden by PPUMASK
+        let x = self.ppu.cycle - 1;
+        let mask = &self.ppu.registers.mask;
+        let show_background = mask.render_background() && (x >= 8 || mask.render_background_left());
+        let show_sprites = mask.render_sprites() && (x >= 8 || mask.render_sprite_left());
+
+        let (bg_pixel, bg_palette) = if show_background {
             let bit_mux = 0x8000 >> self.ppu.fine_x;
 
             let px0 = ((self.ppu.bg_shifter_pattern_lo & bit_mux) > 0) as u8;
@@ -486,8 +607,8 @@ impl<'w> PpuQueryItem<'w> {
             (0x00, 0x00)
         };
 
-        let (fg_pixel, fg_palette, fg_priority) = if self.ppu.registers.mask.render_sprites() {
-            self.ppu.sprite_zero_rendering = false;
+        self.ppu.sprite_zero_rendering = false;
+        let (fg_pixel, fg_palette, fg_priority) = if show_sprites {
             let v = (0..self.ppu.scanline_sprites.length)
                 .into_iter()
                 .map(|i| {
@@ -528,45 +649,124 @@ impl<'w> PpuQueryItem<'w> {
             (bg_pixel, _, false) => (bg_pixel, bg_palette),
         };
 
+        // no hit on the last pixel of the line, nor when either pixel is transparent or
+        // clipped on the left column
         if self.ppu.sprite_zero_rendering
             && self.ppu.sprite_zero_possible
-            && self.ppu.registers.mask.render_background()
-            && self.ppu.registers.mask.render_sprites()
+            && bg_pixel != 0x00
+            && self.ppu.scanline >= 0
+            && self.ppu.scanline < 240
+            && (0..255).contains(&x)
         {
-            if !(self.ppu.registers.mask.render_background_left()
-                || self.ppu.registers.mask.render_sprite_left())
-            {
-                if self.ppu.cycle >= 9 && self.ppu.cycle < 258 {
-                    self.ppu.registers.status.set_sprite_zero_hit(true);
-                }
-            } else {
-                if self.ppu.cycle >= 1 && self.ppu.cycle < 258 {
-                    self.ppu.registers.status.set_sprite_zero_hit(true);
-                }
-            }
+            self.ppu.registers.status.set_sprite_zero_hit(true);
         }
 
         let color = self.get_color_from_ram(palette, pixel);
         self.set_pixel(self.ppu.cycle.wrapping_sub(1), self.ppu.scanline, color);
+        if self.ppu.scanline == 0 && self.ppu.cycle == 1 {
+            self.ppu.temp_screen_phase = self.ppu.subcarrier_phase;
+        }
 
         self.ppu.cycle = self.ppu.cycle.wrapping_add(1);
+        // each dot lasts 8 of the 12 subcarrier phases, so lines start 4 phases apart
+        self.ppu.subcarrier_phase = (self.ppu.subcarrier_phase + 8) % 12;
+        // the NTSC PPU skips the last dot of the pre-render line on odd frames
+        if self.ppu.scanline == -1
+            && self.ppu.cycle == 340
+            && self.ppu.odd_frame
+            && self.ppu.rendering_enabled()
+            && self.ppu.region == Region::Ntsc
+        {
+            self.ppu.cycle = 341;
+        }
         if self.ppu.cycle >= 341 {
             self.ppu.cycle = 0;
             self.ppu.scanline = self.ppu.scanline.wrapping_add(1);
-            if self.ppu.scanline >= 261 {
+            if self.ppu.scanline >= self.ppu.region.scanlines() - 1 {
                 self.ppu.scanline = -1;
                 self.ppu.frame_complete = true;
+                self.ppu.odd_frame = !self.ppu.odd_frame;
+                self.ppu.decay_io_latch();
                 self.ppu.swap_screen_buffer();
             }
         }
     }
 
-    fn get_color_from_ram(&mut self, palette: u8, pixel: u8) -> u8 {
+    /// Loads the 8 sprites of the next scanline over dots 257 to 320, each one taking two
+    /// garbage nametable reads and its two pattern reads. Empty slots fetch tile $FF.
+    fn fetch_sprites(&mut self) {
+        if self.ppu.cycle == 257 {
+            let (length, sprite_zero) = match self.ppu.scanline {
+                0.. => (
+                    self.ppu.sprite_eval.secondary_addr / 4,
+                    self.ppu.sprite_eval.sprite_zero,
+                ),
+                _ => (0, false),
+            };
+            self.ppu.scanline_sprites.length = length;
+            self.ppu.sprite_zero_possible = sprite_zero;
+        }
+        self.ppu.registers.oam_addr = 0x00;
+
+        let i = ((self.ppu.cycle - 257) / 8) as usize;
+        let sprite = self.ppu.secondary_oam[i * 4..i * 4 + 4]
+            .try_into()
+            .map(|bytes| OamEntry(u32::from_le_bytes(bytes)))
+            .unwrap();
+        let empty = i >= self.ppu.scanline_sprites.length as usize;
+        match (self.ppu.cycle - 257) % 8 {
+            0x00 => {
+                self.ppu.scanline_sprites.sprites[i] = sprite;
+                self.ppu_read(0x2000 | (self.ppu.vram_addr.0 & 0x0FFF));
+            }
+            0x02 => {
+                self.ppu_read(0x2000 | (self.ppu.vram_addr.0 & 0x0FFF));
+            }
+            0x04 | 0x06 => {
+                let high = self.ppu.cycle % 8 == 0x07;
+                let addr = self.ppu.sprite_pattern_addr(sprite) + if high { 8 } else { 0 };
+                let mut data = self.ppu_read(addr);
+                if sprite.attribute() & 0x40 != 0 {
+                    data = data.reverse_bits();
+                }
+                if empty {
+                    data = 0x00;
+                }
+                if high {
+                    self.ppu.sprite_shifter_pattern_hi[i] = data;
+                } else {
+                    self.ppu.sprite_shifter_pattern_lo[i] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn get_color_from_ram(&mut self, palette: u8, pixel: u8) -> u16 {
         let addr = 0x3F00 + ((palette as u16) << 2) + (pixel as u16);
-        self.ppu_read(addr)
+        let mut color = self.ppu_read(addr);
+        if self.ppu.registers.mask.greyscale() {
+            color &= 0x30;
+        }
+        let color = match self.ppu.vs_ppu {
+            Some(vs_ppu) => vs_ppu.color(color),
+            None => color,
+        };
+        color as u16 | self.emphasis() << 6
     }
 
-    fn set_pixel(&mut self, x: i16, y: i16, color: u8) {
+    /// red, green and blue emphasis bits, the PAL and Dendy PPUs have the red and green
+    /// bits of PPUMASK swapped
+    fn emphasis(&self) -> u16 {
+        let mask = &self.ppu.registers.mask;
+        let (red, green) = match self.ppu.region {
+            Region::Ntsc => (mask.emphasize_red(), mask.emphasize_green()),
+            Region::Pal | Region::Dendy => (mask.emphasize_green(), mask.emphasize_red()),
+        };
+        red as u16 | (green as u16) << 1 | (mask.emphasize_blue() as u16) << 2
+    }
+
+    fn set_pixel(&mut self, x: i16, y: i16, color: u16) {
         if let Some(row) = self.ppu.temp_screen_buffer.get_mut(y as usize) {
             if let Some(pixel) = row.get_mut(x as usize) {
                 *pixel = color;
@@ -576,11 +776,8 @@ impl<'w> PpuQueryItem<'w> {
 
     pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
         match addr {
-            0x2000..=0x3FFF => Some(self.ppu_register_read(addr).unwrap_or(self.ppu.data_buffer)),
-            0x4020..=0xFFFF => self
-                .cartridge
-                .as_mut()
-                .and_then(|cartridge| cartridge.cpu_read(addr)),
+            0x2000..=0x3FFF => Some(self.ppu_register_read(addr)),
+            0x4020..=0xFFFF => self.slot.cpu_read(addr),
             _ => None,
         }
     }
@@ -588,37 +785,56 @@ impl<'w> PpuQueryItem<'w> {
         match addr {
             0x2000..=0x3FFF => self.ppu_register_write(addr, data),
             0x4014 => {}
-            0x4020..=0xFFFF => {
-                if let Some(cartridge) = &mut self.cartridge {
-                    _ = cartridge.cpu_write(addr, data);
-                }
-            }
+            0x4020..=0xFFFF => self.slot.cpu_write(addr, data),
             _ => {}
         }
     }
 
-    fn ppu_register_read(&mut self, addr: u16) -> Option<u8> {
+    /// Reads a PPU register, the bits a register does not drive come from the I/O latch.
+    fn ppu_register_read(&mut self, addr: u16) -> u8 {
         let addr = addr & 0x07;
         match addr {
-            0x00 => None,
-            0x01 => None,
             0x02 => {
-                let data = (self.ppu.registers.status.0 & 0xE0) | (self.ppu.data_buffer & 0x1F);
+                if self.ppu.scanline == self.ppu.region.vblank_scanline() {
+                    match self.ppu.cycle {
+                        // one dot early, the flag reads clear and is not set this frame
+                        1 => self.ppu.suppress_vblank = true,
+                        // right after it is set, the flag reads set but the NMI is lost
+                        2 | 3 => self.ppu.nmi = false,
+                        _ => {}
+                    }
+                }
+                let low_bits = match self.ppu.vs_ppu.and_then(|vs_ppu| vs_ppu.status_id()) {
+                    Some(id) => id,
+                    None => self.ppu.io_latch & 0x1F,
+                };
+                let data = (self.ppu.registers.status.0 & 0xE0) | low_bits;
+                self.ppu.drive_io_latch(data, 0xE0);
                 self.ppu.registers.status.set_vblank(false);
                 self.ppu.addr_latch = false;
-                Some(data)
+                data
+            }
+            0x04 => {
+                let data = self.ppu.oam_data();
+                self.ppu.drive_io_latch(data, 0xFF);
+                data
             }
-            0x03 => None,
-            0x04 => None,
-            0x05 => None,
-            0x06 => None,
             0x07 => {
-                let data = if self.ppu.vram_addr.0 >= 0x3F00 {
-                    self.ppu.data_buffer = self.ppu_read(self.ppu.vram_addr.0);
-                    self.ppu.data_buffer
+                let addr = self.ppu.vram_addr.0 & 0x3FFF;
+                let data = if addr >= 0x3F00 {
+                    // palette RAM answers right away, while the buffer gets the
+                    // nametable byte found under it
+                    self.ppu.data_buffer = self.ppu_read(addr - 0x1000);
+                    let mut color = self.ppu_read(addr) & 0x3F;
+                    if self.ppu.registers.mask.greyscale() {
+                        color &= 0x30;
+                    }
+                    self.ppu.drive_io_latch(color, 0x3F);
+                    self.ppu.io_latch
                 } else {
                     let data = self.ppu.data_buffer;
-                    self.ppu.data_buffer = self.ppu_read(self.ppu.vram_addr.0);
+                    self.ppu.data_buffer = self.ppu_read(addr);
+                    self.ppu.drive_io_latch(data, 0xFF);
                     data
                 };
                 self.ppu.vram_addr.0 += if self.ppu.registers.ctrl.increment_mode() {
@@ -626,17 +842,36 @@ impl<'w> PpuQueryItem<'w> {
                 } else {
                     1
                 };
-                Some(data)
+                data
             }
-            _ => unreachable!(),
+            _ => self.ppu.io_latch,
         }
     }
 
     fn ppu_register_write(&mut self, addr: u16, data: u8) {
-        let addr = addr & 0x07;
+        let addr = match addr & 0x07 {
+            0x00 | 0x01
+                if self
+                    .ppu
+                    .vs_ppu
+                    .is_some_and(|vs_ppu| vs_ppu.swaps_ctrl_mask()) =>
+            {
+                addr & 0x07 ^ 0x01
+            }
+            addr => addr,
+        };
+        self.ppu.drive_io_latch(data, 0xFF);
         match addr {
             0x00 => {
+                let nmi_enabled = self.ppu.registers.ctrl.nmi();
                 self.ppu.registers.ctrl.0 = data;
+                // the NMI output follows the vblank flag while enabled, so enabling it
+                // during vblank raises one and disabling it cancels a pending one
+                match (nmi_enabled, self.ppu.registers.ctrl.nmi()) {
+                    (false, true) if self.ppu.registers.status.vblank() => self.ppu.nmi = true,
+                    (true, false) => self.ppu.nmi = false,
+                    _ => {}
+                }
                 let ntx = self.ppu.registers.ctrl.nametable_x() as u16;
                 self.ppu.tram_addr.set_nametable_x(ntx);
                 let nty = self.ppu.registers.ctrl.nametable_y() as u16;
@@ -645,7 +880,17 @@ impl<'w> PpuQueryItem<'w> {
             0x01 => self.ppu.registers.mask.0 = data,
             0x02 => {}
             0x03 => self.ppu.registers.oam_addr = data,
-            0x04 => self.ppu.registers.oam_data = data,
+            0x04 => {
+                self.ppu.registers.oam_data = data;
+                // writes during rendering only bump the sprite index
+                if self.ppu.rendering_enabled() && self.ppu.scanline < 240 {
+                    self.ppu.registers.oam_addr = self.ppu.registers.oam_addr.wrapping_add(4);
+                } else {
+                    let oam_addr = self.ppu.registers.oam_addr;
+                    self.ppu.oam.write_byte(oam_addr, data);
+                    self.ppu.registers.oam_addr = self.ppu.registers.oam_addr.wrapping_add(1);
+                }
+            }
             0x05 => {
                 if !self.ppu.addr_latch {
                     self.ppu.fine_x = data & 0x07;
@@ -681,37 +926,14 @@ impl<'w> PpuQueryItem<'w> {
 
     pub fn ppu_read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => self
-                .cartridge
-                .as_ref()
-                .and_then(|cartridge| cartridge.ppu_read(addr))
-                .unwrap_or(0),
-            0x2000..=0x2FFF => {
-                match self
-                    .cartridge
-                    .as_ref()
-                    .and_then(|mapper| mapper.ppu_read(addr))
-                {
-                    Some(data) => data,
-                    None => match self
-                        .cartridge
-                        .as_ref()
-                        .map(|cartridge| cartridge.mirroring())
-                    {
-                        Some(Mirroring::Vertical) => {
-                            let bank_id = ((addr & 0x0400) >> 10) as usize;
-                            self.ppu.name_table[bank_id].read(addr)
-                        }
-                        Some(Mirroring::Horizontal) | None => {
-                            let bank_id = ((addr & 0x0800) >> 11) as usize;
-                            self.ppu.name_table[bank_id].read(addr)
-                        }
-
-                        Some(Mirroring::OneScreenLo) => self.ppu.name_table[0].read(addr),
-                        Some(Mirroring::OneScreenHi) => self.ppu.name_table[1].read(addr),
-                    },
-                }
-            }
+            0x0000..=0x1FFF => self.slot.ppu_read(addr).unwrap_or(0),
+            0x2000..=0x2FFF => self.slot.ppu_read(addr).unwrap_or_else(|| {
+                let source = self.slot.nametable(addr);
+                self.ppu
+                    .nametable_read(source, addr)
+                    .or_else(|| self.slot.nametable_read(source, addr))
+                    .unwrap_or(0)
+            }),
             0x3F00..=0x3FFF => {
                 let addr = addr & 0x1F;
                 let addr = match addr {
@@ -730,34 +952,15 @@ impl<'w> PpuQueryItem<'w> {
     pub fn ppu_write(&mut self, addr: u16, data: u8) {
         match addr {
             0x0000..=0x1FFF => {
-                if let Some(cartridge) = &mut self.cartridge {
-                    let _ = cartridge.ppu_write(addr, data);
-                }
+                let _ = self.slot.ppu_write(addr, data);
             }
             0x2000..=0x2FFF => {
-                if let Some(cartridge) = &mut self.cartridge {
-                    if !cartridge.ppu_write(addr, data) {
-                        match self
-                            .cartridge
-                            .as_ref()
-                            .map(|cartridge| cartridge.mirroring())
-                        {
-                            Some(Mirroring::Vertical) => {
-                                let bank_id = ((addr & 0x0400) >> 10) as usize;
-                                self.ppu.name_table[bank_id].write(addr, data)
-                            }
-                            Some(Mirroring::Horizontal) | None => {
-                                let bank_id = ((addr & 0x0800) >> 11) as usize;
-                                self.ppu.name_table[bank_id].write(addr, data)
-                            }
-                            Some(Mirroring::OneScreenLo) => {
-                                self.ppu.name_table[0].write(addr, data)
-                            }
-                            Some(Mirroring::OneScreenHi) => {
-                                self.ppu.name_table[1].write(addr, data)
-                            }
-                        }
-                    }
+                if self.slot.ppu_write(addr, data) {
+                    return;
+                }
+                let source = self.slot.nametable(addr);
+                if !self.slot.nametable_write(source, addr, data) {
+                    self.ppu.nametable_write(source, addr, data);
                 }
             }
             0x3F00..=0x3FFF => {
@@ -784,30 +987,21 @@ impl<'w> PpuQueryReadOnlyItem<'w> {
     pub fn cpu_read(&self, addr: u16) -> u8 {
         match addr {
             0x2000..=0x3FFF => self.ppu_register_read(addr),
-            0x4020..=0xFFFF => self
-                .cartridge
-                .as_ref()
-                .and_then(|cartridge| cartridge.cpu_read(addr))
-                .unwrap_or(0),
+            0x4020..=0xFFFF => self.slot.cpu_read(addr).unwrap_or(0),
             _ => 0,
         }
     }
 
     pub fn ppu_read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => self
-                .cartridge
-                .as_ref()
-                .and_then(|cartridge| cartridge.ppu_read(addr))
-                .unwrap_or(0),
-            0x2000..=0x2FFF => self
-                .cartridge
-                .as_ref()
-                .and_then(|mapper| mapper.ppu_read(addr))
-                .unwrap_or_else(|| {
-                    let bank_nbr = (addr & 0x0800 >> 11) as usize;
-                    self.ppu.name_table[bank_nbr].read(addr)
-                }),
+            0x0000..=0x1FFF => self.slot.ppu_read(addr).unwrap_or(0),
+            0x2000..=0x2FFF => self.slot.ppu_read(addr).unwrap_or_else(|| {
+                let source = self.slot.nametable(addr);
+                self.ppu
+                    .nametable_read(source, addr)
+                    .or_else(|| self.slot.nametable_read(source, addr))
+                    .unwrap_or(0)
+            }),
             0x3F00..=0x3FFF => {
                 let addr = addr & 0x1F;
                 let addr = match addr {
@@ -919,35 +1113,14 @@ pub fn ppu_gui(query: Query<PpuQuery>, mut contexts: EguiContexts) {
     });
 }
 
-pub fn oam_gui(query: Query<PpuQuery>, mut contexts: EguiContexts) {
-    egui::Window::new("OAM Memory").show(&contexts.ctx_mut(), |ui| {
-        if let Ok(query) = query.get_single() {
-            let text_style = egui::TextStyle::Monospace;
-            let row_height = ui.text_style_height(&text_style);
-            let total_rows = 64;
-            ui.push_id("wram", |ui| {
-                ScrollArea::vertical()
-                    .auto_shrink(false)
-                    .max_height(200.0)
-                    .show_rows(ui, row_height, total_rows, |ui, row_range| {
-                        for row in row_range {
-                            ui.monospace(format!("{}", query.ppu.oam.get_entry(row as u8)));
-                        }
-                    });
-            });
-        } else {
-            ui.label("No PPU found");
-        }
-    });
-}
-
 #[cfg(test)]
 mod tests {
-    use super::LoopyRegister;
+    use super::{LoopyRegister, IO_LATCH_DECAY_FRAMES};
     use crate::{
-        cartridge::{CartridgeHeader, Mirroring},
+        cartridge::{Cartridge, CartridgeHeader, Mapper, Mirroring, NametableSource},
         nes::NesBundle,
-        ppu::{Cartridge, PpuQuery},
+        ppu::{PpuQuery, PpuQueryItem},
+        region::Region,
     };
     use bevy::prelude::*;
 
@@ -1047,4 +1220,450 @@ mod tests {
         assert_eq!(query.ppu_read(0x27FF), 0x04);
         assert_eq!(query.ppu_read(0x2FFF), 0x04);
     }
+
+    #[test]
+    fn four_screen_nametables() {
+        setup!(query, Mirroring::FourScreen);
+
+        query.ppu_write(0x2000, 0x01);
+        query.ppu_write(0x2400, 0x02);
+        query.ppu_write(0x2800, 0x03);
+        query.ppu_write(0x2C00, 0x04);
+        assert_eq!(query.ppu_read(0x2000), 0x01);
+        assert_eq!(query.ppu_read(0x2400), 0x02);
+        assert_eq!(query.ppu_read(0x2800), 0x03);
+        assert_eq!(query.ppu_read(0x2C00), 0x04);
+
+        query.ppu_write(0x2BFF, 0x05);
+        assert_eq!(query.ppu_read(0x23FF), 0x00);
+        assert_eq!(query.ppu_read(0x27FF), 0x00);
+        assert_eq!(query.ppu_read(0x2BFF), 0x05);
+        assert_eq!(query.ppu_read(0x2FFF), 0x00);
+    }
+
+    /// Maps slot 0 to cartridge VRAM page 2, slot 1 to CHR-ROM page 1 and slot 3 to
+    /// CIRAM page 0, leaving slot 2 to the four screen layout.
+    struct NametableMapper;
+
+    impl Mapper for NametableMapper {
+        fn cpu_map_read(&self, _addr: u16) -> Option<u8> {
+            None
+        }
+        fn cpu_map_write(&mut self, _addr: u16, _data: u8) -> bool {
+            false
+        }
+        fn ppu_map_read(&self, _addr: u16) -> Option<u8> {
+            None
+        }
+        fn ppu_map_write(&mut self, _addr: u16, _data: u8) -> bool {
+            false
+        }
+        fn mirroring(&self) -> Option<Mirroring> {
+            None
+        }
+        fn ui(&self, _ui: &mut bevy_egui::egui::Ui) {}
+
+        fn nametable(&self, addr: u16) -> Option<NametableSource> {
+            match (addr >> 10) & 0x03 {
+                0 => Some(NametableSource::CartridgeRam(2)),
+                1 => Some(NametableSource::ChrRom(1)),
+                3 => Some(NametableSource::Ciram(0)),
+                _ => None,
+            }
+        }
+
+        fn chr_rom_read(&self, page: usize, addr: u16) -> Option<u8> {
+            Some(0xA0 | page as u8 | ((addr & 0x03FF == 0x03FF) as u8 * 0x08))
+        }
+    }
+
+    #[test]
+    fn cartridge_nametables() {
+        let mut app = App::new();
+        let cart = Cartridge::testing_with_mapper(
+            CartridgeHeader::with_mirroring(Mirroring::FourScreen),
+            Box::new(NametableMapper),
+        );
+        app.world_mut().spawn((NesBundle::default(), cart));
+        let mut query = app.world_mut().query::<PpuQuery>();
+        let mut query = query.single_mut(app.world_mut());
+
+        // slots 0 and 2 share the third page of cartridge VRAM
+        query.ppu_write(0x2010, 0x11);
+        assert_eq!(query.ppu_read(0x2010), 0x11);
+        assert_eq!(query.ppu_read(0x2810), 0x11);
+        query.ppu_write(0x2820, 0x22);
+        assert_eq!(query.ppu_read(0x2020), 0x22);
+
+        // CHR-ROM pages are read only, the write does not fall through to CIRAM
+        assert_eq!(query.ppu_read(0x2400), 0xA1);
+        assert_eq!(query.ppu_read(0x27FF), 0xA9);
+        query.ppu_write(0x2400, 0x33);
+        assert_eq!(query.ppu_read(0x2400), 0xA1);
+        assert_eq!(query.ppu_read(0x2C00), 0x00);
+
+        query.ppu_write(0x2C00, 0x44);
+        assert_eq!(query.ppu_read(0x2C00), 0x44);
+        assert_eq!(query.ppu_read(0x2000), 0x00);
+    }
+
+    /// Fills the background with an opaque tile and puts sprite 0 with the same tile
+    /// at (x, 11). Returns the scanline and dot of the first sprite 0 hit.
+    fn sprite_zero_hit(query: &mut PpuQueryItem, x: u8, mask: u8) -> Option<(i16, i16)> {
+        for row in 0..8 {
+            query.ppu_write(0x0010 + row, 0xFF);
+        }
+        for addr in 0x2000..0x23C0 {
+            query.ppu_write(addr, 0x01);
+        }
+        query.ppu_write(0x3F01, 0x30);
+        // sprites are drawn one line below their Y coordinate
+        for (i, data) in [10, 0x01, 0x00, x].into_iter().enumerate() {
+            query.oam_write(i as u8, data);
+        }
+        query.cpu_write(0x2001, mask);
+        run_frame_until(query, |query| query.ppu.registers.status.sprite_zero_hit())
+    }
+
+    /// Ticks for up to a frame and returns the scanline and dot of the tick after which
+    /// `condition` holds.
+    fn run_frame_until(
+        query: &mut PpuQueryItem,
+        condition: impl Fn(&PpuQueryItem) -> bool,
+    ) -> Option<(i16, i16)> {
+        for _ in 0..341 * 262 {
+            let dot = (query.ppu.scanline, query.ppu.cycle);
+            query.tick();
+            if condition(query) {
+                return Some(dot);
+            }
+        }
+        None
+    }
+
+    /// writes the 4 bytes of each sprite to OAM from entry 0 and enables rendering
+    fn sprite_overflow(query: &mut PpuQueryItem, sprites: &[[u8; 4]]) -> Option<(i16, i16)> {
+        for (i, data) in sprites.iter().flatten().enumerate() {
+            query.oam_write(i as u8, *data);
+        }
+        query.cpu_write(0x2001, 0x18);
+        run_frame_until(query, |query| query.ppu.registers.status.sprite_overflow())
+    }
+
+    #[test]
+    fn sprite_zero_hit_timing() {
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 20, 0x1E), Some((11, 21)));
+    }
+
+    #[test]
+    fn sprite_zero_hit_right_edge() {
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 250, 0x1E), Some((11, 251)));
+
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 255, 0x1E), None);
+    }
+
+    #[test]
+    fn sprite_zero_hit_left_clipping() {
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 4, 0x1E), Some((11, 5)));
+
+        // either layer clipped delays the hit to x = 8
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 4, 0x1C), Some((11, 9)));
+
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 4, 0x1A), Some((11, 9)));
+
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 0, 0x18), None);
+    }
+
+    #[test]
+    fn sprite_zero_no_hit() {
+        // background disabled
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 20, 0x14), None);
+
+        // sprites disabled
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(sprite_zero_hit(&mut query, 20, 0x0A), None);
+
+        // transparent background
+        setup!(query, Mirroring::Horizontal);
+        for addr in 0x0010..0x0018 {
+            query.ppu_write(addr + 0x1000, 0x00);
+        }
+        query.cpu_write(0x2000, 0x10);
+        assert_eq!(sprite_zero_hit(&mut query, 20, 0x1E), None);
+    }
+
+    #[test]
+    fn left_column_clipping() {
+        setup!(query, Mirroring::Horizontal);
+        query.ppu_write(0x3F00, 0x0F);
+        sprite_zero_hit(&mut query, 255, 0x18);
+        assert_eq!(query.ppu.screen_buffer[11][..8], [0x0F; 8]);
+        assert_eq!(query.ppu.screen_buffer[11][8], 0x30);
+
+        setup!(query, Mirroring::Horizontal);
+        query.ppu_write(0x3F00, 0x0F);
+        sprite_zero_hit(&mut query, 255, 0x1A);
+        assert_eq!(query.ppu.screen_buffer[11][..8], [0x30; 8]);
+    }
+
+    #[test]
+    fn sprite_overflow_timing() {
+        setup!(query, Mirroring::Horizontal);
+        // every other entry stays at y = 0
+        let mut sprites = [[200, 0x00, 0x00, 0x00]; 64];
+        sprites[..9].fill([10, 0x00, 0x00, 0x00]);
+        // 8 sprites copied in 8 dots each from dot 65, the ninth is read on dot 129
+        assert_eq!(sprite_overflow(&mut query, &sprites), Some((10, 130)));
+        assert_eq!(query.ppu.sprite_eval.secondary_addr, 0x20);
+    }
+
+    #[test]
+    fn sprite_overflow_bug() {
+        // the tile of entry 9 is checked as a Y coordinate once the secondary OAM is full
+        setup!(query, Mirroring::Horizontal);
+        let mut sprites = [[200, 0x00, 0x00, 0x00]; 64];
+        sprites[..8].fill([10, 0x00, 0x00, 0x00]);
+        sprites[9] = [200, 10, 0x00, 0x00];
+        assert_eq!(sprite_overflow(&mut query, &sprites), Some((10, 132)));
+
+        // and a ninth sprite in range is missed for the same reason
+        setup!(query, Mirroring::Horizontal);
+        let mut sprites = [[0xF0; 4]; 64];
+        sprites[..8].fill([10, 0x00, 0x00, 0x00]);
+        sprites[9] = [10, 0xF0, 0xF0, 0xF0];
+        assert_eq!(sprite_overflow(&mut query, &sprites), None);
+    }
+
+    #[test]
+    fn sprite_fetches() {
+        setup!(query, Mirroring::Horizontal);
+        for row in 0..8 {
+            query.ppu_write(0x0020 + row, 0x81);
+        }
+        for (i, data) in [10, 0x02, 0x40, 0x30, 10, 0x02, 0x00, 0x40]
+            .into_iter()
+            .enumerate()
+        {
+            query.oam_write(i as u8, data);
+        }
+        query.cpu_write(0x2001, 0x18);
+        run_frame_until(&mut query, |query| {
+            query.ppu.scanline == 10 && query.ppu.cycle == 321
+        });
+        assert_eq!(query.ppu.scanline_sprites.length, 2);
+        assert!(query.ppu.sprite_zero_possible);
+        assert_eq!(query.ppu.scanline_sprites.sprites[1].x(), 0x40);
+        assert_eq!(query.ppu.sprite_shifter_pattern_lo[..3], [0x81, 0x81, 0x00]);
+        assert_eq!(query.ppu.registers.oam_addr, 0x00);
+    }
+
+    fn run_until_dot(query: &mut PpuQueryItem, scanline: i16, cycle: i16) {
+        run_frame_until(query, |query| {
+            query.ppu.scanline == scanline && query.ppu.cycle == cycle
+        })
+        .expect("the dot should be reached within a frame");
+    }
+
+    /// PPU ticks of the two frames following the next frame completion
+    fn frame_lengths(query: &mut PpuQueryItem) -> [usize; 2] {
+        while !query.frame_complete() {
+            query.tick();
+        }
+        [0; 2].map(|_| {
+            let mut ticks = 1;
+            query.tick();
+            while !query.frame_complete() {
+                query.tick();
+                ticks += 1;
+            }
+            ticks
+        })
+    }
+
+    #[test]
+    fn odd_frame_skip() {
+        // vbl_nmi_timing 3.even_odd_frames: one dot shorter every other frame
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2001, 0x08);
+        let mut lengths = frame_lengths(&mut query);
+        lengths.sort();
+        assert_eq!(lengths, [89341, 89342]);
+
+        // not when rendering is disabled
+        setup!(query, Mirroring::Horizontal);
+        assert_eq!(frame_lengths(&mut query), [89342; 2]);
+
+        // nor on PAL
+        setup!(query, Mirroring::Horizontal);
+        query.ppu.region = Region::Pal;
+        query.cpu_write(0x2001, 0x08);
+        assert_eq!(frame_lengths(&mut query), [106392; 2]);
+    }
+
+    fn screen_phases(query: &mut PpuQueryItem) -> [u8; 4] {
+        [0; 4].map(|_| {
+            query.tick();
+            while !query.frame_complete() {
+                query.tick();
+            }
+            query.ppu.screen_phase
+        })
+    }
+
+    #[test]
+    fn dot_crawl_phase() {
+        // frames start 4 subcarrier phases apart, making a three frame cycle
+        setup!(query, Mirroring::Horizontal);
+        let phases = screen_phases(&mut query);
+        assert!(phases.windows(2).all(|p| p[1] == (p[0] + 4) % 12));
+
+        // the skipped dot brings every other frame back to the same phase
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2001, 0x08);
+        let phases = screen_phases(&mut query);
+        assert_ne!(phases[0], phases[1]);
+        assert_eq!(phases[0], phases[2]);
+        assert_eq!(phases[1], phases[3]);
+    }
+
+    #[test]
+    fn vblank_read_race() {
+        // vbl_nmi_timing 2.vbl_timing and 5.nmi_suppression: a read one dot before the
+        // flag is set reads it clear and suppresses it and the NMI
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2000, 0x80);
+        run_until_dot(&mut query, 241, 1);
+        assert_eq!(query.cpu_read(0x2002).unwrap() & 0x80, 0x00);
+        query.tick();
+        assert!(!query.ppu.registers.status.vblank());
+        assert!(!query.nmi());
+
+        // on the dot it is set or the next one, it reads set and the NMI is suppressed
+        for cycle in [2, 3] {
+            setup!(query, Mirroring::Horizontal);
+            query.cpu_write(0x2000, 0x80);
+            run_until_dot(&mut query, 241, cycle);
+            assert_eq!(query.cpu_read(0x2002).unwrap() & 0x80, 0x80);
+            assert!(!query.nmi());
+        }
+
+        // later reads do not affect the NMI
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2000, 0x80);
+        run_until_dot(&mut query, 241, 4);
+        assert_eq!(query.cpu_read(0x2002).unwrap() & 0x80, 0x80);
+        assert!(query.nmi());
+    }
+
+    #[test]
+    fn nmi_control_during_vblank() {
+        // vbl_nmi_timing 4.nmi_control: enabling NMI during vblank raises one right away
+        setup!(query, Mirroring::Horizontal);
+        run_until_dot(&mut query, 241, 10);
+        assert!(!query.nmi());
+        query.cpu_write(0x2000, 0x80);
+        assert!(query.nmi());
+        // only on the transition
+        query.cpu_write(0x2000, 0x80);
+        assert!(!query.nmi());
+        query.cpu_write(0x2000, 0x00);
+        query.cpu_write(0x2000, 0x80);
+        assert!(query.nmi());
+
+        // disabling it as vblank starts cancels the NMI
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2000, 0x80);
+        run_until_dot(&mut query, 241, 2);
+        query.cpu_write(0x2000, 0x00);
+        assert!(!query.nmi());
+
+        // and no NMI once vblank is over
+        setup!(query, Mirroring::Horizontal);
+        run_until_dot(&mut query, -1, 2);
+        query.cpu_write(0x2000, 0x80);
+        assert!(!query.nmi());
+    }
+
+    #[test]
+    fn io_latch() {
+        setup!(query, Mirroring::Horizontal);
+        // writes to any register, even read only ones, fill the latch
+        query.cpu_write(0x2002, 0xA5);
+        assert_eq!(query.cpu_read(0x2000), Some(0xA5));
+        assert_eq!(query.cpu_read(0x2005), Some(0xA5));
+        // $2002 only drives its top 3 bits
+        assert_eq!(query.cpu_read(0x2002), Some(0x05));
+        assert_eq!(query.cpu_read(0x2006), Some(0x05));
+
+        query.cpu_write(0x2003, 0xFF);
+        for _ in 1..IO_LATCH_DECAY_FRAMES {
+            query.ppu.decay_io_latch();
+        }
+        assert_eq!(query.cpu_read(0x2001), Some(0xFF));
+        // bits refreshed by the $2002 read last longer
+        query.ppu.registers.status.set_sprite_zero_hit(true);
+        assert_eq!(query.cpu_read(0x2002), Some(0x5F));
+        query.ppu.decay_io_latch();
+        assert_eq!(query.cpu_read(0x2001), Some(0x40));
+        for _ in 1..IO_LATCH_DECAY_FRAMES {
+            query.ppu.decay_io_latch();
+        }
+        assert_eq!(query.cpu_read(0x2001), Some(0x00));
+    }
+
+    #[test]
+    fn palette_read_buffer() {
+        setup!(query, Mirroring::Horizontal);
+        query.ppu_write(0x2F05, 0x42);
+        query.ppu_write(0x3F05, 0x21);
+        query.cpu_write(0x2006, 0x3F);
+        query.cpu_write(0x2006, 0x05);
+        // the palette is read right away, its top 2 bits coming from the latch
+        query.cpu_write(0x2002, 0xC0);
+        assert_eq!(query.cpu_read(0x2007), Some(0xE1));
+        assert_eq!(query.ppu.data_buffer, 0x42);
+
+        // while the buffer holds the nametable byte under the palette
+        query.cpu_write(0x2006, 0x20);
+        query.cpu_write(0x2006, 0x00);
+        assert_eq!(query.cpu_read(0x2007), Some(0x42));
+
+        // greyscale applies to palette reads
+        query.cpu_write(0x2001, 0x01);
+        query.cpu_write(0x2006, 0x3F);
+        query.cpu_write(0x2006, 0x05);
+        assert_eq!(query.cpu_read(0x2007), Some(0x20));
+    }
+
+    #[test]
+    fn oam_data_reads() {
+        setup!(query, Mirroring::Horizontal);
+        query.cpu_write(0x2003, 0x04);
+        for data in [0x10, 0x20, 0xFF, 0x40] {
+            query.cpu_write(0x2004, data);
+        }
+        assert_eq!(query.ppu.registers.oam_addr, 0x08);
+        query.cpu_write(0x2003, 0x05);
+        // reads do not increment the address
+        assert_eq!(query.cpu_read(0x2004), Some(0x20));
+        assert_eq!(query.cpu_read(0x2004), Some(0x20));
+        query.cpu_write(0x2003, 0x06);
+        assert_eq!(query.cpu_read(0x2004), Some(0xE3));
+
+        // secondary OAM is cleared to $FF at the start of each line while rendering
+        query.cpu_write(0x2001, 0x18);
+        run_until_dot(&mut query, 10, 20);
+        assert_eq!(query.cpu_read(0x2004), Some(0xFF));
+        // then the evaluation reads the Y coordinates
+        run_until_dot(&mut query, 10, 70);
+        assert_eq!(query.cpu_read(0x2004), Some(0x00));
+    }
 }
@@ -0,0 +1,189 @@
+use crate::ppu::palette::PaletteState;
+use crate::ppu::{PpuQuery, PpuQueryReadOnlyItem};
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Color32, Stroke},
+    EguiContexts,
+};
+use bevy_pixel_buffer::frame::GetFrameFromImages;
+use bevy_pixel_buffer::pixel_buffer::PixelBufferSize;
+use bevy_pixel_buffer::{builder::PixelBufferBuilder, egui::EguiTexture};
+
+use super::palette::Palette;
+
+const NAMETABLE_WIDTH: u32 = 512;
+const NAMETABLE_HEIGHT: u32 = 480;
+
+const NAMETABLE_SIZE: PixelBufferSize = PixelBufferSize {
+    size: UVec2::new(NAMETABLE_WIDTH, NAMETABLE_HEIGHT),
+    pixel_size: UVec2::new(1, 1),
+};
+
+/// The four logical nametables laid out as they are scrolled over, as palette indices.
+#[derive(Component)]
+pub struct NametableBuffer {
+    buffer: Vec<u8>,
+    tile_grid: bool,
+    attribute_grid: bool,
+}
+
+impl Default for NametableBuffer {
+    fn default() -> Self {
+        Self {
+            buffer: vec![0; (NAMETABLE_WIDTH * NAMETABLE_HEIGHT) as usize],
+            tile_grid: false,
+            attribute_grid: false,
+        }
+    }
+}
+
+/// Nametable byte and attribute palette of the tile at (`tile_x`, `tile_y`) of the 64x60
+/// tile view.
+struct Tile {
+    addr: u16,
+    id: u8,
+    palette: u8,
+}
+
+impl Tile {
+    fn read(ppu: &PpuQueryReadOnlyItem, tile_x: u16, tile_y: u16) -> Self {
+        let nametable = 0x2000 | ((tile_y / 30) << 11) | ((tile_x / 32) << 10);
+        let (coarse_x, coarse_y) = (tile_x % 32, tile_y % 30);
+        let addr = nametable | (coarse_y << 5) | coarse_x;
+        let attribute = ppu.ppu_read(nametable | 0x03C0 | ((coarse_y >> 2) << 3) | (coarse_x >> 2));
+        let shift = ((coarse_y & 0x02) << 1) | (coarse_x & 0x02);
+        Self {
+            addr,
+            id: ppu.ppu_read(addr),
+            palette: (attribute >> shift) & 0x03,
+        }
+    }
+}
+
+pub fn init_nametable_buffer(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    PixelBufferBuilder::new()
+        .with_render(false)
+        .with_size(NAMETABLE_SIZE)
+        .spawn(&mut commands, &mut images)
+        .entity()
+        .insert(NametableBuffer::default());
+}
+
+pub fn update_nametable_buffer(ppu: Query<PpuQuery>, mut nametables: Query<&mut NametableBuffer>) {
+    if let (Ok(ppu), Ok(mut nametable)) = (ppu.get_single(), nametables.get_single_mut()) {
+        let pattern_table = (ppu.ppu.registers.ctrl.pattern_background() as u16) << 12;
+        for tile_y in 0..(NAMETABLE_HEIGHT / 8) as u16 {
+            for tile_x in 0..(NAMETABLE_WIDTH / 8) as u16 {
+                let tile = Tile::read(&ppu, tile_x, tile_y);
+                for offset_y in 0..8 {
+                    let pattern_addr = pattern_table | ((tile.id as u16) << 4) | offset_y;
+                    let lsb = ppu.ppu_read(pattern_addr);
+                    let msb = ppu.ppu_read(pattern_addr + 8);
+                    for offset_x in 0..8 {
+                        let pixel = ((lsb >> (7 - offset_x)) & 0x01)
+                            | (((msb >> (7 - offset_x)) & 0x01) << 1);
+                        // transparent pixels show the backdrop color
+                        let addr = if pixel == 0 {
+                            0x3F00
+                        } else {
+                            0x3F00 | ((tile.palette as u16) << 2) | pixel as u16
+                        };
+                        let x = tile_x * 8 + offset_x;
+                        let y = tile_y * 8 + offset_y;
+                        nametable.buffer[(x as u32 + y as u32 * NAMETABLE_WIDTH) as usize] =
+                            ppu.ppu_read(addr);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_nametable_buffer(
+    mut images: ResMut<Assets<Image>>,
+    palette_state: Res<PaletteState>,
+    palettes: Res<Assets<Palette>>,
+    pbs: Query<(&Handle<Image>, &NametableBuffer)>,
+) {
+    if let (Some(palette), Ok((img, nametable))) = (
+        palettes.get(&palette_state.palette_handle),
+        pbs.get_single(),
+    ) {
+        images.frame(img).per_pixel(|coord, _| {
+            let color_id = nametable.buffer[(coord.x + coord.y * NAMETABLE_WIDTH) as usize];
+            palette
+                .get_color(color_id.into())
+                .unwrap_or_else(|| panic!("invalid color id {:#04x}", color_id))
+        });
+    }
+}
+
+pub fn nametable_gui(
+    mut contexts: EguiContexts,
+    mut pbs: Query<(&EguiTexture, &mut NametableBuffer)>,
+    ppu: Query<PpuQuery>,
+) {
+    let (Ok((texture, mut nametable)), Ok(ppu)) = (pbs.get_single_mut(), ppu.get_single()) else {
+        return;
+    };
+    egui::Window::new("Nametables").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut nametable.tile_grid, "Tile grid");
+            ui.checkbox(&mut nametable.attribute_grid, "Attribute grid");
+        });
+        let response = ui.image(egui::load::SizedTexture::new(texture.id, texture.size));
+        let rect = response.rect;
+        let scale = rect.width() / NAMETABLE_WIDTH as f32;
+        let painter = ui.painter_at(rect);
+
+        let grids = [
+            (nametable.tile_grid, 8, Color32::from_white_alpha(40)),
+            (
+                nametable.attribute_grid,
+                16,
+                Color32::from_rgba_unmultiplied(0, 160, 255, 90),
+            ),
+        ];
+        for (_, size, color) in grids.into_iter().filter(|(enabled, _, _)| *enabled) {
+            let stroke = Stroke::new(1.0f32, color);
+            for x in (0..NAMETABLE_WIDTH).step_by(size) {
+                let x = rect.left() + x as f32 * scale;
+                painter.vline(x, rect.y_range(), stroke);
+            }
+            for y in (0..NAMETABLE_HEIGHT).step_by(size) {
+                let y = rect.top() + y as f32 * scale;
+                painter.hline(rect.x_range(), y, stroke);
+            }
+        }
+
+        // the viewport wraps around the edges of the view like the scrolling does
+        let tram_addr = &ppu.ppu.tram_addr;
+        let scroll_x = tram_addr.nametable_x() as u32 * 256
+            + tram_addr.coarse_x() as u32 * 8
+            + ppu.ppu.fine_x as u32;
+        let scroll_y = tram_addr.nametable_y() as u32 * 240
+            + tram_addr.coarse_y() as u32 * 8
+            + tram_addr.fine_y() as u32;
+        let stroke = Stroke::new(2.0f32, Color32::RED);
+        for offset_x in [0.0, -(NAMETABLE_WIDTH as f32)] {
+            for offset_y in [0.0, -(NAMETABLE_HEIGHT as f32)] {
+                let min = egui::pos2(
+                    rect.left() + (scroll_x as f32 + offset_x) * scale,
+                    rect.top() + (scroll_y as f32 + offset_y) * scale,
+                );
+                let viewport = egui::Rect::from_min_size(min, egui::vec2(256.0, 240.0) * scale);
+                painter.rect_stroke(viewport, 0.0, stroke);
+            }
+        }
+
+        if let Some(pos) = response.hover_pos() {
+            let tile_x = ((pos.x - rect.left()) / scale / 8.0) as u16;
+            let tile_y = ((pos.y - rect.top()) / scale / 8.0) as u16;
+            let tile = Tile::read(&ppu, tile_x.min(63), tile_y.min(59));
+            response.on_hover_text_at_pointer(format!(
+                "address: {:#06X}\ntile id: {:#04X}\npalette: {}",
+                tile.addr, tile.id, tile.palette
+            ));
+        }
+    });
+}
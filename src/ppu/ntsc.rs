@@ -0,0 +1,306 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+
+use super::palette::Palette;
+use crate::region::Region;
+
+/// Voltages of the composite signal relative to sync, for the low and high half of the
+/// square wave at each of the 4 luma levels.
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+/// attenuation of the signal during the phases of an emphasized color
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+/// hue of color 0 in twelfths of a turn, puts $x6 on red and $x2 on blue
+const HUE_OFFSET: f32 = 3.9;
+
+/// width of the filtered picture, 7 output pixels for every 3 NES pixels like nes_ntsc
+pub const NTSC_WIDTH: usize = 602;
+/// the PPU outputs 8 signal samples per pixel, 12 samples making a subcarrier cycle
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_PIXEL;
+
+/// TV calibration knobs applied when decoding the signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscSettings {
+    /// hue rotation in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    /// gamma of the display, 2.2 leaves the decoded colors unchanged
+    pub gamma: f32,
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl NtscSettings {
+    /// Calibration matching the PPU of `region`. The PAL and Dendy PPUs put their color
+    /// phases half a step, 15 degrees, away from the NTSC ones.
+    pub fn for_region(region: Region) -> Self {
+        match region {
+            Region::Ntsc => Self::default(),
+            Region::Pal | Region::Dendy => Self {
+                hue: -15.0,
+                ..Self::default()
+            },
+        }
+    }
+}
+
+/// the square wave of a color is high for 6 of the 12 phases of the color subcarrier
+fn in_color_phase(color: u16, phase: u16) -> bool {
+    (color + phase) % 12 < 6
+}
+
+/// Signal level, between 0 for black and 1 for white, output for the 9 bit color index
+/// `pixel` (palette value and emphasis bits) at one of the 12 subcarrier phases.
+pub fn signal_level(pixel: u16, phase: u16) -> f32 {
+    let color = pixel & 0x0F;
+    // $xE and $xF are black whatever the luma bits
+    let level = if color > 13 { 1 } else { (pixel >> 4) & 0x03 } as usize;
+    let emphasis = pixel >> 6;
+
+    let low = SIGNAL_LOW[level];
+    let high = SIGNAL_HIGH[level];
+    let signal = match color {
+        0x00 => high,
+        0x0D.. => low,
+        _ if in_color_phase(color, phase) => high,
+        _ => low,
+    };
+    // red, green and blue emphasis darken the phases of colors $xC, $x4 and $x8
+    let attenuated = color < 0x0E
+        && [0x0C, 0x04, 0x08]
+            .iter()
+            .enumerate()
+            .any(|(bit, &phase_color)| {
+                emphasis & (1 << bit) != 0 && in_color_phase(phase_color, phase)
+            });
+    let signal = if attenuated {
+        signal * EMPHASIS_ATTENUATION
+    } else {
+        signal
+    };
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+/// Converts YIQ to RGB with the calibration knobs, clamped and gamma corrected.
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32, settings: &NtscSettings) -> [f32; 3] {
+    let y = y * settings.contrast + settings.brightness;
+    let (i, q) = (i * settings.saturation, q * settings.saturation);
+    [
+        y + 0.946882 * i + 0.623557 * q,
+        y - 0.274788 * i - 0.635691 * q,
+        y - 1.108545 * i + 1.709007 * q,
+    ]
+    .map(|value| value.clamp(0.0, 1.0).powf(2.2 / settings.gamma))
+}
+
+/// angle of the subcarrier at `phase`, the hue knob rotating all colors
+pub fn phase_angle(phase: f32, settings: &NtscSettings) -> f32 {
+    PI * (phase + HUE_OFFSET) / 6.0 + settings.hue.to_radians()
+}
+
+/// Decodes the whole color cycle of each of the 512 color indices, the way a TV averages
+/// a flat area of a single color.
+pub fn generate_palette(settings: &NtscSettings) -> Palette {
+    let colors = (0..512)
+        .map(|pixel| {
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for phase in 0..12 {
+                let level = signal_level(pixel, phase);
+                let angle = phase_angle(phase as f32, settings);
+                y += level;
+                i += level * angle.cos();
+                q += level * angle.sin();
+            }
+            let [r, g, b] = yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, settings);
+            Color::srgb(r, g, b)
+        })
+        .collect();
+    Palette { colors }
+}
+
+/// Composite video filter: re-creates the signal of each line from the palette indices and
+/// decodes it back like a TV, each output pixel averaging one subcarrier cycle around it.
+/// Colors bleed over their neighbours at edges, and as lines and frames start on different
+/// phases the fringes form diagonal patterns that crawl from frame to frame.
+#[derive(Resource)]
+pub struct NtscFilter {
+    pub enabled: bool,
+    /// signal level of each color index at each phase
+    levels: Box<[[f32; 12]; 512]>,
+    /// RGB colors of the last filtered frame
+    pixels: Vec<[f32; 3]>,
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        let mut levels = Box::new([[0.0; 12]; 512]);
+        for (pixel, phases) in levels.iter_mut().enumerate() {
+            for (phase, level) in phases.iter_mut().enumerate() {
+                *level = signal_level(pixel as u16, phase as u16);
+            }
+        }
+        Self {
+            enabled: false,
+            levels,
+            pixels: vec![[0.0; 3]; NTSC_WIDTH * 240],
+        }
+    }
+}
+
+impl NtscFilter {
+    /// Filters a frame whose first pixel starts at subcarrier `phase`.
+    pub fn filter(&mut self, frame: &[[u16; 256]; 240], phase: u8, settings: &NtscSettings) {
+        let carrier: [(f32, f32); 12] = std::array::from_fn(|phase| {
+            let angle = phase_angle(phase as f32, settings);
+            (angle.cos(), angle.sin())
+        });
+        // running sums of the signal and of its products with the subcarrier, so each
+        // output pixel gets its window of samples in constant time
+        let mut sums = vec![[0.0f32; 3]; LINE_SAMPLES + 1];
+        for (y, line) in frame.iter().enumerate() {
+            // lines last 341 dots, 4 phases more than a whole number of cycles
+            let line_phase = (phase as usize + y * 4) % 12;
+            for sample in 0..LINE_SAMPLES {
+                let phase = (line_phase + sample) % 12;
+                let level = self.levels[(line[sample / SAMPLES_PER_PIXEL] & 0x1FF) as usize][phase];
+                let (cos, sin) = carrier[phase];
+                let [y, i, q] = sums[sample];
+                sums[sample + 1] = [y + level, i + level * cos, q + level * sin];
+            }
+            let row = &mut self.pixels[y * NTSC_WIDTH..(y + 1) * NTSC_WIDTH];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let center = (2 * x + 1) * LINE_SAMPLES / (2 * NTSC_WIDTH);
+                // samples past the edges of the line are blanking, at the black level
+                let start = center.saturating_sub(6);
+                let end = (center + 6).min(LINE_SAMPLES);
+                let [y, i, q] = [0, 1, 2].map(|c| (sums[end][c] - sums[start][c]) / 12.0);
+                *pixel = yiq_to_rgb(y, i, q, settings);
+            }
+        }
+    }
+
+    pub fn get_color(&self, x: u32, y: u32) -> Option<Color> {
+        if x as usize >= NTSC_WIDTH {
+            return None;
+        }
+        self.pixels
+            .get(y as usize * NTSC_WIDTH + x as usize)
+            .map(|&[r, g, b]| Color::srgb(r, g, b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{generate_palette, NtscFilter, NtscSettings, NTSC_WIDTH};
+    use crate::region::Region;
+
+    fn rgb(palette: &super::Palette, color_id: u16) -> [u8; 3] {
+        let [r, g, b, _] = palette
+            .get_color(color_id)
+            .unwrap()
+            .to_srgba()
+            .to_u8_array();
+        [r, g, b]
+    }
+
+    #[test]
+    fn generated_colors() {
+        let palette = generate_palette(&NtscSettings::default());
+        assert_eq!(palette.colors.len(), 512);
+        assert_eq!(rgb(&palette, 0x30), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(rgb(&palette, 0x0F), [0x00, 0x00, 0x00]);
+        assert_eq!(rgb(&palette, 0x1D), [0x00, 0x00, 0x00]);
+
+        let [r, g, b] = rgb(&palette, 0x16);
+        assert!(r > g && r > b, "$16 is red");
+        let [r, g, b] = rgb(&palette, 0x12);
+        assert!(b > r && b > g, "$12 is blue");
+        let [r, g, b] = rgb(&palette, 0x1A);
+        assert!(g > r && g > b, "$1A is green");
+        // red emphasis dims green and blue
+        let [r, g, b] = rgb(&palette, 0x070);
+        assert!(r > g && r > b);
+
+        let turned = generate_palette(&NtscSettings {
+            hue: 180.0,
+            ..Default::default()
+        });
+        let [r, g, b] = rgb(&turned, 0x16);
+        assert!(r < g && r < b, "$16 turns cyan");
+        let grey = generate_palette(&NtscSettings {
+            saturation: 0.0,
+            ..Default::default()
+        });
+        let [r, g, b] = rgb(&grey, 0x16);
+        assert!(r == g && g == b);
+    }
+
+    #[test]
+    fn region_palettes() {
+        let ntsc = generate_palette(&NtscSettings::for_region(Region::Ntsc));
+        let pal = generate_palette(&NtscSettings::for_region(Region::Pal));
+        assert_eq!(
+            ntsc.colors,
+            generate_palette(&NtscSettings::default()).colors
+        );
+        assert_ne!(rgb(&ntsc, 0x16), rgb(&pal, 0x16));
+        // greys have no hue to shift
+        assert_eq!(rgb(&ntsc, 0x10), rgb(&pal, 0x10));
+        let [r, g, b] = rgb(&pal, 0x16);
+        assert!(r > g && r > b, "$16 stays red");
+        assert_eq!(
+            NtscSettings::for_region(Region::Dendy),
+            NtscSettings::for_region(Region::Pal)
+        );
+    }
+
+    #[test]
+    fn composite_filter() {
+        let settings = NtscSettings::default();
+        let palette = generate_palette(&settings);
+        let mut frame = Box::new([[0x16; 256]; 240]);
+        // a column of white in the middle of red
+        for line in frame.iter_mut() {
+            line[128] = 0x30;
+        }
+        let mut filter = NtscFilter::default();
+        filter.filter(&frame, 0, &settings);
+
+        let to_u8 = |color: Color| color.to_srgba().to_u8_array();
+        // flat areas decode to the palette colors
+        assert_eq!(
+            to_u8(filter.get_color(100, 10).unwrap()),
+            to_u8(palette.get_color(0x16).unwrap())
+        );
+        // the edges of the column blend and take artifact colors that depend on the line
+        let edge = NTSC_WIDTH as u32 * 128 / 256 - 1;
+        let line_colors: Vec<_> = (0..3)
+            .map(|y| to_u8(filter.get_color(edge, y).unwrap()))
+            .collect();
+        assert_ne!(line_colors[0], line_colors[1]);
+        assert_ne!(line_colors[1], line_colors[2]);
+        // and crawl with the frame phase
+        let first_frame = to_u8(filter.get_color(edge, 0).unwrap());
+        filter.filter(&frame, 4, &settings);
+        assert_ne!(to_u8(filter.get_color(edge, 0).unwrap()), first_frame);
+        assert!(filter.get_color(NTSC_WIDTH as u32, 0).is_none());
+    }
+}
@@ -42,6 +42,11 @@ impl Oam {
         unsafe { OamEntry(self.entries.get_unchecked(index as usize).0) }
     }
 
+    pub fn read_byte(&self, index: u8) -> u8 {
+        let entry = self.get_entry(index >> 2);
+        (entry.0 >> ((index & 0x3) << 3)) as u8
+    }
+
     pub fn write_byte(&mut self, index: u8, data: u8) {
         let entry_index = (index >> 2) as usize;
         let entry = unsafe { self.entries.get_unchecked_mut(entry_index) };
@@ -1,25 +1,91 @@
+use std::path::Path;
+
 use bevy::{
     asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
     prelude::*,
     reflect::TypePath,
 };
+use bevy_egui::{
+    egui::{self, Color32},
+    EguiContexts,
+};
 
 use thiserror::Error;
 
+use crate::region::Region;
+
+use super::{
+    ntsc::{generate_palette, NtscFilter, NtscSettings},
+    Ppu, PpuQuery,
+};
+
 #[derive(Debug, Error)]
 pub enum PaletteLoaderError {
     #[error("I/O error")]
     Io(#[from] std::io::Error),
+    #[error("palette files hold 64 or 512 colors, found {0} bytes")]
+    Size(usize),
 }
 
+/// attenuation of the channels that are not emphasized
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/// 512 colors, the bits 6 to 8 of a color index being the red, green and blue emphasis
+/// bits of PPUMASK.
 #[derive(Asset, Debug, TypePath)]
 pub struct Palette {
-    pub colors: [Color; 64],
+    pub colors: Vec<Color>,
 }
 
 impl Palette {
-    pub fn get_color(&self, color_id: u8) -> Option<Color> {
-        self.colors.get((color_id & 0x3F) as usize).copied()
+    pub fn get_color(&self, color_id: u16) -> Option<Color> {
+        self.colors.get((color_id & 0x1FF) as usize).copied()
+    }
+
+    /// Reads the 8 bit RGB triplets of a .pal file. Files with only 64 colors get their
+    /// emphasized colors approximated by darkening the channels that are not emphasized.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PaletteLoaderError> {
+        let rgb: Vec<[f32; 3]> = bytes
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]].map(|c| c as f32 / 255.0))
+            .collect();
+        let rgb = match rgb.len() {
+            512.. => rgb[..512].to_vec(),
+            64..512 => (0..512)
+                .map(|i| {
+                    let emphasis = i >> 6;
+                    let mut color = rgb[i & 0x3F];
+                    for (channel, value) in color.iter_mut().enumerate() {
+                        for bit in (0..3).filter(|bit| emphasis & (1 << bit) != 0) {
+                            if bit != channel {
+                                *value *= EMPHASIS_ATTENUATION;
+                            }
+                        }
+                    }
+                    color
+                })
+                .collect(),
+            _ => return Err(PaletteLoaderError::Size(bytes.len())),
+        };
+        Ok(Palette {
+            colors: rgb
+                .iter()
+                .map(|[r, g, b]| Color::srgb(*r, *g, *b))
+                .collect(),
+        })
+    }
+
+    /// Writes the colors as a .pal file, with only the first 64 colors unless `emphasis`.
+    pub fn to_bytes(&self, emphasis: bool) -> Vec<u8> {
+        let len = if emphasis { 512 } else { 64 };
+        self.colors
+            .iter()
+            .take(len)
+            .flat_map(|color| {
+                let [r, g, b, _] = color.to_srgba().to_u8_array();
+                [r, g, b]
+            })
+            .collect()
     }
 }
 
@@ -39,18 +105,9 @@ impl AssetLoader for PaletteLoader {
         _settings: &'a Self::Settings,
         _load_context: &'a mut LoadContext<'_>,
     ) -> Result<Self::Asset, Self::Error> {
-        let mut buffer = [0; 3];
-        let mut colors = [Color::BLACK; 64];
-        for i in 0..64 {
-            reader.read_exact(&mut buffer).await?;
-            let color = Color::srgb(
-                buffer[0] as f32 / 255.0,
-                buffer[1] as f32 / 255.0,
-                buffer[2] as f32 / 255.0,
-            );
-            colors[i] = color;
-        }
-        Ok(Palette { colors })
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Palette::from_bytes(&bytes)
     }
 
     fn extensions(&self) -> &[&str] {
@@ -58,10 +115,87 @@ impl AssetLoader for PaletteLoader {
     }
 }
 
+/// directory of the bundled palettes, relative to the assets
+const PALETTE_DIR: &str = "palettes";
+/// name shown for the palette made by the NTSC generator
+const NTSC_PALETTE: &str = "NTSC (generated)";
+/// palette bundled for the NTSC PPU
+const NTSC_PALETTE_FILE: &str = "palettes/nespalette.pal";
+
 #[derive(Resource, Default)]
 pub struct PaletteState {
     pub palette_handle: Handle<Palette>,
+    /// palette shown by the pattern table viewer
     pub palette_id: u16,
+    /// name of the palette in use, its asset path or the path of a file loaded from disk
+    palette_name: String,
+    /// .pal files found in the palettes directory
+    palette_files: Vec<String>,
+    /// path of the palette file to load, typed in the palette window
+    path: String,
+    ntsc: NtscSettings,
+    /// region of the console, the generator settings reset to its PPU
+    region: Region,
+    /// file the generated palette is saved to, kept apart from the loaded one
+    save_path: String,
+    /// save the emphasized colors along with the 64 base colors
+    save_emphasis: bool,
+}
+
+impl PaletteState {
+    fn load_asset(&mut self, asset_server: &AssetServer, path: String) {
+        info!("Loading palette {}", path);
+        self.palette_handle = asset_server.load(&path);
+        self.palette_name = path;
+    }
+
+    fn load_file(&mut self, palettes: &mut Assets<Palette>, path: String) {
+        let palette = std::fs::read(&path)
+            .map_err(PaletteLoaderError::from)
+            .and_then(|bytes| Palette::from_bytes(&bytes));
+        match palette {
+            Ok(palette) => {
+                info!("Loaded palette {}", path);
+                self.palette_handle = palettes.add(palette);
+                self.palette_name = path;
+            }
+            Err(err) => error!("Failed to load palette {}: {}", path, err),
+        }
+    }
+
+    /// TV calibration shared by the palette generator and the composite filter
+    pub fn ntsc(&self) -> &NtscSettings {
+        &self.ntsc
+    }
+
+    /// Regenerates the NTSC palette, in place when it is already the one in use so
+    /// dragging the sliders does not pile up assets.
+    fn generate(&mut self, palettes: &mut Assets<Palette>) {
+        let palette = generate_palette(&self.ntsc);
+        if self.palette_name == NTSC_PALETTE {
+            palettes.insert(&self.palette_handle, palette);
+        } else {
+            self.palette_handle = palettes.add(palette);
+            self.palette_name = NTSC_PALETTE.to_string();
+        }
+    }
+}
+
+/// asset paths of the .pal files of `dir`, sorted by name
+fn palette_files(dir: &Path) -> Vec<String> {
+    let mut files: Vec<String> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pal"))
+        .filter_map(|path| {
+            path.file_name()
+                .map(|name| format!("{}/{}", PALETTE_DIR, name.to_string_lossy()))
+        })
+        .collect();
+    files.sort();
+    files
 }
 
 pub struct PalettePlugin;
@@ -71,10 +205,193 @@ impl Plugin for PalettePlugin {
         app.init_resource::<PaletteState>()
             .init_asset::<Palette>()
             .init_asset_loader::<PaletteLoader>()
-            .add_systems(Startup, palette_setup);
+            .add_systems(PostStartup, palette_setup);
     }
 }
 
-fn palette_setup(mut state: ResMut<PaletteState>, asset_server: Res<AssetServer>) {
-    state.palette_handle = asset_server.load("palettes/nespalette.pal");
+/// NTSC consoles get the bundled palette, the PAL and Dendy PPUs shift hues so their
+/// palette is generated with the settings of their region.
+fn palette_setup(
+    mut state: ResMut<PaletteState>,
+    mut palettes: ResMut<Assets<Palette>>,
+    asset_server: Res<AssetServer>,
+    ppu: Query<&Ppu>,
+) {
+    state.palette_files = palette_files(&Path::new("assets").join(PALETTE_DIR));
+    state.region = ppu.get_single().map(|ppu| ppu.region).unwrap_or_default();
+    state.ntsc = NtscSettings::for_region(state.region);
+    match state.region {
+        Region::Ntsc => state.load_asset(&asset_server, NTSC_PALETTE_FILE.to_string()),
+        Region::Pal | Region::Dendy => {
+            info!("Generating the {:?} palette", state.region);
+            state.generate(&mut palettes);
+        }
+    }
+}
+
+/// Palette RAM as swatches, background palettes on the first row and sprite palettes on
+/// the second, along with the palette file picker.
+pub fn palette_gui(
+    mut contexts: EguiContexts,
+    mut state: ResMut<PaletteState>,
+    mut palettes: ResMut<Assets<Palette>>,
+    asset_server: Res<AssetServer>,
+    mut filter: ResMut<NtscFilter>,
+    ppu: Query<PpuQuery>,
+) {
+    egui::Window::new("Palettes").show(contexts.ctx_mut(), |ui| {
+        let mut selected = None;
+        egui::ComboBox::from_label("palette")
+            .selected_text(state.palette_name.as_str())
+            .show_ui(ui, |ui| {
+                for file in state.palette_files.iter() {
+                    if ui
+                        .selectable_label(*file == state.palette_name, file.as_str())
+                        .clicked()
+                    {
+                        selected = Some(file.clone());
+                    }
+                }
+            });
+        if let Some(file) = selected {
+            state.load_asset(&asset_server, file);
+        }
+        ui.horizontal(|ui| {
+            ui.label("file");
+            ui.text_edit_singleline(&mut state.path);
+            if ui.button("load").clicked() {
+                let path = state.path.clone();
+                state.load_file(&mut palettes, path);
+            }
+        });
+        egui::CollapsingHeader::new("NTSC generator").show(ui, |ui| {
+            // the filter decodes the signal itself and ignores the palette in use
+            ui.checkbox(&mut filter.enabled, "composite filter on the screen");
+            let ntsc = &mut state.ntsc;
+            let sliders = [
+                ui.add(egui::Slider::new(&mut ntsc.hue, -180.0..=180.0).text("hue")),
+                ui.add(egui::Slider::new(&mut ntsc.saturation, 0.0..=2.0).text("saturation")),
+                ui.add(egui::Slider::new(&mut ntsc.contrast, 0.5..=1.5).text("contrast")),
+                ui.add(egui::Slider::new(&mut ntsc.brightness, -0.5..=0.5).text("brightness")),
+                ui.add(egui::Slider::new(&mut ntsc.gamma, 1.0..=3.0).text("gamma")),
+            ];
+            let changed = sliders.iter().any(|slider| slider.changed());
+            ui.horizontal(|ui| {
+                let reset = ui.button("reset").clicked();
+                if reset {
+                    state.ntsc = NtscSettings::for_region(state.region);
+                }
+                if ui.button("generate").clicked() || reset || changed {
+                    state.generate(&mut palettes);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("file");
+                ui.text_edit_singleline(&mut state.save_path);
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut state.save_emphasis, "512 colors");
+                if ui.button("save to file").clicked() {
+                    let bytes = generate_palette(&state.ntsc).to_bytes(state.save_emphasis);
+                    match std::fs::write(&state.save_path, bytes) {
+                        Ok(()) => info!("Saved palette {}", state.save_path),
+                        Err(err) => error!("Failed to save palette {}: {}", state.save_path, err),
+                    }
+                }
+            });
+        });
+
+        ui.separator();
+        let Ok(ppu) = ppu.get_single() else {
+            ui.label("No PPU found");
+            return;
+        };
+        let palette = palettes.get(&state.palette_handle);
+        egui::Grid::new("palette_ram")
+            .spacing([2.0, 2.0])
+            .show(ui, |ui| {
+                for index in 0..0x20u16 {
+                    // read through the PPU so the sprite backdrop entries show their mirrors
+                    let value = ppu.ppu_read(0x3F00 + index);
+                    let color_id = match ppu.ppu.vs_ppu {
+                        Some(vs_ppu) => vs_ppu.color(value),
+                        None => value & 0x3F,
+                    };
+                    let color = palette
+                        .and_then(|palette| palette.get_color(color_id.into()))
+                        .map(|color| {
+                            let [r, g, b, _] = color.to_srgba().to_u8_array();
+                            Color32::from_rgb(r, g, b)
+                        })
+                        .unwrap_or(Color32::BLACK);
+                    // light colors get dark text
+                    let text = if color.r() as u16 + color.g() as u16 + color.b() as u16 > 384 {
+                        Color32::BLACK
+                    } else {
+                        Color32::WHITE
+                    };
+                    let (rect, response) =
+                        ui.allocate_exact_size(egui::vec2(28.0, 28.0), egui::Sense::hover());
+                    ui.painter().rect_filled(rect, 2.0, color);
+                    ui.painter().text(
+                        rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        format!("{:02X}", value & 0x3F),
+                        egui::FontId::monospace(11.0),
+                        text,
+                    );
+                    response.on_hover_text(format!(
+                        "address: {:#06X}\nvalue: {:#04X}",
+                        0x3F00 + index,
+                        value
+                    ));
+                    if index % 16 == 15 {
+                        ui.end_row();
+                    }
+                }
+            });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+
+    use super::{palette_files, Palette, EMPHASIS_ATTENUATION};
+
+    #[test]
+    fn emphasis_colors() {
+        let bytes: Vec<u8> = (0..64).flat_map(|_| [0xFF, 0xFF, 0xFF]).collect();
+        let palette = Palette::from_bytes(&bytes).unwrap();
+        assert_eq!(palette.colors.len(), 512);
+        assert_eq!(palette.get_color(0x30), Some(Color::srgb(1.0, 1.0, 1.0)));
+        // red emphasis
+        let dimmed = EMPHASIS_ATTENUATION;
+        assert_eq!(
+            palette.get_color(0x070),
+            Some(Color::srgb(1.0, dimmed, dimmed))
+        );
+        // red and blue emphasis
+        let green = dimmed * dimmed;
+        assert_eq!(
+            palette.get_color(0x170),
+            Some(Color::srgb(dimmed, green, dimmed))
+        );
+        assert!(Palette::from_bytes(&bytes[..30]).is_err());
+        assert_eq!(palette.to_bytes(false), bytes);
+        assert_eq!(palette.to_bytes(true).len(), 512 * 3);
+    }
+
+    #[test]
+    fn palette_directory() {
+        let dir = std::env::temp_dir().join("nes-rs-palette-directory");
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["b.pal", "a.pal", "notes.txt"] {
+            std::fs::write(dir.join(file), []).unwrap();
+        }
+        let files = palette_files(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, ["palettes/a.pal", "palettes/b.pal"]);
+        assert!(palette_files(&dir).is_empty());
+    }
 }
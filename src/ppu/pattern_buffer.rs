@@ -87,10 +87,12 @@ pub fn draw_pattern_buffer(
                 let pixel = pb.buffer[(coord.x + coord.y * PATTERN_WIDTH) as usize] as u16;
                 let addr = 0x3F00 + (palette_state.palette_id << 2) + pixel;
                 let color_id = query.ppu_read(addr);
-                palette.get_color(color_id).expect(&format!(
-                    "invalid color id {:#04x} was found at memory location {:#06x}",
-                    color_id, addr
-                ))
+                palette.get_color(color_id.into()).unwrap_or_else(|| {
+                    panic!(
+                        "invalid color id {:#04x} was found at memory location {:#06x}",
+                        color_id, addr
+                    )
+                })
             });
         }
     }
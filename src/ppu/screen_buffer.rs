@@ -1,12 +1,14 @@
 use bevy::{prelude::*, window::WindowResized};
-use bevy_fundsp::prelude::Num;
 use bevy_pixel_buffer::{
     builder::PixelBufferBuilder,
     frame::GetFrameFromImages,
     pixel_buffer::{PixelBuffer, PixelBufferSize},
 };
 
+use crate::nes::ArgsResource;
+
 use super::{
+    ntsc::{NtscFilter, NTSC_WIDTH},
     palette::{Palette, PaletteState},
     Ppu,
 };
@@ -20,58 +22,93 @@ const SCREEN_SIZE: PixelBufferSize = PixelBufferSize {
 };
 
 #[derive(Component)]
-struct ScreenBuffer;
+pub struct ScreenBuffer {
+    /// window pixels per NES pixel
+    pub scale: f32,
+}
 
 pub struct ScreenBufferPlugin;
 
 impl Plugin for ScreenBufferPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, init_screen_buffer).add_systems(
-            PostUpdate,
-            (update_screen_buffer, resize_screen_buffer).chain(),
-        );
+        app.init_resource::<NtscFilter>()
+            .add_systems(Startup, init_screen_buffer)
+            .add_systems(
+                PostUpdate,
+                (update_screen_buffer, resize_screen_buffer).chain(),
+            );
     }
 }
 
-fn init_screen_buffer(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+fn init_screen_buffer(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut filter: ResMut<NtscFilter>,
+    args: Res<ArgsResource>,
+) {
+    filter.enabled = args.ntsc_filter;
     PixelBufferBuilder::new()
         .with_render(true)
         .with_size(SCREEN_SIZE)
         .spawn(&mut commands, &mut images)
         .entity()
-        .insert(ScreenBuffer);
+        .insert(ScreenBuffer { scale: 1.0 });
 }
 
+/// The buffer is 602 pixels wide while the NTSC filter is on, drawn over the same area.
 fn update_screen_buffer(
     mut images: ResMut<Assets<Image>>,
     palette_state: Res<PaletteState>,
     palettes: Res<Assets<Palette>>,
-    pb: Query<(&Handle<Image>, &ScreenBuffer)>,
+    mut filter: ResMut<NtscFilter>,
+    mut pb: Query<(&Handle<Image>, &mut PixelBuffer), With<ScreenBuffer>>,
     ppu: Query<&Ppu>,
 ) {
-    if let (Ok((pb, _)), Ok(ppu), Some(palette)) = (
-        pb.get_single(),
-        ppu.get_single(),
-        palettes.get(&palette_state.palette_handle),
-    ) {
-        images.frame(pb).per_pixel(|coord, _| {
-            let color_id = ppu.screen_buffer[coord.y as usize][coord.x as usize];
-            palette
-                .get_color(color_id)
-                .expect(&format!("invalid color id {:#04x}", color_id))
-        });
+    let Ok((image, mut pb)) = pb.get_single_mut() else {
+        return;
+    };
+    let width = if filter.enabled {
+        NTSC_WIDTH as u32
+    } else {
+        SCREEN_WIDTH
+    };
+    if pb.size.size.x != width {
+        pb.size.size.x = width;
+    }
+
+    if let Ok(ppu) = ppu.get_single() {
+        // the image is resized after this system, pixels out of the frame stay black
+        if filter.enabled {
+            filter.filter(&ppu.screen_buffer, ppu.screen_phase, palette_state.ntsc());
+            images
+                .frame(image)
+                .per_pixel(|coord, _| filter.get_color(coord.x, coord.y).unwrap_or(Color::BLACK));
+        } else if let Some(palette) = palettes.get(&palette_state.palette_handle) {
+            images.frame(image).per_pixel(|coord, _| {
+                let Some(&color_id) = ppu.screen_buffer[coord.y as usize].get(coord.x as usize)
+                else {
+                    return Color::BLACK;
+                };
+                palette
+                    .get_color(color_id)
+                    .unwrap_or_else(|| panic!("invalid color id {:#05x}", color_id))
+            });
+        }
     }
 }
 
 fn resize_screen_buffer(
     mut resize_reader: EventReader<WindowResized>,
-    mut pb: Query<&mut Transform, With<PixelBuffer>>,
+    mut pb: Query<(&mut Transform, &mut ScreenBuffer, &PixelBuffer)>,
 ) {
-    if let Ok(mut tf) = pb.get_single_mut() {
+    if let Ok((mut tf, mut screen, pb)) = pb.get_single_mut() {
         for e in resize_reader.read() {
             let px_dim = (e.width / (SCREEN_WIDTH as f32)).min(e.height / (SCREEN_HEIGHT as f32));
             info!("px_dim: {}", px_dim);
-            tf.scale = Vec3::new(px_dim, px_dim, 1.0);
+            screen.scale = px_dim;
         }
+        // the filtered picture squeezes its wider buffer into the same width
+        let x_scale = screen.scale * SCREEN_WIDTH as f32 / pb.size.size.x as f32;
+        tf.scale = Vec3::new(x_scale, screen.scale, 1.0);
     }
 }
@@ -0,0 +1,319 @@
+use crate::ppu::palette::PaletteState;
+use crate::ppu::PpuQuery;
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{self, Color32, ScrollArea, Stroke},
+    EguiContexts,
+};
+use bevy_pixel_buffer::frame::GetFrameFromImages;
+use bevy_pixel_buffer::pixel_buffer::PixelBufferSize;
+use bevy_pixel_buffer::{builder::PixelBufferBuilder, egui::EguiTexture};
+
+use super::{oam::Oam, palette::Palette, screen_buffer::ScreenBuffer};
+
+/// the 64 sprites are laid out on 8 rows of 8 cells, tall enough for 8x16 sprites
+const SPRITE_COLUMNS: u32 = 8;
+const CELL_WIDTH: u32 = 8;
+const CELL_HEIGHT: u32 = 16;
+const SPRITE_WIDTH: u32 = SPRITE_COLUMNS * CELL_WIDTH;
+const SPRITE_HEIGHT: u32 = 64 / SPRITE_COLUMNS * CELL_HEIGHT;
+
+const SPRITE_SIZE: PixelBufferSize = PixelBufferSize {
+    size: UVec2::new(SPRITE_WIDTH, SPRITE_HEIGHT),
+    pixel_size: UVec2::new(3, 3),
+};
+
+/// color drawn for transparent sprite pixels
+const TRANSPARENT: u8 = 0xFF;
+
+#[derive(Component)]
+pub struct SpriteBuffer {
+    buffer: Vec<u8>,
+    selected: Option<u8>,
+    overlay: bool,
+}
+
+impl Default for SpriteBuffer {
+    fn default() -> Self {
+        Self {
+            buffer: vec![TRANSPARENT; (SPRITE_WIDTH * SPRITE_HEIGHT) as usize],
+            selected: None,
+            overlay: false,
+        }
+    }
+}
+
+/// Sprites that miss some of their lines because 8 sprites with a lower index are
+/// already on them.
+fn dropped_sprites(oam: &Oam, height: u8) -> [bool; 64] {
+    let mut dropped = [false; 64];
+    // sprites are drawn from the line after their Y coordinate
+    for line in 1..240u16 {
+        let on_line = (0..64).filter(|&index| {
+            let y = oam.get_entry(index).y() as u16 + 1;
+            line >= y && line < y + height as u16
+        });
+        for index in on_line.skip(8) {
+            dropped[index as usize] = true;
+        }
+    }
+    dropped
+}
+
+pub fn init_sprite_buffer(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    PixelBufferBuilder::new()
+        .with_render(false)
+        .with_size(SPRITE_SIZE)
+        .spawn(&mut commands, &mut images)
+        .entity()
+        .insert(SpriteBuffer::default());
+}
+
+pub fn update_sprite_buffer(ppu: Query<PpuQuery>, mut sprites: Query<&mut SpriteBuffer>) {
+    if let (Ok(ppu), Ok(mut sprites)) = (ppu.get_single(), sprites.get_single_mut()) {
+        let height = ppu.ppu.sprite_height() as u16;
+        for index in 0..64u32 {
+            let sprite = ppu.ppu.oam.get_entry(index as u8);
+            let attribute = sprite.attribute();
+            let cell_x = index % SPRITE_COLUMNS * CELL_WIDTH;
+            let cell_y = index / SPRITE_COLUMNS * CELL_HEIGHT;
+            for row in 0..CELL_HEIGHT as u16 {
+                let y = cell_y + row as u32;
+                let line = (cell_x + y * SPRITE_WIDTH) as usize;
+                // 8x8 sprites leave the bottom half of their cell empty
+                if row >= height {
+                    sprites.buffer[line..line + CELL_WIDTH as usize].fill(TRANSPARENT);
+                    continue;
+                }
+                let pattern_row = if attribute & 0x80 != 0 {
+                    height - 1 - row
+                } else {
+                    row
+                };
+                let addr = ppu.ppu.sprite_row_addr(sprite.tile_id() as u8, pattern_row);
+                let lsb = ppu.ppu_read(addr);
+                let msb = ppu.ppu_read(addr + 8);
+                for column in 0..CELL_WIDTH {
+                    let bit = if attribute & 0x40 != 0 {
+                        column
+                    } else {
+                        7 - column
+                    };
+                    let pixel = ((lsb >> bit) & 0x01) | (((msb >> bit) & 0x01) << 1);
+                    let color = if pixel == 0 {
+                        TRANSPARENT
+                    } else {
+                        ppu.ppu_read(0x3F10 | ((attribute as u16 & 0x03) << 2) | pixel as u16)
+                    };
+                    sprites.buffer[line + column as usize] = color;
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_sprite_buffer(
+    mut images: ResMut<Assets<Image>>,
+    palette_state: Res<PaletteState>,
+    palettes: Res<Assets<Palette>>,
+    pbs: Query<(&Handle<Image>, &SpriteBuffer)>,
+) {
+    if let (Some(palette), Ok((img, sprites))) = (
+        palettes.get(&palette_state.palette_handle),
+        pbs.get_single(),
+    ) {
+        images.frame(img).per_pixel(|coord, _| {
+            match sprites.buffer[(coord.x + coord.y * SPRITE_WIDTH) as usize] {
+                TRANSPARENT => Color::srgb(0.2, 0.2, 0.2),
+                color_id => palette
+                    .get_color(color_id.into())
+                    .unwrap_or_else(|| panic!("invalid color id {:#04x}", color_id)),
+            }
+        });
+    }
+}
+
+pub fn sprite_gui(
+    mut contexts: EguiContexts,
+    mut pbs: Query<(&EguiTexture, &mut SpriteBuffer)>,
+    ppu: Query<PpuQuery>,
+) {
+    let (Ok((texture, mut sprites)), Ok(ppu)) = (pbs.get_single_mut(), ppu.get_single()) else {
+        return;
+    };
+    let dropped = dropped_sprites(&ppu.ppu.oam, ppu.ppu.sprite_height() as u8);
+    egui::Window::new("Sprites").show(contexts.ctx_mut(), |ui| {
+        ui.checkbox(&mut sprites.overlay, "Show sprites on screen");
+        let response = ui.add(
+            egui::Image::new(egui::load::SizedTexture::new(texture.id, texture.size))
+                .sense(egui::Sense::click()),
+        );
+        let rect = response.rect;
+        let scale = rect.width() / SPRITE_WIDTH as f32;
+        let cell = egui::vec2(CELL_WIDTH as f32, CELL_HEIGHT as f32) * scale;
+        let cell_rect = |index: u8| {
+            let column = index as u32 % SPRITE_COLUMNS;
+            let row = index as u32 / SPRITE_COLUMNS;
+            egui::Rect::from_min_size(
+                rect.min + egui::vec2(column as f32, row as f32) * cell,
+                cell,
+            )
+        };
+        let painter = ui.painter_at(rect);
+        for index in (0..64).filter(|&index| dropped[index as usize]) {
+            painter.rect_stroke(cell_rect(index), 0.0, Stroke::new(1.0f32, Color32::RED));
+        }
+        if let Some(index) = sprites.selected {
+            painter.rect_stroke(cell_rect(index), 0.0, Stroke::new(2.0f32, Color32::YELLOW));
+        }
+
+        let hovered = response.hover_pos().map(|pos| {
+            let column = ((pos.x - rect.left()) / cell.x) as u8;
+            let row = ((pos.y - rect.top()) / cell.y) as u8;
+            (row * SPRITE_COLUMNS as u8 + column.min(7)).min(63)
+        });
+        if let Some(index) = hovered {
+            if response.clicked() {
+                sprites.selected = (sprites.selected != Some(index)).then_some(index);
+            }
+            response.on_hover_text_at_pointer(format!(
+                "#{:02} {}{}",
+                index,
+                ppu.ppu.oam.get_entry(index),
+                if dropped[index as usize] {
+                    "\ndropped on some lines"
+                } else {
+                    ""
+                }
+            ));
+        }
+
+        ui.separator();
+        let text_style = egui::TextStyle::Monospace;
+        let row_height = ui.text_style_height(&text_style);
+        ui.push_id("oam", |ui| {
+            ScrollArea::vertical()
+                .auto_shrink(false)
+                .max_height(200.0)
+                .show_rows(ui, row_height, 64, |ui, row_range| {
+                    for row in row_range {
+                        let entry = format!("#{:02} {}", row, ppu.ppu.oam.get_entry(row as u8));
+                        if ui
+                            .selectable_label(
+                                sprites.selected == Some(row as u8),
+                                egui::RichText::new(entry).monospace(),
+                            )
+                            .clicked()
+                        {
+                            sprites.selected = Some(row as u8);
+                        }
+                    }
+                });
+        });
+    });
+}
+
+/// Draws the bounding boxes of the selected sprite, and of every sprite when the overlay
+/// is on, over the game screen. Sprites losing lines to the 8 sprites limit are in red.
+pub fn sprite_overlay(
+    mut gizmos: Gizmos,
+    sprites: Query<&SpriteBuffer>,
+    screen: Query<(&Transform, &ScreenBuffer)>,
+    ppu: Query<PpuQuery>,
+) {
+    let (Ok(sprites), Ok((transform, screen)), Ok(ppu)) =
+        (sprites.get_single(), screen.get_single(), ppu.get_single())
+    else {
+        return;
+    };
+    let height = ppu.ppu.sprite_height() as f32;
+    let dropped = dropped_sprites(&ppu.ppu.oam, height as u8);
+    let scale = screen.scale;
+    for index in 0..64u8 {
+        let selected = sprites.selected == Some(index);
+        if !sprites.overlay && !selected {
+            continue;
+        }
+        let sprite = ppu.ppu.oam.get_entry(index);
+        let y = sprite.y() as f32 + 1.0;
+        if y >= 240.0 {
+            continue;
+        }
+        // the screen is centered on the origin with Y going up
+        let center = Vec2::new(sprite.x() as f32 + 4.0 - 128.0, 120.0 - y - height / 2.0);
+        let color = match (selected, dropped[index as usize]) {
+            (true, _) => Color::srgb(1.0, 1.0, 0.0),
+            (false, true) => Color::srgb(1.0, 0.0, 0.0),
+            (false, false) => Color::srgb(0.0, 1.0, 0.0),
+        };
+        gizmos.rect_2d(
+            transform.translation.truncate() + center * scale,
+            0.0,
+            Vec2::new(8.0, height) * scale,
+            color,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dropped_sprites, update_sprite_buffer, SpriteBuffer, SPRITE_WIDTH, TRANSPARENT};
+    use crate::{
+        cartridge::Cartridge,
+        nes::NesBundle,
+        ppu::{oam::Oam, PpuQuery},
+    };
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+
+    #[test]
+    fn sprites_over_the_limit() {
+        let mut oam = Oam::default();
+        for index in 0..64u8 {
+            oam.write_byte(index * 4, 0xF0);
+        }
+        // 8 sprites on lines 21 to 28, a ninth one sharing line 28 with them
+        for index in 0..8 {
+            oam.write_byte(index * 4, 20);
+        }
+        oam.write_byte(8 * 4, 27);
+        // a tenth sprite starting under the others is not dropped
+        oam.write_byte(9 * 4, 28);
+
+        let dropped = dropped_sprites(&oam, 8);
+        assert!(dropped[..8].iter().all(|dropped| !dropped));
+        assert!(dropped[8]);
+        assert!(!dropped[9]);
+
+        // but it is when it overlaps them in 8x16 mode
+        let dropped = dropped_sprites(&oam, 16);
+        assert!(dropped[9]);
+    }
+
+    #[test]
+    fn flipped_sprites() {
+        let mut app = App::new();
+        app.world_mut()
+            .spawn((NesBundle::default(), Cartridge::testing(None)));
+        let buffer = app.world_mut().spawn(SpriteBuffer::default()).id();
+        {
+            let mut query = app.world_mut().query::<PpuQuery>();
+            let mut query = query.single_mut(app.world_mut());
+            // first row of tile 1 is solid
+            query.ppu_write(0x0010, 0xFF);
+            query.ppu_write(0x3F11, 0x16);
+            // 8x8 sprite 0 showing tile 1 flipped vertically
+            query.ppu.oam.write_byte(1, 0x01);
+            query.ppu.oam.write_byte(2, 0x80);
+        }
+        app.world_mut().run_system_once(update_sprite_buffer);
+
+        let sprites = app.world().get::<SpriteBuffer>(buffer).unwrap();
+        let row = |y: u32| {
+            let start = (y * SPRITE_WIDTH) as usize;
+            &sprites.buffer[start..start + 8]
+        };
+        assert!((0..7).all(|y| row(y).iter().all(|&color| color == TRANSPARENT)));
+        assert!(row(7).iter().all(|&color| color == 0x16));
+        assert!((8..16).all(|y| row(y).iter().all(|&color| color == TRANSPARENT)));
+    }
+}
@@ -0,0 +1,67 @@
+use clap::ValueEnum;
+
+/// Console timing. PAL and Dendy consoles share their master clock, the Dendy keeps the
+/// NTSC CPU divider and APU timings while having as many scanlines as PAL.
+#[derive(Default, Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn master_clock_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 21_477_272.0,
+            Region::Pal | Region::Dendy => 26_601_712.0,
+        }
+    }
+
+    /// master clock cycles per PPU cycle
+    pub fn ppu_divider(&self) -> u8 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /// master clock cycles per CPU cycle, giving 3.2 PPU cycles per CPU cycle on PAL
+    pub fn cpu_divider(&self) -> u8 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    pub fn cpu_hz(&self) -> f64 {
+        self.master_clock_hz() / self.cpu_divider() as f64
+    }
+
+    /// scanlines per frame, including the pre-render one
+    pub fn scanlines(&self) -> i16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// scanline at which the vblank flag is set, the Dendy delays it by 50 lines to keep
+    /// NTSC games working with its longer frame
+    pub fn vblank_scanline(&self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// CPU cycles of the frame sequencer steps, the fourth being the last step of the
+    /// 4-step sequence and the fifth the last one of the 5-step sequence
+    pub fn frame_steps(&self) -> [usize; 5] {
+        match self {
+            Region::Ntsc | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+            Region::Pal => [8313, 16627, 24939, 33253, 41565],
+        }
+    }
+}
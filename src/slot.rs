@@ -0,0 +1,133 @@
+use bevy::ecs::query::QueryData;
+
+use crate::{
+    cartridge::{Cartridge, Mirroring, NametableSource},
+    fds::Fds,
+    nsf::Nsf,
+};
+
+/// Whatever is plugged in the cartridge slot of the console: an iNES `Cartridge`, the
+/// Famicom Disk System RAM adapter or an NSF player.
+#[derive(QueryData)]
+#[query_data(mutable)]
+pub struct SlotQuery {
+    cartridge: Option<&'static mut Cartridge>,
+    fds: Option<&'static mut Fds>,
+    pub nsf: Option<&'static mut Nsf>,
+}
+
+impl<'w> SlotQueryItem<'w> {
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(fds) = &mut self.fds {
+            fds.cpu_read(addr)
+        } else if let Some(nsf) = &mut self.nsf {
+            nsf.cpu_read(addr)
+        } else {
+            self.cartridge
+                .as_ref()
+                .and_then(|cartridge| cartridge.cpu_read(addr))
+        }
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let Some(fds) = &mut self.fds {
+            fds.cpu_write(addr, data);
+        } else if let Some(nsf) = &mut self.nsf {
+            nsf.cpu_write(addr, data);
+        } else if let Some(cartridge) = &mut self.cartridge {
+            _ = cartridge.cpu_write(addr, data);
+        }
+    }
+
+    /// pattern table and cartridge mapped nametable reads
+    pub fn ppu_read(&self, addr: u16) -> Option<u8> {
+        match (&self.fds, &self.cartridge) {
+            (Some(fds), _) => (addr < 0x2000).then(|| fds.ppu_read(addr)),
+            (_, Some(cartridge)) => cartridge.ppu_read(addr),
+            _ => None,
+        }
+    }
+
+    /// returns true when the write was handled by the slot
+    pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        match (&mut self.fds, &mut self.cartridge) {
+            (Some(fds), _) if addr < 0x2000 => {
+                fds.ppu_write(addr, data);
+                true
+            }
+            (Some(_), _) => false,
+            (_, Some(cartridge)) => cartridge.ppu_write(addr, data),
+            _ => false,
+        }
+    }
+
+    pub fn nametable(&self, addr: u16) -> NametableSource {
+        match (&self.fds, &self.cartridge) {
+            (Some(fds), _) => fds.mirroring().nametable(addr),
+            (_, Some(cartridge)) => cartridge.nametable(addr),
+            _ => Mirroring::Horizontal.nametable(addr),
+        }
+    }
+
+    pub fn nametable_read(&self, source: NametableSource, addr: u16) -> Option<u8> {
+        self.cartridge
+            .as_ref()
+            .and_then(|cartridge| cartridge.nametable_read(source, addr))
+    }
+
+    /// returns true when the write landed in memory located in the slot
+    pub fn nametable_write(&mut self, source: NametableSource, addr: u16, data: u8) -> bool {
+        self.cartridge
+            .as_mut()
+            .is_some_and(|cartridge| cartridge.nametable_write(source, addr, data))
+    }
+
+    /// Clocks the slot hardware for one CPU cycle, returns the expansion audio level.
+    pub fn tick(&mut self) -> f32 {
+        if let Some(nsf) = &mut self.nsf {
+            nsf.tick();
+        }
+        self.fds.as_mut().map_or(0.0, |fds| fds.tick())
+    }
+
+    pub fn irq(&self) -> bool {
+        self.fds.as_ref().is_some_and(|fds| fds.irq())
+    }
+
+    /// true when the Disk System RAM adapter, and its sound registers, are plugged in
+    pub fn has_fds(&self) -> bool {
+        self.fds.is_some()
+    }
+}
+
+impl<'w> SlotQueryReadOnlyItem<'w> {
+    pub fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match (self.fds, self.nsf, self.cartridge) {
+            (Some(fds), _, _) => fds.peek(addr),
+            (_, Some(nsf), _) => nsf.peek(addr),
+            (_, _, Some(cartridge)) => cartridge.cpu_read(addr),
+            _ => None,
+        }
+    }
+
+    pub fn ppu_read(&self, addr: u16) -> Option<u8> {
+        match (self.fds, self.cartridge) {
+            (Some(fds), _) => (addr < 0x2000).then(|| fds.ppu_read(addr)),
+            (_, Some(cartridge)) => cartridge.ppu_read(addr),
+            _ => None,
+        }
+    }
+
+    pub fn nametable(&self, addr: u16) -> NametableSource {
+        match (self.fds, self.cartridge) {
+            (Some(fds), _) => fds.mirroring().nametable(addr),
+            (_, Some(cartridge)) => cartridge.nametable(addr),
+            _ => Mirroring::Horizontal.nametable(addr),
+        }
+    }
+
+    pub fn nametable_read(&self, source: NametableSource, addr: u16) -> Option<u8> {
+        self.cartridge
+            .and_then(|cartridge| cartridge.nametable_read(source, addr))
+    }
+}
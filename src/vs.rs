@@ -0,0 +1,186 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use clap::ValueEnum;
+
+/// Colors of the RP2C04 PPUs as indices in the RP2C03 palette. Each chip has the same
+/// colors at different positions to keep the games from running on other boards.
+#[rustfmt::skip]
+const RP2C04_LUT: [[u8; 64]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+        0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+        0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+        0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+    ],
+    [
+        0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+        0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+        0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+        0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2D,
+    ],
+    [
+        0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+        0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+        0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+        0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+    ],
+    [
+        0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+        0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+        0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+    ],
+];
+
+/// PPU fitted on a Vs. System board. The RP2C03 and RC2C05 output the RGB palette
+/// directly, the RP2C04 scramble it and the RC2C05 answer with an ID on $2002.
+#[derive(Default, Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum VsPpu {
+    #[default]
+    Rp2c03,
+    #[value(name = "rp2c04-0001")]
+    Rp2c04V1,
+    #[value(name = "rp2c04-0002")]
+    Rp2c04V2,
+    #[value(name = "rp2c04-0003")]
+    Rp2c04V3,
+    #[value(name = "rp2c04-0004")]
+    Rp2c04V4,
+    #[value(name = "rc2c05-01")]
+    Rc2c05V1,
+    #[value(name = "rc2c05-02")]
+    Rc2c05V2,
+    #[value(name = "rc2c05-03")]
+    Rc2c05V3,
+    #[value(name = "rc2c05-04")]
+    Rc2c05V4,
+    #[value(name = "rc2c05-05")]
+    Rc2c05V5,
+}
+
+impl VsPpu {
+    /// PPU type from the low nibble of byte 13 of an NES 2.0 header
+    pub fn from_header(nibble: u8) -> Self {
+        match nibble & 0x0F {
+            0x02 => VsPpu::Rp2c04V1,
+            0x03 => VsPpu::Rp2c04V2,
+            0x04 => VsPpu::Rp2c04V3,
+            0x05 => VsPpu::Rp2c04V4,
+            0x08 => VsPpu::Rc2c05V1,
+            0x09 => VsPpu::Rc2c05V2,
+            0x0A => VsPpu::Rc2c05V3,
+            0x0B => VsPpu::Rc2c05V4,
+            0x0C => VsPpu::Rc2c05V5,
+            _ => VsPpu::Rp2c03,
+        }
+    }
+
+    /// maps a palette RAM value to the color actually output
+    pub fn color(&self, color: u8) -> u8 {
+        let color = color & 0x3F;
+        match self {
+            VsPpu::Rp2c04V1 => RP2C04_LUT[0][color as usize],
+            VsPpu::Rp2c04V2 => RP2C04_LUT[1][color as usize],
+            VsPpu::Rp2c04V3 => RP2C04_LUT[2][color as usize],
+            VsPpu::Rp2c04V4 => RP2C04_LUT[3][color as usize],
+            _ => color,
+        }
+    }
+
+    /// value of the low bits of $2002 in place of the open bus, games check it to refuse
+    /// other boards
+    pub fn status_id(&self) -> Option<u8> {
+        match self {
+            VsPpu::Rc2c05V1 | VsPpu::Rc2c05V4 => Some(0x1B),
+            VsPpu::Rc2c05V2 => Some(0x3D),
+            VsPpu::Rc2c05V3 => Some(0x1C),
+            _ => None,
+        }
+    }
+
+    /// the RC2C05 have PPUCTRL and PPUMASK at each other's address
+    pub fn swaps_ctrl_mask(&self) -> bool {
+        matches!(
+            self,
+            VsPpu::Rc2c05V1 | VsPpu::Rc2c05V2 | VsPpu::Rc2c05V3 | VsPpu::Rc2c05V4 | VsPpu::Rc2c05V5
+        )
+    }
+}
+
+/// Cabinet inputs of a Vs. UniSystem, read along with the controllers on $4016/$4017.
+#[derive(Component, Default)]
+pub struct VsSystem {
+    pub ppu: VsPpu,
+    /// switch 1 is bit 0
+    pub dip_switches: u8,
+    coins: [bool; 2],
+    service: bool,
+}
+
+impl VsSystem {
+    pub fn new(ppu: VsPpu) -> Self {
+        Self {
+            ppu,
+            ..Default::default()
+        }
+    }
+
+    /// $4016: controller data on bit 0, service button on bit 2, DIP switches 1 and 2 on
+    /// bits 3 and 4 and the coin slots on bits 5 and 6
+    pub fn read_4016(&self, controller: u8) -> u8 {
+        controller & 0x01
+            | (self.service as u8) << 2
+            | (self.dip_switches & 0x03) << 3
+            | (self.coins[0] as u8) << 5
+            | (self.coins[1] as u8) << 6
+    }
+
+    /// $4017: controller data on bit 0 and DIP switches 3 to 8 on bits 2 to 7
+    pub fn read_4017(&self, controller: u8) -> u8 {
+        controller & 0x01 | self.dip_switches & 0xFC
+    }
+}
+
+pub fn update_vs_inputs(mut query: Query<&mut VsSystem>, keys: Res<ButtonInput<KeyCode>>) {
+    if let Ok(mut vs) = query.get_single_mut() {
+        vs.coins = [keys.pressed(KeyCode::Digit5), keys.pressed(KeyCode::Digit6)];
+        vs.service = keys.pressed(KeyCode::Digit9);
+    }
+}
+
+pub fn vs_gui(mut query: Query<&mut VsSystem>, mut contexts: EguiContexts) {
+    egui::Window::new("Vs. System").show(contexts.ctx_mut(), |ui| {
+        let Ok(mut vs) = query.get_single_mut() else {
+            ui.label("No Vs. System game loaded");
+            return;
+        };
+        ui.monospace(format!("PPU: {:?}", vs.ppu));
+        ui.label("coin 1: 5, coin 2: 6, service: 9, player 2 start: 8");
+        ui.separator();
+        ui.label("DIP switches");
+        ui.horizontal(|ui| {
+            for switch in 0..8 {
+                let mut on = vs.dip_switches & (1 << switch) != 0;
+                if ui.checkbox(&mut on, format!("{}", switch + 1)).changed() {
+                    vs.dip_switches ^= 1 << switch;
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VsPpu, VsSystem};
+
+    #[test]
+    fn cabinet_inputs() {
+        let mut vs = VsSystem::new(VsPpu::Rc2c05V3);
+        vs.dip_switches = 0b1010_0101;
+        vs.coins[0] = true;
+        assert_eq!(vs.read_4016(0x01), 0b0010_1001);
+        assert_eq!(vs.read_4017(0x00), 0b1010_0100);
+        assert_eq!(vs.ppu.status_id(), Some(0x1C));
+        assert_eq!(VsPpu::from_header(0x03).color(0x00), 0x2E);
+    }
+}